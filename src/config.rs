@@ -0,0 +1,262 @@
+use std::{env, fs, io, path::{Path, PathBuf}, process};
+use serde::{Deserialize, Serialize};
+
+use crate::playset::index::ScanOptions;
+use crate::music_player::Backend;
+use crate::playset::export::ExportOptions;
+use crate::playset::sync::SyncProfile;
+use crate::playset::import::ImportOptions;
+
+const USAGE: &str = "Usage: MusicApp [options]
+
+  --config FILE     Read settings from FILE instead of the default config.toml
+  --library DIR     Library root holding U/, subsets/ and the caches
+  --songs DIR       Directory with every song, defaults to <library>/U
+  --subsets DIR     Directory with one file per playset, defaults to <library>/subsets
+  --device NAME     Audio output device
+  --output OUTPUT   device, null for no sound, or a .wav file to record into
+  --theme THEME     dark, light or system
+  --no-scan         Trust the saved index instead of rescanning the songs directory
+  --help            Show this message";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+    /// Follows the desktop
+    System,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::Dark, Theme::Light, Theme::System];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Theme::Dark => "Dark",
+            Theme::Light => "Light",
+            Theme::System => "System",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Theme> {
+        Theme::ALL.into_iter().find(|t| t.label().eq_ignore_ascii_case(s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibraryConfig {
+    /// Holds the covers, waveforms and session, and by default the songs and sets
+    pub root: PathBuf,
+    /// Every song, the universal set. Defaults to `root/U`
+    pub songs: Option<PathBuf>,
+    /// One file per playset. Defaults to `root/subsets`, the index, history and stats sit next to it
+    pub subsets: Option<PathBuf>,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self { root: PathBuf::from("./song_library"), songs: None, subsets: None }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Output device by name, the system default when unset
+    pub device: Option<String>,
+    pub output: Backend,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutosaveConfig {
+    /// Keep the queue, position and layout for the next start
+    pub session: bool,
+    /// Seconds between saves while running, the session is also saved on exit
+    pub interval: u64,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self { session: true, interval: 10 }
+    }
+}
+
+/// Everything read from `config.toml`, with command line overrides applied on top
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub library: LibraryConfig,
+    pub audio: AudioConfig,
+    pub theme: Theme,
+    pub autosave: AutosaveConfig,
+    pub scanning: ScanOptions,
+    /// Used for the last export, as a starting point for the next one
+    pub export: ExportOptions,
+    pub sync: Vec<SyncProfile>,
+    pub import: ImportOptions,
+}
+
+/// `$XDG_CONFIG_HOME/playset`, falling back to `~/.config/playset` or `%APPDATA%\playset`
+pub fn config_dir() -> PathBuf {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("playset")
+}
+
+pub fn default_path() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+impl Config {
+    /// Reads `path`, writing the defaults there first if it doesn't exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let config = Self::default();
+                if let Err(e) = config.save(&path) {
+                    println!("Failed to write default config: {}", e);
+                }
+                Ok(config)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let out = toml::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(dir) = path.as_ref().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, out)
+    }
+
+    /// Loads the config named by `--config` or the default one, then applies the other flags.
+    /// Returns the config with the path it was read from.
+    pub fn from_args(args: impl Iterator<Item = String>) -> io::Result<(Self, PathBuf)> {
+        let mut args = args.collect::<Vec<String>>().into_iter();
+        let mut path = default_path();
+        let mut overrides = vec![];
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs a value\n\n{}", arg, USAGE)));
+            match arg.as_str() {
+                "--config" => path = PathBuf::from(value()?),
+                "--library" | "--songs" | "--subsets" | "--device" | "--output" | "--theme" => {
+                    let value = value()?;
+                    overrides.push((arg, Some(value)));
+                },
+                "--no-scan" => overrides.push((arg, None)),
+                "--help" | "-h" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown option {}\n\n{}", arg, USAGE))),
+            }
+        }
+
+        let mut config = Self::load(&path)?;
+        for (flag, value) in overrides {
+            let value = value.unwrap_or_default();
+            match flag.as_str() {
+                "--library" => config.library.root = PathBuf::from(value),
+                "--songs" => config.library.songs = Some(PathBuf::from(value)),
+                "--subsets" => config.library.subsets = Some(PathBuf::from(value)),
+                "--device" => config.audio.device = Some(value),
+                "--output" => config.audio.output = match value.as_str() {
+                    "device" => Backend::Device,
+                    "null" => Backend::Null,
+                    _ if value.to_lowercase().ends_with(".wav") => Backend::Wav(PathBuf::from(value)),
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown output {}, use device, null or a .wav file", value))),
+                },
+                "--theme" => config.theme = Theme::parse(&value)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown theme {}, use dark, light or system", value)))?,
+                "--no-scan" => config.scanning.on_startup = false,
+                _ => unreachable!(),
+            }
+        }
+        Ok((config, path))
+    }
+
+    pub fn songs_dir(&self) -> PathBuf {
+        self.library.songs.clone().unwrap_or_else(|| self.library.root.join("U"))
+    }
+
+    pub fn subsets_dir(&self) -> PathBuf {
+        self.library.subsets.clone().unwrap_or_else(|| self.library.root.join("subsets"))
+    }
+
+    pub fn covers_dir(&self) -> PathBuf {
+        self.library.root.join("covers")
+    }
+
+    pub fn waveforms_dir(&self) -> PathBuf {
+        self.library.root.join("waveforms")
+    }
+
+    pub fn session_path(&self) -> PathBuf {
+        self.library.root.join("session.json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(path: &Path, rest: &[&str]) -> impl Iterator<Item = String> {
+        let mut args = vec![String::from("--config"), path.display().to_string()];
+        args.extend(rest.iter().map(|a| a.to_string()));
+        args.into_iter()
+    }
+
+    #[test]
+    fn flags_override_the_file_without_changing_it() {
+        let dir = env::temp_dir().join(format!("playset-config-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("config.toml");
+
+        // The defaults are written on first run
+        let (config, read_from) = Config::from_args(args(&path, &[])).unwrap();
+        assert_eq!((config, read_from), (Config::default(), path.clone()));
+        let written = fs::read_to_string(&path).unwrap();
+
+        let (config, _) = Config::from_args(args(&path, &[
+            "--library", "/music", "--songs", "/music/all", "--device", "Speakers",
+            "--output", "out.WAV", "--theme", "LIGHT", "--no-scan",
+        ])).unwrap();
+        assert_eq!(config.library.root, PathBuf::from("/music"));
+        assert_eq!(config.songs_dir(), PathBuf::from("/music/all"));
+        assert_eq!(config.subsets_dir(), PathBuf::from("/music/subsets"));
+        assert_eq!(config.audio.device.as_deref(), Some("Speakers"));
+        assert_eq!(config.audio.output, Backend::Wav(PathBuf::from("out.WAV")));
+        assert_eq!(config.theme, Theme::Light);
+        assert!(!config.scanning.on_startup);
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+
+        let (config, _) = Config::from_args(args(&path, &["--output", "null"])).unwrap();
+        assert_eq!(config.audio.output, Backend::Null);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        let dir = env::temp_dir().join(format!("playset-config-errors-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("config.toml");
+        for bad in [&["--frobnicate"][..], &["--theme"], &["--theme", "purple"], &["--output", "speakers"]] {
+            let e = Config::from_args(args(&path, bad)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", bad);
+        }
+
+        fs::write(&path, "theme = 3").unwrap();
+        assert_eq!(Config::from_args(args(&path, &[])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
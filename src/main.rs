@@ -1,7 +1,15 @@
 mod ui;
 mod playset;
 mod music_player;
+mod config;
 
 fn main() {
-    ui::run();
+    let (config, config_path) = match config::Config::from_args(std::env::args().skip(1)) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    ui::run(config, config_path);
 }
@@ -1,12 +1,464 @@
-use std::io::BufReader;
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+use std::io::{self, BufReader, BufWriter};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use std::time::Duration;
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sample, Sink, Source};
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::queue::SourcesQueueOutput;
+use serde::{Deserialize, Serialize};
+use rodio::cpal::traits::HostTrait;
+use rodio::DeviceTrait;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use crate::playset::export;
 
-pub fn play_music(file_path: &str, stream_handle: &Option<OutputStreamHandle>, sink: &Sink) {
+/// Mono samples kept for visualizers, enough for one FFT window plus some slack
+const TAP_LENGTH: usize = 8192;
+/// Samples collected before taking the lock, so the audio thread rarely touches it
+const TAP_BATCH: usize = 256;
+
+/// The latest samples that went to the output, mixed down to mono
+#[derive(Default)]
+pub struct TapBuffer {
+    pub samples: VecDeque<f32>,
+    pub sample_rate: u32,
+}
+
+pub type Tap = Arc<Mutex<TapBuffer>>;
+
+/// Passes a source through unchanged while copying what it plays into a `Tap`
+pub struct TapSource<S> {
+    inner: S,
+    tap: Tap,
+    pending: Vec<f32>,
+    frame: f32,
+    channel: u16,
+}
+
+impl<S: Source> TapSource<S> where S::Item: Sample {
+    pub fn new(inner: S, tap: Tap) -> Self {
+        Self { inner, tap, pending: Vec::with_capacity(TAP_BATCH), frame: 0.0, channel: 0 }
+    }
+
+    fn flush(&mut self) {
+        let mut tap = self.tap.lock().unwrap();
+        tap.sample_rate = self.inner.sample_rate();
+        tap.samples.extend(self.pending.drain(..));
+        let excess = tap.samples.len().saturating_sub(TAP_LENGTH);
+        tap.samples.drain(..excess);
+    }
+}
+
+impl<S: Source> Iterator for TapSource<S> where S::Item: Sample {
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let sample = self.inner.next()?;
+        let channels = self.inner.channels().max(1);
+        self.frame += sample.to_f32();
+        self.channel += 1;
+        if self.channel >= channels {
+            self.pending.push(self.frame / channels as f32);
+            self.frame = 0.0;
+            self.channel = 0;
+            if self.pending.len() == TAP_BATCH {
+                self.flush();
+            }
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for TapSource<S> where S::Item: Sample {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+/// Sample rate and channels of everything written by the file and null backends
+pub const RENDER_RATE: u32 = 44100;
+pub const RENDER_CHANNELS: u16 = 2;
+
+/// What the player plays into
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A sound card, see `AudioConfig::device`
+    #[default]
+    Device,
+    /// Nothing, playback still advances in real time
+    Null,
+    /// Records whatever plays into a WAV file, in real time like `Null`
+    Wav(PathBuf),
+}
+
+/// Where the sink's samples end up
+pub struct Output {
+    /// Device name, or what plays instead of one
+    pub name: String,
+    pub handle: Option<OutputStreamHandle>,
+    _stream: Option<OutputStream>,
+    /// Stops the thread draining a null or file output
+    drain: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        // Waited for so a recording is finalized before the process exits
+        if let Some((stop, thread)) = self.drain.take() {
+            stop.store(true, Ordering::Relaxed);
+            if thread.join().is_err() {
+                println!("Output thread of {} panicked", self.name);
+            }
+        }
+    }
+}
+
+/// Names of the output devices of the default host
+pub fn output_devices() -> Vec<String> {
+    match rodio::cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+        Err(e) => {
+            println!("Failed to list output devices: {}", e);
+            vec![]
+        }
+    }
+}
+
+pub fn wav_spec() -> hound::WavSpec {
+    hound::WavSpec { channels: RENDER_CHANNELS, sample_rate: RENDER_RATE, bits_per_sample: 16, sample_format: hound::SampleFormat::Int }
+}
+
+/// Opens `backend`. A device is looked up by `device` name, falling back to the default device
+/// and then to the null backend, so the app still runs without any sound card.
+pub fn open_output(backend: &Backend, device: Option<&str>) -> (Output, Sink) {
+    match backend {
+        Backend::Device => {},
+        Backend::Null => return drained_output(String::from("No output"), None),
+        Backend::Wav(path) => match hound::WavWriter::create(path, wav_spec()) {
+            Ok(writer) => return drained_output(format!("Recording to {}", path.display()), Some(writer)),
+            Err(e) => println!("Failed to create {}: {}", path.display(), e),
+        },
+    }
+
+    if let Some(name) = device {
+        let found = rodio::cpal::default_host().output_devices().ok().and_then(|mut d| d.find(|d| d.name().is_ok_and(|n| n == name)));
+        match found.map(|d| OutputStream::try_from_device(&d)) {
+            Some(Ok((stream, handle))) => {
+                if let Ok(sink) = Sink::try_new(&handle) {
+                    return (Output { name: name.to_owned(), handle: Some(handle), _stream: Some(stream), drain: None }, sink);
+                }
+            },
+            Some(Err(e)) => println!("Failed to open {}: {}", name, e),
+            None => println!("No output device named {}, using the default", name),
+        }
+    }
+
+    if let Ok((stream, handle)) = OutputStream::try_default() && let Ok(sink) = Sink::try_new(&handle) {
+        let name = rodio::cpal::default_host().default_output_device().and_then(|d| d.name().ok()).unwrap_or_else(|| String::from("Default"));
+        return (Output { name, handle: Some(handle), _stream: Some(stream), drain: None }, sink);
+    }
+
+    println!("No audio output available, playing silently");
+    drained_output(String::from("No output"), None)
+}
+
+/// A sink that nothing plays, its samples come out of the returned queue whenever the caller pulls them,
+/// so playback can be stepped a known number of samples at a time. `Sink::try_seek` waits for the
+/// queue to be pulled, seeking the queue itself does the same without blocking.
+pub fn manual_output(name: String) -> (Output, Sink, SourcesQueueOutput<f32>) {
+    let (sink, queue) = Sink::new_idle();
+    (Output { name, handle: None, _stream: None, drain: None }, sink, queue)
+}
+
+/// A manual output pulled at playback speed by a thread, so songs still advance and seek.
+/// The samples are written to `wav` if given and dropped otherwise.
+fn drained_output(name: String, mut wav: Option<hound::WavWriter<BufWriter<File>>>) -> (Output, Sink) {
+    let (mut output, sink, queue) = manual_output(name);
+    // Songs of different formats come out of the queue as one steady stream
+    let mut samples = UniformSourceIterator::<_, i16>::new(queue, RENDER_CHANNELS, RENDER_RATE);
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = thread::spawn(move || {
+        let started = Instant::now();
+        let mut played = 0.0;
+        // 10 ms at a time, the queue yields silence when empty
+        let chunk = (RENDER_RATE as usize * RENDER_CHANNELS as usize) / 100;
+        while !stopped.load(Ordering::Relaxed) {
+            for sample in samples.by_ref().take(chunk) {
+                if let Some(writer) = &mut wav && let Err(e) = writer.write_sample(sample) {
+                    println!("Failed to record: {}", e);
+                    wav = None;
+                }
+            }
+            played += 0.01;
+            let ahead = played - started.elapsed().as_secs_f64();
+            if ahead > 0.0 {
+                thread::sleep(Duration::from_secs_f64(ahead));
+            }
+        }
+        if let Some(Err(e)) = wav.map(|w| w.finalize()) {
+            println!("Failed to finish recording: {}", e);
+        }
+    });
+    output.drain = Some((stop, thread));
+    (output, sink)
+}
+
+pub fn play_music(file_path: &Path, stream_handle: &Option<OutputStreamHandle>, sink: &Sink, tap: &Tap) {
     sink.clear();
 
     let file = BufReader::new(File::open(file_path).unwrap());
     let source = Decoder::new(file).unwrap();
 
-    sink.append(source);
+    sink.append(TapSource::new(source, tap.clone()));
+}
+
+/// Replaces whatever is playing with `file_paths`, played back to back.
+/// Files that can't be opened or decoded are left out, their positions in `file_paths` are returned.
+pub fn queue_music(file_paths: &[PathBuf], sink: &Sink, tap: &Tap) -> Vec<usize> {
+    sink.clear();
+
+    let mut skipped = vec![];
+    for (i, file_path) in file_paths.iter().enumerate() {
+        let source = File::open(file_path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| Decoder::new(BufReader::new(f)).map_err(|e| e.to_string()));
+        match source {
+            Ok(source) => sink.append(TapSource::new(source, tap.clone())),
+            Err(e) => {
+                println!("Skipping {}: {}", file_path.display(), e);
+                skipped.push(i);
+            },
+        }
+    }
+    skipped
+}
+
+/// Mixes `file_paths` into one WAV file at `out`, each song fading into the next over `crossfade`.
+/// Renders as fast as the files decode, `progress` is told how many songs are done.
+/// Songs are streamed through, only the crossfade's worth of samples is held in memory.
+pub fn render_to_wav(file_paths: &[PathBuf], crossfade: Duration, out: &Path, mut progress: impl FnMut(usize)) -> io::Result<()> {
+    let mut writer = hound::WavWriter::create(out, wav_spec()).map_err(io::Error::other)?;
+    let mut write = |sample: f32| writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).map_err(io::Error::other);
+    let channels = RENDER_CHANNELS as usize;
+    let overlap = (crossfade.as_secs_f32() * RENDER_RATE as f32) as usize * channels;
+    // The end of the previous song, held back to mix with the start of the next
+    let mut tail: VecDeque<f32> = VecDeque::new();
+
+    for (done, file_path) in file_paths.iter().enumerate() {
+        let source = Decoder::new(BufReader::new(File::open(file_path)?)).map_err(io::Error::other)?;
+        let mut samples = UniformSourceIterator::<_, f32>::new(source, RENDER_CHANNELS, RENDER_RATE);
+
+        // A song shorter than the tail fades in over all of it
+        let head = samples.by_ref().take(tail.len()).collect::<Vec<f32>>();
+        let fade = head.len() / channels * channels;
+        for sample in tail.drain(..tail.len() - fade) {
+            write(sample)?;
+        }
+        for (i, (old, new)) in tail.drain(..).zip(&head).enumerate() {
+            let t = (i / channels) as f32 / (fade / channels).max(1) as f32;
+            write(old * (1.0 - t) + new * t)?;
+        }
+
+        let keep = if done + 1 < file_paths.len() { overlap } else { 0 };
+        for sample in head[fade..].iter().copied().chain(samples) {
+            tail.push_back(sample);
+            if tail.len() > keep {
+                write(tail.pop_front().unwrap())?;
+            }
+        }
+        progress(done + 1);
+    }
+    writer.finalize().map_err(io::Error::other)
+}
+
+/// `render_to_wav`, or when `out` ends in `.flac` a WAV beside it that `ffmpeg` then encodes.
+/// The intermediate WAV is removed either way.
+pub fn render_to_file(file_paths: &[PathBuf], crossfade: Duration, out: &Path, ffmpeg: &Path, progress: impl FnMut(usize)) -> io::Result<()> {
+    if !out.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("flac")) {
+        return render_to_wav(file_paths, crossfade, out, progress);
+    }
+    let name = out.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let wav = out.with_file_name(format!(".partial-{}.wav", name));
+    let result = render_to_wav(file_paths, crossfade, &wav, progress)
+        .and_then(|_| export::run_ffmpeg(ffmpeg, &wav, &["-c:a", "flac"], out));
+    if let Err(e) = std::fs::remove_file(&wav) && e.kind() != io::ErrorKind::NotFound {
+        println!("Failed to remove {}: {}", wav.display(), e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames per second of the generated test files, the same as the render rate so nothing is resampled
+    const RATE: usize = RENDER_RATE as usize;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("playset-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A stereo file of `frames` frames, both channels of frame `i` holding `value(i)`
+    fn write_wav(path: &Path, frames: usize, value: impl Fn(usize) -> i16) {
+        let mut writer = hound::WavWriter::create(path, wav_spec()).unwrap();
+        for i in 0..frames {
+            writer.write_sample(value(i)).unwrap();
+            writer.write_sample(value(i)).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn pull(queue: &mut SourcesQueueOutput<f32>, samples: usize) -> Vec<i16> {
+        queue.by_ref().take(samples).map(|s| (s * 32768.0).round() as i16).collect()
+    }
+
+    fn ramp(i: usize) -> i16 {
+        (i % 10000) as i16
+    }
+
+    fn assert_near(actual: Duration, expected: Duration) {
+        // The sink updates its position every 5 ms of audio
+        let difference = actual.as_secs_f64() - expected.as_secs_f64();
+        assert!(difference.abs() <= 0.006, "position {:?}, expected {:?}", actual, expected);
+    }
+
+    #[test]
+    fn queue_plays_back_to_back_and_seeks() {
+        let dir = test_dir("queue");
+        let (first, second) = (dir.join("first.wav"), dir.join("second.wav"));
+        write_wav(&first, RATE, ramp);
+        write_wav(&second, RATE / 2, |_| 1000);
+
+        let (_output, sink, mut queue) = manual_output(String::from("Test"));
+        let skipped = queue_music(&[first, dir.join("missing.wav"), second], &sink, &Tap::default());
+        assert_eq!(skipped, vec![1]);
+        assert_eq!(sink.len(), 2);
+        sink.play();
+
+        let played = pull(&mut queue, RATE / 4 * 2);
+        assert_eq!(played.len(), RATE / 2);
+        assert_eq!(&played[..4], &[0, 0, 1, 1]);
+        assert_eq!(played[played.len() - 1], ramp(RATE / 4 - 1));
+        assert_near(sink.get_pos(), Duration::from_millis(250));
+
+        let seek_to = RATE * 6 / 10;
+        queue.try_seek(Duration::from_millis(600)).unwrap();
+        assert_eq!(pull(&mut queue, 2), vec![ramp(seek_to); 2]);
+        let rest = pull(&mut queue, (RATE - seek_to - 1) * 2);
+        assert_eq!(rest[rest.len() - 1], ramp(RATE - 1));
+        assert_near(sink.get_pos(), Duration::from_secs(1));
+        assert_eq!(sink.len(), 2);
+
+        // The first song only ends when the queue asks it for more
+        assert_eq!(pull(&mut queue, 2), vec![1000; 2]);
+        assert_eq!(sink.len(), 1);
+        let rest = pull(&mut queue, (RATE / 2 - 1) * 2);
+        assert!(rest.iter().all(|s| *s == 1000));
+        assert_near(sink.get_pos(), Duration::from_millis(500));
+
+        // An empty queue keeps going with silence
+        assert_eq!(pull(&mut queue, 2), vec![0; 2]);
+        assert!(sink.empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn render_mixes_the_crossfade() {
+        let dir = test_dir("render");
+        let (first, second, out) = (dir.join("first.wav"), dir.join("second.wav"), dir.join("out.wav"));
+        write_wav(&first, RATE, |_| 16384);
+        write_wav(&second, RATE, |_| -8192);
+
+        let mut done = vec![];
+        render_to_wav(&[first, second], Duration::from_millis(500), &out, |d| done.push(d)).unwrap();
+        assert_eq!(done, vec![1, 2]);
+
+        let samples = hound::WavReader::open(&out).unwrap().samples::<i16>().map(|s| s.unwrap()).collect::<Vec<i16>>();
+        // Two one second songs overlapping by half a second
+        assert_eq!(samples.len(), RATE * 3 / 2 * 2);
+        let frame = |seconds: f32| samples[(seconds * RATE as f32) as usize * 2] as i32;
+        let near = |value: i32, expected: i32| (value - expected).abs() <= 2;
+        assert!(near(frame(0.25), 16383));
+        // Halfway through the fade both songs are at half volume
+        assert!(near(frame(0.75), (16383 - 8191) / 2));
+        assert!(near(frame(1.25), -8191));
+        // The fade is linear, from all of the first song to all of the second
+        assert!(near(frame(0.5), 16383));
+        assert!(near(frame(0.625), 16383 - (16383 + 8191) / 4));
+        assert!(near(frame(0.875), 16383 - (16383 + 8191) * 3 / 4));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn render_handles_short_songs_and_no_crossfade() {
+        let dir = test_dir("render-short");
+        let (long, short, out) = (dir.join("long.wav"), dir.join("short.wav"), dir.join("out.wav"));
+        write_wav(&long, RATE, |_| 16384);
+        write_wav(&short, RATE / 4, |_| -8192);
+        let read = |path: &Path| hound::WavReader::open(path).unwrap().samples::<i16>().map(|s| s.unwrap()).collect::<Vec<i16>>();
+
+        // Without a crossfade the songs follow each other sample for sample
+        render_to_wav(&[long.clone(), short.clone()], Duration::ZERO, &out, |_| {}).unwrap();
+        let samples = read(&out);
+        assert_eq!(samples.len(), (RATE + RATE / 4) * 2);
+        assert_eq!((samples[RATE * 2 - 1], samples[RATE * 2]), (16383, -8191));
+
+        // A song shorter than the crossfade fades in over all of its length
+        render_to_wav(&[long, short], Duration::from_millis(500), &out, |_| {}).unwrap();
+        let samples = read(&out);
+        assert_eq!(samples.len(), RATE * 2);
+        assert_eq!(samples[RATE / 4 * 2], 16383);
+        assert!((samples[samples.len() - 1] as i32 + 8191).abs() <= 200);
+
+        // A FLAC render without ffmpeg fails and leaves nothing behind
+        let flac = dir.join("out.flac");
+        assert!(render_to_file(&[dir.join("short.wav")], Duration::ZERO, &flac, &dir.join("no-ffmpeg"), |_| {}).is_err());
+        assert!(!flac.exists() && !dir.join(".partial-out.flac.wav").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn queue_seeks_back_and_into_the_next_song() {
+        let dir = test_dir("seek");
+        let (first, second) = (dir.join("first.wav"), dir.join("second.wav"));
+        write_wav(&first, RATE, ramp);
+        write_wav(&second, RATE, |i| -ramp(i));
+
+        let (_output, sink, mut queue) = manual_output(String::from("Test"));
+        queue_music(&[first, second], &sink, &Tap::default());
+        sink.play();
+        pull(&mut queue, RATE);
+        assert_near(sink.get_pos(), Duration::from_millis(500));
+
+        queue.try_seek(Duration::from_millis(100)).unwrap();
+        assert_eq!(pull(&mut queue, 2), vec![ramp(RATE / 10); 2]);
+        pull(&mut queue, (RATE / 100 - 1) * 2);
+        assert_near(sink.get_pos(), Duration::from_millis(110));
+
+        // Past the end the song finishes and the next one starts from its beginning
+        queue.try_seek(Duration::from_secs(5)).unwrap();
+        let next = pull(&mut queue, 4);
+        assert_eq!(sink.len(), 1);
+        assert_eq!(next[next.len() - 1], -ramp(1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,185 @@
+use std::{io, path::Path};
+
+use super::Song;
+use super::fingerprint::{chroma, decode};
+
+/// Samples per onset envelope step at the analysis rate, about 43 steps a second
+const HOP: usize = 256;
+const MIN_BPM: f32 = 70.0;
+const MAX_BPM: f32 = 180.0;
+
+/// Krumhansl-Schmuckler key profiles, starting at the tonic
+const MAJOR: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// Tempo in beats per minute and Camelot key of one song, either can be missing
+pub type Analysis = (Option<f32>, Option<String>);
+
+/// Camelot wheel notation, e.g. 8B for C major and 8A for A minor.
+/// Keys next to each other on the wheel mix well.
+pub fn camelot(pitch_class: usize, minor: bool) -> String {
+    // Minor keys sit on the number of their relative major, three semitones up
+    let major = if minor { (pitch_class + 3) % 12 } else { pitch_class };
+    let number = (7 * major + 8) % 12;
+    format!("{}{}", if number == 0 { 12 } else { number }, if minor { 'A' } else { 'B' })
+}
+
+/// Wheel position and letter of a Camelot key, case and surrounding spaces ignored
+fn parse_camelot(key: &str) -> Option<(u8, char)> {
+    let key = key.trim();
+    let (number, letter) = match key.strip_suffix(['A', 'a']) {
+        Some(number) => (number, 'A'),
+        None => (key.strip_suffix(['B', 'b'])?, 'B'),
+    };
+    let number = number.parse().ok().filter(|n| (1..=12).contains(n))?;
+    Some((number, letter))
+}
+
+/// Steps between two keys on the Camelot wheel: 0 for the same key, 1 for a harmonic neighbour
+pub fn key_distance(a: &str, b: &str) -> Option<u8> {
+    let (a_number, a_letter) = parse_camelot(a)?;
+    let (b_number, b_letter) = parse_camelot(b)?;
+    let around = (a_number as i32 - b_number as i32).rem_euclid(12) as u8;
+    Some(around.min(12 - around) + (a_letter != b_letter) as u8)
+}
+
+fn correlation(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / 12.0;
+    let mean_b = b.iter().sum::<f32>() / 12.0;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for i in 0..12 {
+        cov += (a[i] - mean_a) * (b[i] - mean_b);
+        var_a += (a[i] - mean_a).powi(2);
+        var_b += (b[i] - mean_b).powi(2);
+    }
+    cov / (var_a * var_b).sqrt().max(1e-9)
+}
+
+/// The key whose profile best matches the song's average pitch class energy
+fn estimate_key(samples: &[f32], rate: u32) -> Option<String> {
+    let frames = chroma(samples, rate);
+    if frames.is_empty() {
+        return None;
+    }
+    let mut total = [0.0; 12];
+    for frame in &frames {
+        (0..12).for_each(|c| total[c] += frame[c]);
+    }
+
+    let mut best = (f32::MIN, 0, false);
+    for tonic in 0..12 {
+        for (profile, minor) in [(&MAJOR, false), (&MINOR, true)] {
+            let rotated: [f32; 12] = std::array::from_fn(|c| profile[(c + 12 - tonic) % 12]);
+            let score = correlation(&total, &rotated);
+            if score > best.0 {
+                best = (score, tonic, minor);
+            }
+        }
+    }
+    Some(camelot(best.1, best.2))
+}
+
+/// Tempo from the autocorrelation of how sharply loudness rises, the strongest beat period in range wins
+fn estimate_bpm(samples: &[f32], rate: u32) -> Option<f32> {
+    let energies = samples.chunks(HOP).map(|c| (c.iter().map(|s| s * s).sum::<f32>() / c.len() as f32 + 1e-9).ln()).collect::<Vec<f32>>();
+    let onsets = energies.windows(2).map(|w| (w[1] - w[0]).max(0.0)).collect::<Vec<f32>>();
+    let steps_per_second = rate as f32 / HOP as f32;
+
+    let min_lag = (60.0 * steps_per_second / MAX_BPM) as usize;
+    let max_lag = (60.0 * steps_per_second / MIN_BPM).ceil() as usize;
+    if onsets.len() < max_lag * 4 {
+        return None;
+    }
+    let autocorrelation = (0..=max_lag + 1)
+        .map(|lag| onsets.iter().zip(&onsets[lag..]).map(|(a, b)| a * b).sum::<f32>() / (onsets.len() - lag) as f32)
+        .collect::<Vec<f32>>();
+    let lag = (min_lag.max(1)..=max_lag).max_by(|a, b| autocorrelation[*a].total_cmp(&autocorrelation[*b]))?;
+
+    // Parabolic interpolation between neighbouring lags for a tempo finer than whole steps
+    let (left, middle, right) = (autocorrelation[lag - 1], autocorrelation[lag], autocorrelation[lag + 1]);
+    let denominator = left - 2.0 * middle + right;
+    let shift = if denominator.abs() > 1e-9 { 0.5 * (left - right) / denominator } else { 0.0 };
+    let bpm = 60.0 * steps_per_second / (lag as f32 + shift.clamp(-0.5, 0.5));
+    Some((bpm * 10.0).round() / 10.0)
+}
+
+/// Tempo in beats per minute and key in Camelot notation, either can fail to be found
+pub fn analyze<P: AsRef<Path>>(path: P) -> io::Result<Analysis> {
+    let (samples, rate) = decode(path)?;
+    Ok((estimate_bpm(&samples, rate), estimate_key(&samples, rate)))
+}
+
+/// Orders `songs` for DJ style mixing: starting from the first, each next song is the closest
+/// remaining one on the Camelot wheel, with the smallest tempo change breaking ties.
+/// Songs that were not analyzed go last.
+pub fn harmonic_order(songs: Vec<Song>) -> Vec<Song> {
+    let (mut remaining, unknown): (Vec<Song>, Vec<Song>) = songs.into_iter().partition(|s| s.key.is_some());
+    let mut out = vec![];
+    if !remaining.is_empty() {
+        out.push(remaining.remove(0));
+    }
+    while !remaining.is_empty() {
+        let last = out.last().unwrap();
+        let cost = |s: &Song| {
+            let keys = key_distance(last.key.as_deref().unwrap(), s.key.as_deref().unwrap()).unwrap_or(12);
+            let tempo = last.bpm.zip(s.bpm).map(|(a, b)| (a - b).abs()).unwrap_or(f32::MAX);
+            (keys, tempo)
+        };
+        let next = (0..remaining.len())
+            .min_by(|a, b| {
+                let (a, b) = (cost(&remaining[*a]), cost(&remaining[*b]));
+                a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+            })
+            .unwrap();
+        out.push(remaining.remove(next));
+    }
+    out.extend(unknown);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(name: &str, key: Option<&str>, bpm: Option<f32>) -> Song {
+        Song { name: name.to_owned(), key: key.map(|k| k.to_owned()), bpm, ..Default::default() }
+    }
+
+    #[test]
+    fn camelot_keys() {
+        assert_eq!(camelot(0, false), "8B");
+        assert_eq!(camelot(9, true), "8A");
+        assert_eq!(camelot(7, false), "9B");
+        assert_eq!(camelot(4, false), "12B");
+        assert_eq!(parse_camelot("12B"), Some((12, 'B')));
+        assert_eq!(parse_camelot(" 8a "), Some((8, 'A')));
+        for bad in ["", "A", "8", "13A", "0B", "8C", "é", "8é", "éA"] {
+            assert_eq!(parse_camelot(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn distances_wrap_around_the_wheel() {
+        assert_eq!(key_distance("8A", "8A"), Some(0));
+        assert_eq!(key_distance("8A", "9A"), Some(1));
+        assert_eq!(key_distance("8A", "8B"), Some(1));
+        assert_eq!(key_distance("12B", "1B"), Some(1));
+        assert_eq!(key_distance("1A", "7B"), Some(7));
+        assert_eq!(key_distance("8A", "x"), None);
+    }
+
+    #[test]
+    fn harmonic_order_walks_neighbours() {
+        let songs = vec![
+            song("start", Some("8A"), Some(120.0)),
+            song("unknown", None, Some(120.0)),
+            song("far", Some("2A"), Some(120.0)),
+            song("next-slow", Some("9A"), Some(90.0)),
+            song("next", Some("9A"), Some(122.0)),
+            song("after", Some("10A"), Some(122.0)),
+        ];
+        let order = harmonic_order(songs).into_iter().map(|s| s.name).collect::<Vec<String>>();
+        assert_eq!(order, ["start", "next", "next-slow", "after", "far", "unknown"]);
+        assert!(harmonic_order(vec![]).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use std::{cmp::Ordering, collections::{BTreeMap, HashSet}, rc::Rc};
+
+use super::{pset_format, Library, Song, SongSet, SongTree, SongTreeNode};
+use super::rules::{Condition, Rule, SongField};
+
+pub const UNKNOWN_ARTIST: &str = "Unknown artist";
+pub const UNKNOWN_ALBUM: &str = "Unknown album";
+
+/// How an album or artist is turned into a playset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetKind {
+    /// A fixed copy of the songs it has right now
+    Terminal,
+    /// Rules that also pick up songs added to the library later
+    Rule,
+}
+
+#[derive(Debug, Clone)]
+pub struct Album {
+    pub title: String,
+    pub artist: String,
+    pub year: Option<i32>,
+    /// In disc and track order
+    pub songs: Vec<Song>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Artist {
+    pub name: String,
+    /// Oldest first, albums without a year last
+    pub albums: Vec<Album>,
+}
+
+/// Albums are credited to the album artist, the track artist only stands in when there is none
+pub fn album_artist(song: &Song) -> &str {
+    if !song.album_artist.is_empty() {
+        &song.album_artist
+    } else if !song.artist.is_empty() {
+        &song.artist
+    } else {
+        UNKNOWN_ARTIST
+    }
+}
+
+fn album_title(song: &Song) -> &str {
+    if song.album.is_empty() { UNKNOWN_ALBUM } else { &song.album }
+}
+
+/// Missing disc and track numbers sort after present ones, the title breaks ties
+fn track_order(a: &Song, b: &Song) -> Ordering {
+    let number = |n: Option<u16>| n.unwrap_or(u16::MAX);
+    number(a.disc_number).cmp(&number(b.disc_number))
+        .then(number(a.track_number).cmp(&number(b.track_number)))
+        .then_with(|| a.display_title().to_lowercase().cmp(&b.display_title().to_lowercase()))
+}
+
+/// Every song of the universal set grouped into artists and their albums, artists sorted by name
+pub fn artists(library: &Library) -> Vec<Artist> {
+    let mut grouped: BTreeMap<(String, String), BTreeMap<String, Vec<Song>>> = BTreeMap::new();
+    for song in library.universal_set.songs.borrow().flatten(library) {
+        let artist = album_artist(&song).to_owned();
+        grouped
+            .entry((artist.to_lowercase(), artist))
+            .or_default()
+            .entry(album_title(&song).to_owned())
+            .or_default()
+            .push(song);
+    }
+
+    grouped.into_iter().map(|((_, name), albums)| {
+        let mut albums = albums.into_iter().map(|(title, mut songs)| {
+            songs.sort_by(track_order);
+            Album {
+                year: songs.iter().filter_map(|s| s.year).min(),
+                title,
+                artist: name.clone(),
+                songs,
+            }
+        }).collect::<Vec<Album>>();
+        albums.sort_by(|a, b| match (a.year, b.year) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }.then_with(|| a.title.to_lowercase().cmp(&b.title.to_lowercase())));
+        Artist { name, albums }
+    }).collect()
+}
+
+fn rule(field: SongField, value: &str) -> Rc<SongTree> {
+    Rc::new(SongTree::Set(SongSet::Rule(Rule { field, condition: Condition::Is(value.to_owned()) })))
+}
+
+/// Songs credited to `artist` either as album artist or as track artist.
+/// Unknown artists have no tag to match, so they are matched by both being empty.
+fn artist_rules(artist: &str) -> SongTree {
+    let artist = if artist == UNKNOWN_ARTIST { "" } else { artist };
+    if artist.is_empty() {
+        return SongTree::Operation(pset_format::INTERSECTION, SongTreeNode::new(rule(SongField::AlbumArtist, ""), rule(SongField::Artist, "")));
+    }
+    SongTree::Operation(pset_format::UNION, SongTreeNode::new(rule(SongField::AlbumArtist, artist), rule(SongField::Artist, artist)))
+}
+
+fn terminal<'a>(songs: impl Iterator<Item = &'a Song>) -> SongTree {
+    SongTree::Set(SongSet::Terminal(songs.cloned().collect::<HashSet<Song>>()))
+}
+
+impl Album {
+    pub fn to_tree(&self, kind: SetKind) -> SongTree {
+        match kind {
+            SetKind::Terminal => terminal(self.songs.iter()),
+            SetKind::Rule => {
+                let title = if self.title == UNKNOWN_ALBUM { "" } else { &self.title };
+                SongTree::Operation(pset_format::INTERSECTION, SongTreeNode::new(rule(SongField::Album, title), Rc::new(artist_rules(&self.artist))))
+            },
+        }
+    }
+
+    pub fn duration(&self) -> u64 {
+        self.songs.iter().map(|s| s.duration).sum()
+    }
+}
+
+impl Artist {
+    pub fn to_tree(&self, kind: SetKind) -> SongTree {
+        match kind {
+            SetKind::Terminal => terminal(self.albums.iter().flat_map(|a| a.songs.iter())),
+            SetKind::Rule => artist_rules(&self.name),
+        }
+    }
+
+    /// Every track, album after album
+    pub fn songs(&self) -> Vec<Song> {
+        self.albums.iter().flat_map(|a| a.songs.iter().cloned()).collect()
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::{HashMap, HashSet}, fs, io, path::{Path, PathBuf}, sync::mpsc, thread};
+use audiotags::Tag;
+
+use super::Song;
+
+/// Images next to a song that are used as its cover when none is embedded
+const COVER_FILES: [&str; 8] = [
+    "cover.jpg", "cover.png", "Cover.jpg", "Cover.png",
+    "folder.jpg", "folder.png", "front.jpg", "front.png",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoverSize {
+    Thumbnail,
+    Large,
+}
+
+impl CoverSize {
+    fn pixels(&self) -> u32 {
+        match self {
+            CoverSize::Thumbnail => 128,
+            CoverSize::Large => 512,
+        }
+    }
+}
+
+struct Job {
+    key: (String, CoverSize),
+    song_path: PathBuf,
+    cache_path: PathBuf,
+}
+
+/// Extracts album covers on a background thread and keeps resized copies on disk so they are only decoded once
+pub struct CoverCache {
+    dir: PathBuf,
+    /// Songs already looked up this run, `None` when they have no cover at all
+    known: HashMap<(String, CoverSize), Option<PathBuf>>,
+    pending: HashSet<(String, CoverSize)>,
+    jobs: mpsc::Sender<Job>,
+    done: mpsc::Receiver<((String, CoverSize), Option<PathBuf>)>,
+}
+
+/// FNV-1a, stable across runs unlike the std hasher, for naming cache files
+pub(super) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+impl CoverCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        let (done_tx, done) = mpsc::channel();
+        thread::spawn(move || {
+            for job in job_rx {
+                let size = job.key.1.pixels();
+                let found = match extract(&job.song_path) {
+                    Some(image) => match image.thumbnail(size, size).save(&job.cache_path) {
+                        Ok(_) => Some(job.cache_path),
+                        Err(e) => {
+                            println!("Failed to cache cover of {}: {}", job.key.0, e);
+                            None
+                        },
+                    },
+                    None => None,
+                };
+                if done_tx.send((job.key, found)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            known: HashMap::new(),
+            pending: HashSet::new(),
+            jobs,
+            done,
+        })
+    }
+
+    /// Takes in the covers the worker finished, returns true if there were any
+    pub fn poll(&mut self) -> bool {
+        let mut any = false;
+        for (key, found) in self.done.try_iter() {
+            // Songs forgotten while their cover was extracted are looked up again
+            if self.pending.remove(&key) {
+                self.known.insert(key, found);
+                any = true;
+            }
+        }
+        any
+    }
+
+    /// True while covers are still being extracted
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Path of a cached cover image for `song`, which lives at `song_path`.
+    /// A cover not on disk yet is queued and `None` returned until `poll` picks it up.
+    pub fn cover<P: AsRef<Path>>(&mut self, song_path: P, song: &Song, size: CoverSize) -> Option<PathBuf> {
+        let key = (song.name.clone(), size);
+        if let Some(known) = self.known.get(&key) {
+            return known.clone();
+        }
+        if self.pending.contains(&key) {
+            return None;
+        }
+
+        // The file size is part of the name so a cover changed through the tag editor is picked up
+        let hash = fnv1a(format!("{}{}", song.name, song.file_size).as_bytes());
+        let cached = self.dir.join(format!("{:016x}_{}.png", hash, size.pixels()));
+        if cached.exists() {
+            self.known.insert(key, Some(cached.clone()));
+            return Some(cached);
+        }
+
+        self.pending.insert(key.clone());
+        let job = Job { key, song_path: song_path.as_ref().to_path_buf(), cache_path: cached };
+        if self.jobs.send(job).is_err() {
+            println!("Cover worker stopped, not showing the cover of {}", song.name);
+        }
+        None
+    }
+
+    /// Drops what is remembered about `song`, e.g. after its tags were edited
+    pub fn forget(&mut self, song: &str) {
+        self.known.retain(|(name, _), _| name != song);
+        self.pending.retain(|(name, _)| name != song);
+    }
+}
+
+/// The embedded cover, or else one of `COVER_FILES` from the song's directory
+fn extract(song_path: &Path) -> Option<image::DynamicImage> {
+    let embedded = Tag::new()
+        .read_from_path(song_path)
+        .ok()
+        .and_then(|tag| tag.album_cover().and_then(|cover| image::load_from_memory(cover.data).ok()));
+    if embedded.is_some() {
+        return embedded;
+    }
+
+    let dir = song_path.parent()?;
+    COVER_FILES.iter().map(|f| dir.join(f)).find(|p| p.exists()).and_then(|p| image::open(p).ok())
+}
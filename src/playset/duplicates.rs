@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use super::{Library, Song};
+
+/// Songs whose lengths differ by more than this are never the same recording
+const DURATION_TOLERANCE: u64 = 3;
+/// Fingerprints at most this far apart are considered the same audio
+const FINGERPRINT_THRESHOLD: f32 = 0.2;
+
+/// Lowercase words only, dropping anything in brackets like "(Remastered)" or "[Official Video]"
+pub fn normalize(text: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for c in text.chars() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = (depth - 1).max(0),
+            c if depth == 0 && c.is_alphanumeric() => out.extend(c.to_lowercase()),
+            c if depth == 0 && c.is_whitespace() && !out.is_empty() && !out.ends_with(' ') => out.push(' '),
+            _ => {},
+        }
+    }
+    out.trim_end().to_owned()
+}
+
+/// Normalized artist and title, guessed from an "Artist - Title" file name when the song is untagged
+fn song_key(song: &Song) -> (String, String) {
+    if song.artist.is_empty() && song.title.is_empty() && let Some((artist, title)) = song.display_title().split_once(" - ") {
+        return (normalize(artist), normalize(title));
+    }
+    (normalize(&song.artist), normalize(song.display_title()))
+}
+
+/// Groups of two or more songs in the universal set that are likely the same recording, each sorted by file name.
+/// Songs match on normalized artist, title and duration, and with `by_fingerprint` also when the
+/// fingerprints stored in the index match, see `Library::add_fingerprints`.
+pub fn find(library: &Library, by_fingerprint: bool) -> Vec<Vec<Song>> {
+    let mut songs = library.index.songs().cloned().collect::<Vec<Song>>();
+    songs.sort_by_key(|s| s.duration);
+    let keys = songs.iter().map(song_key).collect::<Vec<(String, String)>>();
+
+    // Union-find over song positions, only songs of similar length are compared
+    let mut group = (0..songs.len()).collect::<Vec<usize>>();
+    fn root(group: &mut [usize], mut i: usize) -> usize {
+        while group[i] != i {
+            group[i] = group[group[i]];
+            i = group[i];
+        }
+        i
+    }
+    for i in 0..songs.len() {
+        for j in i + 1..songs.len() {
+            if songs[j].duration - songs[i].duration > DURATION_TOLERANCE {
+                break;
+            }
+            let same_tags = !keys[i].1.is_empty() && keys[i] == keys[j];
+            let same_audio = by_fingerprint && !same_tags && {
+                let a = library.index.fingerprint(&songs[i].name);
+                let b = library.index.fingerprint(&songs[j].name);
+                a.zip(b).is_some_and(|(a, b)| a.distance(b) <= FINGERPRINT_THRESHOLD)
+            };
+            if same_tags || same_audio {
+                let (a, b) = (root(&mut group, i), root(&mut group, j));
+                group[a] = b;
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<Song>> = HashMap::new();
+    for (i, song) in songs.iter().enumerate() {
+        let r = root(&mut group, i);
+        groups.entry(r).or_default().push(song.clone());
+    }
+    let mut groups = groups.into_values().filter(|g| g.len() > 1).collect::<Vec<Vec<Song>>>();
+    for group in &mut groups {
+        group.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    groups.sort_by(|a, b| a[0].name.cmp(&b[0].name));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::playset::tests::library;
+
+    fn names(groups: &[Vec<Song>]) -> Vec<Vec<&str>> {
+        groups.iter().map(|g| g.iter().map(|s| s.name.as_str()).collect()).collect()
+    }
+
+    #[test]
+    fn brackets_and_case_are_ignored() {
+        assert_eq!(normalize("Song  Title (Remastered 2011) [Official Video]"), "song title");
+        assert_eq!(normalize("  Don't   Stop!"), "dont stop");
+        let untagged = Song { name: String::from("The Band - Song (Live).mp3"), ..Default::default() };
+        assert_eq!(song_key(&untagged), (String::from("the band"), String::from("song")));
+    }
+
+    #[test]
+    fn groups_merge_into_the_kept_song() {
+        let (dir, mut library) = library("duplicates", &[
+            ("a.mp3", "The Band", "Song (Remastered)"),
+            ("b.mp3", "the band", "Song"),
+            ("c.mp3", "THE BAND", "song [Live]"),
+            ("d.mp3", "The Band", "Other Song"),
+            ("e.mp3", "Someone Else", "Song"),
+        ]);
+        assert_eq!(names(&find(&library, false)), [["a.mp3", "b.mp3", "c.mp3"]]);
+
+        library.create_set(String::from("Mix")).unwrap();
+        library.add_songs("Mix", vec![String::from("b.mp3"), String::from("d.mp3")]).unwrap();
+        library.merge_duplicates("a.mp3", &[String::from("b.mp3"), String::from("c.mp3"), String::from("a.mp3")]).unwrap();
+        assert!(find(&library, false).is_empty());
+        assert!(!dir.join("songs/b.mp3").exists() && dir.join("duplicates/b.mp3").exists());
+        let mut mix = library.sets["Mix"].songs.borrow().flatten(&library).into_iter().map(|s| s.name).collect::<Vec<String>>();
+        mix.sort();
+        assert_eq!(mix, ["a.mp3", "d.mp3"]);
+
+        library.undo().unwrap();
+        assert_eq!(names(&find(&library, false)), [["a.mp3", "b.mp3", "c.mp3"]]);
+        assert!(dir.join("songs/b.mp3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, process::Command, time::UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use super::Song;
+use super::tag_writer;
+
+/// Remembers what was exported where, kept in the target directory
+pub const MANIFEST: &str = ".playset-export.json";
+/// The same for syncs, kept apart so a sync only ever deletes what a sync wrote
+pub const SYNC_MANIFEST: &str = ".playset-sync.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Format {
+    /// The original file, byte for byte
+    #[default]
+    Copy,
+    Mp3,
+    Opus,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Copy, Format::Mp3, Format::Opus];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Format::Copy => "Original files",
+            Format::Mp3 => "MP3",
+            Format::Opus => "Opus",
+        }
+    }
+
+    fn extension<'a>(&self, song: &'a Song) -> &'a str {
+        match self {
+            Format::Copy => song.name.rsplit_once('.').map(|(_, e)| e).unwrap_or(""),
+            Format::Mp3 => "mp3",
+            Format::Opus => "opus",
+        }
+    }
+
+    fn codec(&self) -> Option<&'static str> {
+        match self {
+            Format::Copy => None,
+            Format::Mp3 => Some("libmp3lame"),
+            Format::Opus => Some("libopus"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    pub target: PathBuf,
+    /// Path of each file below `target` without the extension, `/` separates folders.
+    /// Fields: {artist} {album_artist} {album} {title} {track} {disc} {year} {genre} {name}
+    pub template: String,
+    pub format: Format,
+    /// Kilobits per second when transcoding
+    pub bitrate: u32,
+    /// Also write `<set name>.m3u` into `target`
+    pub m3u: bool,
+    /// Used for transcoding
+    pub ffmpeg: PathBuf,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            target: PathBuf::new(),
+            template: String::from("{album_artist}/{album}/{track} {title}"),
+            format: Format::Copy,
+            bitrate: 192,
+            m3u: true,
+            ffmpeg: PathBuf::from("ffmpeg"),
+        }
+    }
+}
+
+/// What a file in the target was made from, to tell whether it needs redoing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub song: String,
+    pub size: u64,
+    pub modified: u64,
+    pub format: Format,
+    pub bitrate: u32,
+    /// Stars in the exported file's POPM tag right after it was written, to spot ratings made on the device
+    #[serde(default)]
+    pub rating: Option<u8>,
+}
+
+impl ManifestEntry {
+    fn same_source(&self, other: &ManifestEntry) -> bool {
+        self.song == other.song && self.size == other.size && self.modified == other.modified && self.format == other.format && self.bitrate == other.bitrate
+    }
+}
+
+/// Target paths, relative to the target directory, and where they came from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: HashMap<String, ManifestEntry>,
+    /// M3U files written into the target
+    #[serde(default)]
+    pub playlists: Vec<String>,
+}
+
+impl Manifest {
+    /// `file` is `MANIFEST` or `SYNC_MANIFEST`
+    pub fn load<P: AsRef<Path>>(target: P, file: &str) -> io::Result<Self> {
+        match fs::read_to_string(target.as_ref().join(file)) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, target: P, file: &str) -> io::Result<()> {
+        let out = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(target.as_ref().join(file), out)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportReport {
+    pub copied: usize,
+    pub transcoded: usize,
+    /// Already up to date from an earlier export
+    pub skipped: usize,
+    /// Song names and why they failed
+    pub failed: Vec<(String, String)>,
+    /// Target paths of every song, relative to the target directory
+    pub files: Vec<String>,
+}
+
+impl ExportReport {
+    pub fn add(&mut self, other: ExportReport) {
+        self.copied += other.copied;
+        self.transcoded += other.transcoded;
+        self.skipped += other.skipped;
+        self.failed.extend(other.failed);
+        self.files.extend(other.files);
+    }
+}
+
+/// Characters that are trouble on FAT formatted sticks and phones
+pub(super) fn sanitize(part: &str) -> String {
+    let cleaned = part.chars().map(|c| if "/\\:*?\"<>|".contains(c) || c.is_control() { '_' } else { c }).collect::<String>();
+    let cleaned = cleaned.trim().trim_end_matches('.').to_owned();
+    if cleaned.is_empty() { String::from("Unknown") } else { cleaned }
+}
+
+/// Fills in `template` for `song`, each folder and the file name sanitized on its own
+pub fn target_path(template: &str, song: &Song, format: Format) -> String {
+    let stem = song.name.rsplit_once('.').map(|(s, _)| s).unwrap_or(&song.name);
+    let album_artist = if song.album_artist.is_empty() { &song.artist } else { &song.album_artist };
+    let fields = [
+        ("{artist}", song.artist.clone()),
+        ("{album_artist}", album_artist.clone()),
+        ("{album}", song.album.clone()),
+        ("{title}", song.display_title().to_owned()),
+        ("{track}", song.track_number.map(|t| format!("{:02}", t)).unwrap_or_default()),
+        ("{disc}", song.disc_number.map(|d| d.to_string()).unwrap_or_default()),
+        ("{year}", song.year.map(|y| y.to_string()).unwrap_or_default()),
+        ("{genre}", song.genre.clone()),
+        ("{name}", stem.to_owned()),
+    ];
+    let path = template.split('/')
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| {
+            let filled = fields.iter().fold(part.to_owned(), |out, (field, value)| out.replace(field, value));
+            sanitize(&filled)
+        })
+        .collect::<Vec<String>>()
+        .join("/");
+    let path = if path.is_empty() { sanitize(stem) } else { path };
+    match format.extension(song) {
+        "" => path,
+        extension => format!("{}.{}", path, extension),
+    }
+}
+
+/// Name of the playlist written for the set `name`
+pub fn playlist_name(name: &str) -> String {
+    format!("{}.m3u", sanitize(name))
+}
+
+/// `relative`, or when another song already has it `relative (2)`, `relative (3)`... like songs brought into the library.
+/// Taken are the paths given to other songs in this export, ignoring case for FAT, and those the manifest has for another song still in `target`.
+fn unique_path(relative: String, song: &str, claimed: &HashMap<String, String>, manifest: &Manifest, target: &Path) -> String {
+    let taken = |path: &str| {
+        claimed.get(&path.to_lowercase()).is_some_and(|s| s != song)
+            || manifest.files.get(path).is_some_and(|e| e.song != song && target.join(path).exists())
+    };
+    if !taken(&relative) {
+        return relative;
+    }
+    let extension = Path::new(&relative).extension().and_then(|e| e.to_str()).map(|e| format!(".{}", e)).unwrap_or_default();
+    let base = &relative[..relative.len() - extension.len()];
+    let mut n = 2;
+    loop {
+        let path = format!("{} ({}){}", base, n, extension);
+        if !taken(&path) {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+pub fn file_stamp<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let modified = meta.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Ok((meta.len(), modified))
+}
+
+/// Runs `ffmpeg` to convert `from` into `to`, `args` go between the two and pick the codec
+pub fn run_ffmpeg(ffmpeg: &Path, from: &Path, args: &[&str], to: &Path) -> io::Result<()> {
+    let output = Command::new(ffmpeg)
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(from)
+        .args(args)
+        .arg(to)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+    Ok(())
+}
+
+fn transcode(options: &ExportOptions, codec: &str, from: &Path, to: &Path) -> io::Result<()> {
+    run_ffmpeg(&options.ffmpeg, from, &["-vn", "-map_metadata", "0", "-c:a", codec, "-b:a", &format!("{}k", options.bitrate)], to)
+}
+
+fn export_song(options: &ExportOptions, from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written next to the target and renamed, so an interrupted export never looks finished.
+    // The extension stays last since ffmpeg picks the container from it.
+    let name = to.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let partial = to.with_file_name(format!(".partial-{}", name));
+    match options.format.codec() {
+        None => {
+            fs::copy(from, &partial)?;
+        },
+        Some(codec) => transcode(options, codec, from, &partial)?,
+    }
+    fs::rename(partial, to)
+}
+
+/// Copies or transcodes the songs of the set `name` from `songs_dir` into `options.target`, in order.
+/// Files whose source, format and bitrate match the last export are left alone.
+/// `progress` gets the number of songs handled so far and the one being worked on.
+pub fn export(name: &str, songs: &[Song], songs_dir: &Path, options: &ExportOptions, progress: impl FnMut(usize, &str)) -> io::Result<ExportReport> {
+    export_recorded_in(MANIFEST, name, songs, songs_dir, options, progress)
+}
+
+/// `export`, keeping track of the files in the manifest `manifest_file`
+pub(super) fn export_recorded_in(manifest_file: &str, name: &str, songs: &[Song], songs_dir: &Path, options: &ExportOptions, mut progress: impl FnMut(usize, &str)) -> io::Result<ExportReport> {
+    fs::create_dir_all(&options.target)?;
+    let mut manifest = Manifest::load(&options.target, manifest_file)?;
+    let mut report = ExportReport::default();
+    let mut playlist = vec![String::from("#EXTM3U")];
+    // Lowercased target paths handed out so far and their songs
+    let mut claimed = HashMap::new();
+
+    for (done, song) in songs.iter().enumerate() {
+        progress(done, song.display_title());
+        let relative = unique_path(target_path(&options.template, song, options.format), &song.name, &claimed, &manifest, &options.target);
+        claimed.insert(relative.to_lowercase(), song.name.clone());
+        let from = songs_dir.join(&song.name);
+        let to = options.target.join(&relative);
+
+        let (size, modified) = match file_stamp(&from) {
+            Ok(stamp) => stamp,
+            Err(e) => {
+                report.failed.push((song.name.clone(), e.to_string()));
+                continue;
+            }
+        };
+        // Copies don't depend on the bitrate
+        let bitrate = if options.format == Format::Copy { 0 } else { options.bitrate };
+        let mut entry = ManifestEntry { song: song.name.clone(), size, modified, format: options.format, bitrate, rating: None };
+        if manifest.files.get(&relative).is_some_and(|old| old.same_source(&entry)) && to.exists() {
+            report.skipped += 1;
+        } else {
+            match export_song(options, &from, &to) {
+                Ok(()) => {
+                    if options.format == Format::Copy {
+                        report.copied += 1;
+                    } else {
+                        report.transcoded += 1;
+                    }
+                    entry.rating = tag_writer::read_rating(&to).ok().flatten();
+                    manifest.files.insert(relative.clone(), entry);
+                },
+                Err(e) => {
+                    println!("Failed to export {}: {}", song.name, e);
+                    report.failed.push((song.name.clone(), e.to_string()));
+                    continue;
+                }
+            }
+        }
+
+        // Only files that made it into the target count, a sync would otherwise keep a stale copy listed
+        report.files.push(relative.clone());
+        playlist.push(format!("#EXTINF:{},{} - {}", song.duration, song.artist, song.display_title()));
+        playlist.push(relative);
+    }
+    progress(songs.len(), "");
+
+    if options.m3u {
+        let playlist_file = playlist_name(name);
+        fs::write(options.target.join(&playlist_file), playlist.join("\n") + "\n")?;
+        if !manifest.playlists.contains(&playlist_file) {
+            manifest.playlists.push(playlist_file);
+        }
+    }
+    manifest.save(&options.target, manifest_file)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(name: &str) -> Song {
+        Song {
+            name: name.to_owned(),
+            artist: String::from("AC/DC"),
+            album: String::from("Back in Black"),
+            title: String::from("Hells Bells?"),
+            track_number: Some(1),
+            year: Some(1980),
+            ..Default::default()
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("playset-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn templates_fill_in_and_sanitize() {
+        let song = song("hells bells.flac");
+        let default = ExportOptions::default().template;
+        assert_eq!(target_path(&default, &song, Format::Copy), "AC_DC/Back in Black/01 Hells Bells_.flac");
+        assert_eq!(target_path(&default, &song, Format::Opus), "AC_DC/Back in Black/01 Hells Bells_.opus");
+        assert_eq!(target_path("{year}/{genre}/{name}", &song, Format::Mp3), "1980/Unknown/hells bells.mp3");
+        assert_eq!(target_path("//{title}//", &song, Format::Mp3), "Hells Bells_.mp3");
+        assert_eq!(target_path("", &song, Format::Copy), "hells bells.flac");
+
+        let untagged = Song { name: String::from("no extension"), ..Default::default() };
+        assert_eq!(target_path("{artist} - {title}...", &untagged, Format::Copy), "- no extension");
+        let compilation = Song { album_artist: String::from("Various"), ..song };
+        assert_eq!(target_path("{album_artist}/{disc}{track}", &compilation, Format::Mp3), "Various/01.mp3");
+    }
+
+    #[test]
+    fn taken_paths_get_numbered() {
+        let target = test_dir("unique-path");
+        let mut claimed = HashMap::new();
+        let mut manifest = Manifest::default();
+        let unique = |relative: &str, song: &str, claimed: &HashMap<String, String>, manifest: &Manifest| {
+            unique_path(relative.to_owned(), song, claimed, manifest, &target)
+        };
+        assert_eq!(unique("a/Song.mp3", "one", &claimed, &manifest), "a/Song.mp3");
+
+        claimed.insert(String::from("a/song.mp3"), String::from("one"));
+        assert_eq!(unique("a/Song.mp3", "one", &claimed, &manifest), "a/Song.mp3");
+        assert_eq!(unique("a/SONG.mp3", "two", &claimed, &manifest), "a/SONG (2).mp3");
+        claimed.insert(String::from("a/song (2).mp3"), String::from("two"));
+        assert_eq!(unique("a/Song.mp3", "three", &claimed, &manifest), "a/Song (3).mp3");
+        assert_eq!(unique("a/Song", "three", &claimed, &manifest), "a/Song");
+
+        // Files from an earlier export only count while they are still there
+        let entry = ManifestEntry { song: String::from("old"), size: 0, modified: 0, format: Format::Copy, bitrate: 0, rating: None };
+        manifest.files.insert(String::from("b.mp3"), entry);
+        assert_eq!(unique("b.mp3", "new", &claimed, &manifest), "b.mp3");
+        fs::write(target.join("b.mp3"), b"").unwrap();
+        assert_eq!(unique("b.mp3", "new", &claimed, &manifest), "b (2).mp3");
+        assert_eq!(unique("b.mp3", "old", &claimed, &manifest), "b.mp3");
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn only_exported_files_are_reported() {
+        let dir = test_dir("export-report");
+        let songs_dir = dir.join("songs");
+        fs::create_dir_all(&songs_dir).unwrap();
+        fs::write(songs_dir.join("a.mp3"), b"a").unwrap();
+        let songs = [song("a.mp3"), Song { name: String::from("missing.mp3"), ..Default::default() }];
+        let options = ExportOptions { target: dir.join("out"), template: String::from("{name}"), ..Default::default() };
+
+        let report = export("Mix", &songs, &songs_dir, &options, |_, _| {}).unwrap();
+        assert_eq!((report.copied, report.skipped, report.failed.len()), (1, 0, 1));
+        assert_eq!(report.files, ["a.mp3"]);
+        assert_eq!(fs::read(dir.join("out/a.mp3")).unwrap(), b"a");
+        assert!(fs::read_to_string(dir.join("out/Mix.m3u")).unwrap().lines().all(|l| !l.contains("missing")));
+
+        // Unchanged files are skipped but still part of the export
+        let report = export("Mix", &songs, &songs_dir, &options, |_, _| {}).unwrap();
+        assert_eq!((report.copied, report.skipped, report.files.len()), (0, 1, 1));
+
+        // A failed transcode leaves neither a file nor a report entry behind
+        let options = ExportOptions { format: Format::Mp3, ffmpeg: dir.join("no-ffmpeg"), ..options };
+        let report = export("Mix", &songs[..1], &songs_dir, &options, |_, _| {}).unwrap();
+        assert_eq!((report.transcoded, report.failed.len()), (0, 1));
+        assert!(report.files.is_empty());
+        assert!(fs::read_dir(dir.join("out")).unwrap().all(|e| !e.unwrap().file_name().to_string_lossy().starts_with(".partial-")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
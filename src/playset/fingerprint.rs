@@ -0,0 +1,222 @@
+use std::{f32::consts::PI, fs::{self, File}, io::{self, BufReader}, path::Path};
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+
+/// Seconds of audio fingerprinted from the start of a song
+const LENGTH: usize = 120;
+/// Songs are downsampled to about this rate before analysis, the chroma range tops out well below it
+const ANALYSIS_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const HOP: usize = FRAME_SIZE / 3;
+const MIN_FREQUENCY: f32 = 28.0;
+const MAX_FREQUENCY: f32 = 3520.0;
+/// Alignments tried when comparing, in frames either way (about two seconds)
+const MAX_OFFSET: isize = 16;
+/// Fewer overlapping frames than this (about ten seconds) cannot be compared
+const MIN_OVERLAP: usize = 80;
+
+/// Chromaprint-style fingerprint: one 32 bit word per frame, derived from how the energy of the
+/// twelve pitch classes relates across notes and over time. It survives re-encoding and
+/// volume changes, so copies of a recording match while different recordings do not.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub frames: Vec<u32>,
+}
+
+/// In-place radix-2 FFT, `re` and `im` must have a power of two length
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Mono samples at roughly `ANALYSIS_RATE`, and the rate they ended up at
+pub(super) fn decode<P: AsRef<Path>>(path: P) -> io::Result<(Vec<f32>, u32)> {
+    let decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(io::Error::other)?;
+    let channels = decoder.channels().max(1) as usize;
+    let sample_rate = decoder.sample_rate();
+    let factor = (sample_rate / ANALYSIS_RATE).max(1) as usize;
+    let rate = sample_rate / factor as u32;
+
+    // Averaging over channels and then `factor` samples also low-pass filters the downsampling
+    let mut out = Vec::with_capacity(rate as usize * LENGTH);
+    let mut sum = 0.0;
+    let mut n = 0;
+    for sample in decoder.convert_samples::<f32>().take(sample_rate as usize * channels * LENGTH) {
+        sum += sample;
+        n += 1;
+        if n == channels * factor {
+            out.push(sum / n as f32);
+            sum = 0.0;
+            n = 0;
+        }
+    }
+    Ok((out, rate))
+}
+
+/// Energy of each of the twelve pitch classes per frame, normalized to unit length
+pub(super) fn chroma(samples: &[f32], rate: u32) -> Vec<[f32; 12]> {
+    let window = (0..FRAME_SIZE).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME_SIZE as f32).cos()).collect::<Vec<f32>>();
+    let pitch_class = (0..FRAME_SIZE / 2)
+        .map(|k| {
+            let frequency = k as f32 * rate as f32 / FRAME_SIZE as f32;
+            (MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency).then(|| {
+                let note = (12.0 * (frequency / 440.0).log2()).round() as i32 + 69;
+                note.rem_euclid(12) as usize
+            })
+        })
+        .collect::<Vec<Option<usize>>>();
+
+    let mut frames = vec![];
+    let mut start = 0;
+    while start + FRAME_SIZE <= samples.len() {
+        let mut re = samples[start..start + FRAME_SIZE].iter().zip(&window).map(|(s, w)| s * w).collect::<Vec<f32>>();
+        let mut im = vec![0.0; FRAME_SIZE];
+        fft(&mut re, &mut im);
+
+        let mut bins = [0.0; 12];
+        for (k, class) in pitch_class.iter().enumerate() {
+            if let Some(class) = class {
+                bins[*class] += re[k] * re[k] + im[k] * im[k];
+            }
+        }
+        let norm = bins.iter().map(|b| b * b).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            bins.iter_mut().for_each(|b| *b /= norm);
+        }
+        frames.push(bins);
+        start += HOP;
+    }
+    frames
+}
+
+impl Fingerprint {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let (samples, rate) = decode(path)?;
+        let chroma = chroma(&samples, rate);
+
+        // Smoothing over three frames keeps single noisy frames from flipping bits
+        let smoothed = (0..chroma.len())
+            .map(|i| {
+                let around = &chroma[i.saturating_sub(1)..(i + 2).min(chroma.len())];
+                let mut bins = [0.0; 12];
+                for frame in around {
+                    (0..12).for_each(|c| bins[c] += frame[c] / around.len() as f32);
+                }
+                bins
+            })
+            .collect::<Vec<[f32; 12]>>();
+
+        let frames = smoothed.iter().enumerate().map(|(i, c)| {
+            let previous = &smoothed[i.saturating_sub(2)];
+            let mut word = 0u32;
+            for n in 0..12 {
+                // Shape of the chord: each note against its neighbour
+                word |= ((c[n] > c[(n + 1) % 12]) as u32) << n;
+                // Movement: each note against two frames ago
+                word |= ((c[n] > previous[n]) as u32) << (12 + n);
+            }
+            for n in 0..8 {
+                // Intervals: each note against its minor third
+                word |= ((c[n] > c[(n + 3) % 12]) as u32) << (24 + n);
+            }
+            word
+        }).collect();
+
+        Ok(Self { frames })
+    }
+
+    /// Share of differing bits at the best alignment, 0 for identical audio and about 0.5 for unrelated songs
+    pub fn distance(&self, other: &Fingerprint) -> f32 {
+        (-MAX_OFFSET..=MAX_OFFSET)
+            .filter_map(|offset| {
+                let (a, b) = if offset >= 0 {
+                    (self.frames.get(offset as usize..)?, &other.frames[..])
+                } else {
+                    (&self.frames[..], other.frames.get((-offset) as usize..)?)
+                };
+                let len = a.len().min(b.len());
+                if len < MIN_OVERLAP {
+                    return None;
+                }
+                let differing = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum::<u32>();
+                Some(differing as f32 / (len * 32) as f32)
+            })
+            .fold(1.0, f32::min)
+    }
+}
+
+/// Known recordings with their metadata, kept in a local file so untagged songs can be identified offline
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FingerprintDb {
+    pub entries: Vec<DbEntry>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DbEntry {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub genre: String,
+    pub year: Option<i32>,
+    pub track_number: Option<u16>,
+    pub fingerprint: Fingerprint,
+}
+
+/// Matches at most this far apart are taken as the same recording
+pub const MATCH_THRESHOLD: f32 = 0.2;
+
+impl FingerprintDb {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let out = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, out)
+    }
+
+    /// The closest entry within `MATCH_THRESHOLD`
+    pub fn identify(&self, fingerprint: &Fingerprint) -> Option<&DbEntry> {
+        self.entries
+            .iter()
+            .map(|e| (e, e.fingerprint.distance(fingerprint)))
+            .filter(|(_, d)| *d <= MATCH_THRESHOLD)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(e, _)| e)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashSet, fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Genre {
+    pub name: String,
+    /// The broader genre this one belongs to, e.g. House for Deep House
+    pub parent: Option<String>,
+    /// Other spellings found in tags that mean this genre
+    pub aliases: Vec<String>,
+}
+
+/// User editable list of genres, read from `genres.json` next to the subsets directory.
+/// Tags are matched ignoring case, spaces and punctuation, so "Hip-Hop" and "hip hop" need no alias.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenreTaxonomy {
+    pub genres: Vec<Genre>,
+}
+
+/// Lowercase letters and digits only, with "&" read as "and"
+fn key(name: &str) -> String {
+    name.replace('&', "and").chars().filter(|c| c.is_alphanumeric()).flat_map(|c| c.to_lowercase()).collect()
+}
+
+fn genre(name: &str, parent: Option<&str>, aliases: &[&str]) -> Genre {
+    Genre {
+        name: name.to_owned(),
+        parent: parent.map(|p| p.to_owned()),
+        aliases: aliases.iter().map(|a| a.to_string()).collect(),
+    }
+}
+
+impl GenreTaxonomy {
+    /// Writes out the default taxonomy on first run so there is a file to edit
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let taxonomy = Self::default_taxonomy();
+                taxonomy.save(path)?;
+                Ok(taxonomy)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let out = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, out)
+    }
+
+    pub fn default_taxonomy() -> Self {
+        Self {
+            genres: vec![
+                genre("Electronic", None, &["Electronica", "EDM", "Dance"]),
+                genre("House", Some("Electronic"), &[]),
+                genre("Deep House", Some("House"), &[]),
+                genre("Tech House", Some("House"), &[]),
+                genre("Techno", Some("Electronic"), &[]),
+                genre("Trance", Some("Electronic"), &[]),
+                genre("Drum and Bass", Some("Electronic"), &["DnB", "Drum n Bass", "Jungle"]),
+                genre("Dubstep", Some("Electronic"), &[]),
+                genre("Ambient", Some("Electronic"), &[]),
+                genre("Synthwave", Some("Electronic"), &["Retrowave", "Outrun"]),
+                genre("Hip Hop", None, &["Rap", "Hip Hop/Rap"]),
+                genre("Trap", Some("Hip Hop"), &[]),
+                genre("Rock", None, &[]),
+                genre("Alternative Rock", Some("Rock"), &["Alternative", "Alt Rock"]),
+                genre("Indie Rock", Some("Rock"), &["Indie"]),
+                genre("Punk", Some("Rock"), &["Punk Rock"]),
+                genre("Metal", Some("Rock"), &["Heavy Metal"]),
+                genre("Pop", None, &[]),
+                genre("Synthpop", Some("Pop"), &["Electropop"]),
+                genre("K-Pop", Some("Pop"), &[]),
+                genre("R&B", None, &["RnB", "Rhythm and Blues"]),
+                genre("Soul", Some("R&B"), &[]),
+                genre("Jazz", None, &[]),
+                genre("Classical", None, &[]),
+                genre("Country", None, &[]),
+                genre("Folk", None, &[]),
+                genre("Reggae", None, &[]),
+                genre("Soundtrack", None, &["OST", "Score"]),
+            ],
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&Genre> {
+        let wanted = key(name);
+        self.genres.iter().find(|g| key(&g.name) == wanted || g.aliases.iter().any(|a| key(a) == wanted))
+    }
+
+    /// The canonical name for a genre tag, or the tag trimmed if the taxonomy does not know it
+    pub fn normalize(&self, tag: &str) -> String {
+        match self.find(tag) {
+            Some(genre) => genre.name.clone(),
+            None => tag.trim().to_owned(),
+        }
+    }
+
+    pub fn parent(&self, name: &str) -> Option<&str> {
+        self.find(name).and_then(|g| g.parent.as_deref())
+    }
+
+    pub fn children(&self, name: &str) -> Vec<&str> {
+        let wanted = key(name);
+        self.genres.iter().filter(|g| g.parent.as_deref().is_some_and(|p| key(p) == wanted)).map(|g| g.name.as_str()).collect()
+    }
+
+    /// Genres without a parent, in file order
+    pub fn roots(&self) -> Vec<&str> {
+        self.genres.iter().filter(|g| g.parent.is_none()).map(|g| g.name.as_str()).collect()
+    }
+
+    /// Whether `genre` is `ancestor` or one of its sub-genres at any depth
+    pub fn is_within(&self, genre: &str, ancestor: &str) -> bool {
+        let ancestor = key(&self.normalize(ancestor));
+        let mut current = self.normalize(genre);
+        // Bounded in case the file has a parent cycle
+        let mut seen = HashSet::new();
+        loop {
+            if key(&current) == ancestor {
+                return true;
+            }
+            if !seen.insert(key(&current)) {
+                return false;
+            }
+            match self.parent(&current) {
+                Some(parent) => current = parent.to_owned(),
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_match_ignoring_spelling() {
+        let genres = GenreTaxonomy::default_taxonomy();
+        assert_eq!(genres.normalize("hip-hop"), "Hip Hop");
+        assert_eq!(genres.normalize("  DEEP house "), "Deep House");
+        assert_eq!(genres.normalize("Drum & Bass"), "Drum and Bass");
+        assert_eq!(genres.normalize("dnb"), "Drum and Bass");
+        assert_eq!(genres.normalize("Rhythm & Blues"), "R&B");
+        assert_eq!(genres.normalize(" Vaporwave "), "Vaporwave");
+    }
+
+    #[test]
+    fn sub_genres_are_within_their_ancestors() {
+        let genres = GenreTaxonomy::default_taxonomy();
+        assert!(genres.is_within("Deep House", "House"));
+        assert!(genres.is_within("deep-house", "electronica"));
+        assert!(genres.is_within("Techno", "Techno"));
+        assert!(!genres.is_within("House", "Deep House"));
+        assert!(!genres.is_within("Trap", "Electronic"));
+        assert!(!genres.is_within("Vaporwave", "Electronic"));
+        assert!(genres.is_within("Vaporwave", "vaporwave"));
+
+        assert_eq!(genres.parent("Soul"), Some("R&B"));
+        assert_eq!(genres.children("house"), ["Deep House", "Tech House"]);
+        assert!(genres.roots().contains(&"Jazz") && !genres.roots().contains(&"House"));
+    }
+
+    #[test]
+    fn parent_cycles_end() {
+        let genres = GenreTaxonomy { genres: vec![genre("A", Some("B"), &[]), genre("B", Some("A"), &[])] };
+        assert!(genres.is_within("A", "B"));
+        assert!(!genres.is_within("A", "C"));
+    }
+
+    #[test]
+    fn first_load_writes_the_default() {
+        let path = std::env::temp_dir().join(format!("playset-genres-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let genres = GenreTaxonomy::load(&path).unwrap();
+        assert_eq!(genres.genres.len(), GenreTaxonomy::default_taxonomy().genres.len());
+        fs::write(&path, r#"{"genres":[{"name":"Gabber","parent":"Techno"}]}"#).unwrap();
+        let genres = GenreTaxonomy::load(&path).unwrap();
+        assert_eq!(genres.parent("gabber"), Some("Techno"));
+        assert!(genres.genres[0].aliases.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
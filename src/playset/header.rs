@@ -0,0 +1,311 @@
+use std::{fs::File, io::{self, Read, Seek, SeekFrom}, path::Path, time::Duration};
+
+/// How far into a file (past any ID3 tag) the first MP3 frame is looked for
+const MP3_SYNC_WINDOW: usize = 64 * 1024;
+
+/// How much of the end of an Ogg file is searched for the last page
+const OGG_TAIL: u64 = 64 * 1024;
+
+/// Stream details read straight from a file's headers, without decoding any audio
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    /// Only when the headers state or imply it, e.g. not for a VBR mp3 without a Xing header
+    pub duration: Option<Duration>,
+}
+
+/// Reads the stream details of the file at `path`, `None` for formats this doesn't know
+pub fn read_path<P: AsRef<Path>>(path: P) -> io::Result<Option<StreamInfo>> {
+    read(&mut File::open(path)?)
+}
+
+/// Recognizes WAV, FLAC, Ogg (Vorbis and Opus) and MP3 by their contents rather than their extension
+pub fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Option<StreamInfo>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; 12];
+    if read_up_to(reader, &mut magic)? < 4 {
+        return Ok(None);
+    }
+    if magic.starts_with(b"RIFF") && &magic[8..12] == b"WAVE" {
+        return wav(reader);
+    }
+    if magic.starts_with(b"OggS") {
+        return ogg(reader, len);
+    }
+
+    // FLAC and MP3 files can both start with an ID3 tag
+    let start = if magic.starts_with(b"ID3") { 10 + id3_size(&magic) } else { 0 };
+    reader.seek(SeekFrom::Start(start))?;
+    let mut head = vec![0; MP3_SYNC_WINDOW];
+    let n = read_up_to(reader, &mut head)?;
+    head.truncate(n);
+    if head.starts_with(b"fLaC") {
+        return Ok(flac(&head));
+    }
+    Ok(mp3(&head, len.saturating_sub(start)))
+}
+
+/// Fills as much of `buf` as the reader has left
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Size of an ID3v2 tag after its 10 byte header, including the footer when it has one
+fn id3_size(header: &[u8]) -> u64 {
+    let size = header[6..10].iter().fold(0u64, |size, b| (size << 7) | (*b & 0x7F) as u64);
+    if header[5] & 0x10 != 0 { size + 10 } else { size }
+}
+
+fn wav<R: Read + Seek>(reader: &mut R) -> io::Result<Option<StreamInfo>> {
+    reader.seek(SeekFrom::Start(12))?;
+    let (mut sample_rate, mut byte_rate) = (None, 0u32);
+    let mut chunk = [0; 8];
+    while read_up_to(reader, &mut chunk)? == 8 {
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        match &chunk[..4] {
+            b"fmt " => {
+                let mut fmt = [0; 16];
+                if read_up_to(reader, &mut fmt)? < 16 {
+                    break;
+                }
+                sample_rate = Some(u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]));
+                byte_rate = u32::from_le_bytes([fmt[8], fmt[9], fmt[10], fmt[11]]);
+                reader.seek(SeekFrom::Current(size as i64 - 16 + (size & 1) as i64))?;
+            },
+            b"data" => {
+                return Ok(sample_rate.map(|sample_rate| StreamInfo {
+                    sample_rate,
+                    duration: (byte_rate > 0).then(|| Duration::from_secs_f64(size as f64 / byte_rate as f64)),
+                }));
+            },
+            // Chunks are padded to an even length
+            _ => {
+                reader.seek(SeekFrom::Current(size as i64 + (size & 1) as i64))?;
+            },
+        }
+    }
+    Ok(sample_rate.map(|sample_rate| StreamInfo { sample_rate, duration: None }))
+}
+
+/// STREAMINFO is always the first metadata block
+fn flac(head: &[u8]) -> Option<StreamInfo> {
+    let info = head.get(8..26)?;
+    if head[4] & 0x7F != 0 {
+        return None;
+    }
+    let sample_rate = ((info[10] as u32) << 12) | ((info[11] as u32) << 4) | (info[12] as u32 >> 4);
+    let samples = ((info[13] as u64 & 0x0F) << 32) | u32::from_be_bytes([info[14], info[15], info[16], info[17]]) as u64;
+    if sample_rate == 0 {
+        return None;
+    }
+    Some(StreamInfo {
+        sample_rate,
+        duration: (samples > 0).then(|| Duration::from_secs_f64(samples as f64 / sample_rate as f64)),
+    })
+}
+
+/// The sample rate comes from the identification header of the first page, the length from the
+/// granule position of the last one
+fn ogg<R: Read + Seek>(reader: &mut R, len: u64) -> io::Result<Option<StreamInfo>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut first = [0; 27 + 255 + 19];
+    let n = read_up_to(reader, &mut first)?;
+    if n < 27 {
+        return Ok(None);
+    }
+    let packet = 27 + first[26] as usize;
+    let packet = &first[packet.min(n)..n];
+    let (sample_rate, granule_rate, pre_skip) = if packet.len() >= 16 && packet.starts_with(b"\x01vorbis") {
+        let rate = u32::from_le_bytes([packet[12], packet[13], packet[14], packet[15]]);
+        (rate, rate, 0)
+    } else if packet.len() >= 12 && packet.starts_with(b"OpusHead") {
+        // Opus always decodes at 48kHz, whatever rate the source had
+        (48000, 48000, u16::from_le_bytes([packet[10], packet[11]]) as u64)
+    } else {
+        return Ok(None);
+    };
+    if sample_rate == 0 {
+        return Ok(None);
+    }
+
+    let tail_start = len.saturating_sub(OGG_TAIL);
+    reader.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = vec![0; (len - tail_start) as usize];
+    let n = read_up_to(reader, &mut tail)?;
+    tail.truncate(n);
+    let granule = tail.windows(4)
+        .rposition(|w| w == b"OggS")
+        .and_then(|i| tail.get(i + 6..i + 14))
+        .map(|g| u64::from_le_bytes(g.try_into().unwrap()))
+        .filter(|g| *g != u64::MAX);
+    Ok(Some(StreamInfo {
+        sample_rate,
+        duration: granule.map(|g| Duration::from_secs_f64(g.saturating_sub(pre_skip) as f64 / granule_rate as f64)),
+    }))
+}
+
+/// kbit/s by bitrate index, for MPEG-1 and MPEG-2/2.5 layer III
+const MP3_BITRATES: [[u32; 15]; 2] = [
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// Reads the first layer III frame, its Xing/Info header when there is one, otherwise assumes a constant bitrate
+fn mp3(head: &[u8], len: u64) -> Option<StreamInfo> {
+    let sync = head.windows(4).position(|w| {
+        w[0] == 0xFF && w[1] & 0xE0 == 0xE0
+            && (w[1] >> 3) & 3 != 1
+            && (w[1] >> 1) & 3 == 1
+            && (w[2] >> 4) != 15
+            && (w[2] >> 2) & 3 != 3
+    })?;
+    let frame = &head[sync..];
+    let version = (frame[1] >> 3) & 3;
+    let mpeg1 = version == 3;
+    let sample_rate = [44100, 48000, 32000][((frame[2] >> 2) & 3) as usize] >> match version {
+        3 => 0,
+        2 => 1,
+        _ => 2,
+    };
+    let bitrate = MP3_BITRATES[if mpeg1 { 0 } else { 1 }][(frame[2] >> 4) as usize];
+    let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+
+    let mono = frame[3] >> 6 == 3;
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) => 17,
+        (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = frame.get(4 + side_info..4 + side_info + 12)
+        .filter(|x| x.starts_with(b"Xing") || x.starts_with(b"Info"))
+        .filter(|x| x[7] & 1 != 0)
+        .map(|x| u32::from_be_bytes([x[8], x[9], x[10], x[11]]));
+    let duration = match xing {
+        Some(frames) => Some(Duration::from_secs_f64(frames as f64 * samples_per_frame as f64 / sample_rate as f64)),
+        None if bitrate > 0 => Some(Duration::from_secs_f64((len - sync as u64) as f64 * 8.0 / (bitrate as f64 * 1000.0))),
+        None => None,
+    };
+    Some(StreamInfo { sample_rate, duration })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn info(bytes: Vec<u8>) -> Option<StreamInfo> {
+        read(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn secs(info: Option<StreamInfo>) -> f64 {
+        info.unwrap().duration.unwrap().as_secs_f64()
+    }
+
+    /// An MPEG-1 layer III frame header at 128 kbit/s and 44.1kHz, joint stereo
+    fn mp3_frame(xing_frames: Option<u32>) -> Vec<u8> {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x40];
+        frame.resize(4 + 32, 0);
+        if let Some(frames) = xing_frames {
+            frame.extend_from_slice(b"Xing");
+            frame.extend_from_slice(&1u32.to_be_bytes());
+            frame.extend_from_slice(&frames.to_be_bytes());
+        }
+        frame.resize(417, 0);
+        frame
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut wav = vec![];
+        let mut writer = hound::WavWriter::new(Cursor::new(&mut wav), hound::WavSpec {
+            channels: 2,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        }).unwrap();
+        for _ in 0..22050 * 2 * 3 {
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let stream = info(wav);
+        assert_eq!(stream.unwrap().sample_rate, 22050);
+        assert_eq!(secs(stream), 3.0);
+    }
+
+    #[test]
+    fn flac_streaminfo_behind_an_id3_tag() {
+        let mut flac = b"ID3\x04\x00\x00\x00\x00\x00\x05".to_vec();
+        flac.extend_from_slice(&[0; 5]);
+        flac.extend_from_slice(b"fLaC");
+        flac.extend_from_slice(&[0x80, 0, 0, 34]);
+        let mut streaminfo = [0u8; 34];
+        // 96kHz, 2 channels, 24 bits, 480000 samples
+        let (rate, samples) = (96000u64, 480000u64);
+        let packed = (rate << 44) | (1 << 41) | (23 << 36) | samples;
+        streaminfo[10..18].copy_from_slice(&packed.to_be_bytes());
+        flac.extend_from_slice(&streaminfo);
+
+        let stream = info(flac);
+        assert_eq!(stream.unwrap().sample_rate, 96000);
+        assert_eq!(secs(stream), 5.0);
+    }
+
+    fn ogg_page(granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x02".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    #[test]
+    fn ogg_vorbis_and_opus() {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend_from_slice(&32000u32.to_le_bytes());
+        ident.extend_from_slice(&[0; 14]);
+        let mut vorbis = ogg_page(0, &ident);
+        vorbis.extend(ogg_page(32000 * 7, &[0; 40]));
+        let stream = info(vorbis);
+        assert_eq!(stream.unwrap().sample_rate, 32000);
+        assert_eq!(secs(stream), 7.0);
+
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&44100u32.to_le_bytes());
+        head.extend_from_slice(&[0; 3]);
+        let mut opus = ogg_page(0, &head);
+        opus.extend(ogg_page(48000 * 2 + 312, &[0; 40]));
+        let stream = info(opus);
+        assert_eq!(stream.unwrap().sample_rate, 48000);
+        assert_eq!(secs(stream), 2.0);
+    }
+
+    #[test]
+    fn mp3_xing_and_constant_bitrate() {
+        let mut vbr = b"ID3\x04\x00\x00\x00\x00\x00\x00".to_vec();
+        vbr.extend(mp3_frame(Some(1000)));
+        let stream = info(vbr);
+        assert_eq!(stream.unwrap().sample_rate, 44100);
+        assert!((secs(stream) - 1000.0 * 1152.0 / 44100.0).abs() < 1e-9);
+
+        // 16000 bytes at 128 kbit/s
+        let cbr = (0..16000 / 417 + 1).flat_map(|_| mp3_frame(None)).take(16000).collect::<Vec<u8>>();
+        assert_eq!(secs(info(cbr)), 1.0);
+
+        assert_eq!(info(b"not audio at all".to_vec()), None);
+        assert_eq!(info(vec![]), None);
+    }}
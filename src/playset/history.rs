@@ -0,0 +1,76 @@
+use std::{fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+
+/// A single reversible change to the library.
+/// Set contents are snapshotted as pset strings so undo can restore them exactly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Edit {
+    /// `contents` is set when the new set starts out with songs instead of empty
+    CreateSet {
+        name: String,
+        #[serde(default)]
+        contents: Option<String>,
+    },
+    /// `inlined_into` holds the prior contents of every set that had its references to `name` inlined
+    DeleteSet {
+        name: String,
+        contents: String,
+        #[serde(default)]
+        inlined_into: Vec<(String, String)>,
+    },
+    RenameSet { from: String, to: String },
+    DuplicateSet { from: String, to: String },
+    AddSongs { set: String, songs: Vec<String>, before: String },
+    RemoveSongs { set: String, songs: Vec<String>, before: String },
+    ApplyOperation { set: String, op: char, other: String, before: String },
+    Tree { set: String, before: String, after: String },
+    /// `merged` files were moved out of the library, `sets` holds the name, prior and new contents of every rewritten set
+    MergeDuplicates { merged: Vec<String>, sets: Vec<(String, String, String)> },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl History {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let out = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, out)
+    }
+
+    /// Records a freshly performed edit. Anything that was undone is no longer redoable.
+    pub fn record(&mut self, edit: Edit) {
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Edit> {
+        self.undo.pop()
+    }
+    pub fn pop_redo(&mut self) -> Option<Edit> {
+        self.redo.pop()
+    }
+    pub fn push_undo(&mut self, edit: Edit) {
+        self.undo.push(edit);
+    }
+    pub fn push_redo(&mut self, edit: Edit) {
+        self.redo.push(edit);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
@@ -0,0 +1,277 @@
+use std::{fs, io, path::{Path, PathBuf}, process::{self, Command}, sync::atomic::{AtomicUsize, Ordering}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::export::sanitize;
+use super::listening;
+use super::tag_writer::TagEdit;
+
+/// Files that are taken as audio, the rest of a folder (info JSON, thumbnails) is ignored
+const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "m4a", "flac", "ogg", "opus", "wav", "aac", "wma"];
+/// Files whose audio is taken out with ffmpeg
+const VIDEO_EXTENSIONS: [&str; 3] = ["mp4", "webm", "mkv"];
+
+/// Tells scratch folders of imports started in the same second apart
+static SCRATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub enum ImportSource {
+    /// Downloaded with the downloader binary, anything it accepts works
+    Url(String),
+    /// Already downloaded files, copied and left in place
+    Folder(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    /// youtube-dl, yt-dlp or anything taking the same arguments
+    pub downloader: PathBuf,
+    /// What the downloader converts audio to, also used for audio taken out of videos
+    pub audio_format: String,
+    /// Takes the audio out of videos in imported folders
+    pub ffmpeg: PathBuf,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self { downloader: PathBuf::from("yt-dlp"), audio_format: String::from("mp3"), ffmpeg: PathBuf::from("ffmpeg") }
+    }
+}
+
+/// A file moved into the songs directory
+#[derive(Debug, Clone)]
+pub struct Imported {
+    /// File name in the songs directory
+    pub name: String,
+    /// Set when tags couldn't be written or the original couldn't be removed, the file is imported anyway
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    pub imported: Vec<Imported>,
+    /// Files, or the download, and why they failed
+    pub failed: Vec<(String, String)>,
+}
+
+/// Runs the downloader on `url` into `dir`, keeping the info JSON beside each file.
+/// The arguments are those of youtube-dl and yt-dlp:
+/// `--extract-audio --audio-format <format> --write-info-json --no-progress -o <dir>/%(id)s.%(ext)s <url>`
+pub fn download(url: &str, dir: &Path, options: &ImportOptions) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let template = dir.join("%(id)s.%(ext)s");
+    let output = Command::new(&options.downloader)
+        .args(["--extract-audio", "--audio-format", &options.audio_format, "--write-info-json", "--no-progress", "-o"])
+        .arg(template)
+        .arg(url)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run {}: {}", options.downloader.display(), e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+    Ok(())
+}
+
+/// `<stem>.info.json` next to `path`, as youtube-dl writes it
+fn info_json(path: &Path) -> Option<Value> {
+    let stem = path.file_stem()?.to_str()?;
+    let s = fs::read_to_string(path.with_file_name(format!("{}.info.json", stem))).ok()?;
+    serde_json::from_str(&s).ok()
+}
+
+/// Tags from the info JSON, music sites fill in track, artist and album, others only a title and uploader.
+/// Without any, "Artist - Title" file names are split.
+pub fn tags_from(path: &Path, info: Option<&Value>) -> TagEdit {
+    let text = |key: &str| info.and_then(|i| i.get(key)).and_then(|v| v.as_str()).map(|s| s.trim().to_owned()).filter(|s| !s.is_empty());
+    let number = |key: &str| info.and_then(|i| i.get(key)).and_then(|v| v.as_i64());
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+
+    let mut title = text("track").or_else(|| text("title"));
+    // Auto generated channels are named "<artist> - Topic"
+    let mut artist = text("artist").or_else(|| text("creator")).or_else(|| text("uploader").map(|u| u.trim_end_matches(" - Topic").to_owned()));
+    if text("track").is_none() {
+        // Video titles often carry the artist already
+        if let Some((left, right)) = title.clone().unwrap_or_else(|| stem.to_owned()).split_once(" - ") {
+            artist = Some(left.trim().to_owned());
+            title = Some(right.trim().to_owned());
+        }
+    }
+    let year = number("release_year")
+        .or_else(|| text("upload_date").and_then(|d| d.get(..4).and_then(|y| y.parse().ok())))
+        .map(|y| y as i32);
+
+    TagEdit {
+        title,
+        artist,
+        album: text("album"),
+        genre: text("genre"),
+        track_number: number("track_number").map(|t| t as u16),
+        year,
+        cover: None,
+    }
+}
+
+/// "Artist - Title.extension", with a number added when the name is taken
+fn library_name(songs_dir: &Path, tags: &TagEdit, path: &Path, extension: &str) -> String {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("Untitled");
+    let base = match (&tags.artist, &tags.title) {
+        (Some(artist), Some(title)) => sanitize(&format!("{} - {}", artist, title)),
+        (None, Some(title)) => sanitize(title),
+        _ => sanitize(stem),
+    };
+    let mut name = format!("{}.{}", base, extension);
+    let mut n = 2;
+    while songs_dir.join(&name).exists() {
+        name = format!("{} ({}).{}", base, n, extension);
+        n += 1;
+    }
+    name
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| extensions.iter().any(|a| a.eq_ignore_ascii_case(e)))
+}
+
+/// Writes the audio of the video `from` to `to`, encoded for the extension of `to`
+fn extract_audio(ffmpeg: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    let output = Command::new(ffmpeg)
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(from)
+        .args(["-vn", "-map_metadata", "0"])
+        .arg(to)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+    Ok(())
+}
+
+/// Brings the audio files in `dir` into `songs_dir`: tagged from their info JSON, renamed and
+/// moved, or copied when `keep` is set. Videos have their audio taken out in `options.audio_format`.
+/// A file that fails is reported and the rest carry on. The library still has to read them, see `Library::add_files`.
+pub fn ingest(dir: &Path, songs_dir: &Path, keep: bool, options: &ImportOptions) -> io::Result<ImportReport> {
+    let mut files = fs::read_dir(dir)?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|p| p.is_file() && (has_extension(p, &AUDIO_EXTENSIONS) || has_extension(p, &VIDEO_EXTENSIONS)))
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+
+    let mut report = ImportReport::default();
+    for path in files {
+        let file = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_owned();
+        let tags = tags_from(&path, info_json(&path).as_ref());
+        let video = has_extension(&path, &VIDEO_EXTENSIONS);
+        let extension = if video {
+            options.audio_format.to_lowercase()
+        } else {
+            path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase()
+        };
+        let name = library_name(songs_dir, &tags, &path, &extension);
+        let to = songs_dir.join(&name);
+        // Tagged in the library so files brought in from a folder stay untouched
+        let brought_in = if video { extract_audio(&options.ffmpeg, &path, &to) } else { fs::copy(&path, &to).map(|_| ()) };
+        if let Err(e) = brought_in {
+            let _ = fs::remove_file(&to);
+            report.failed.push((file, e.to_string()));
+            continue;
+        }
+        let mut warnings = vec![];
+        if let Err(e) = tags.write(&to) {
+            warnings.push(format!("Tags not written: {}", e));
+        }
+        let removed = if keep { Ok(()) } else { fs::remove_file(&path) };
+        if let Err(e) = removed {
+            warnings.push(format!("{} not removed: {}", file, e));
+        }
+        let warning = (!warnings.is_empty()).then(|| warnings.join(", "));
+        report.imported.push(Imported { name, warning });
+    }
+    Ok(report)
+}
+
+/// Downloads or collects `source` and moves the results into `songs_dir`.
+/// Downloads go through a scratch folder next to the songs directory that is removed afterwards.
+/// Whatever was downloaded is imported even when the downloader fails part way, e.g. on one video of a playlist.
+pub fn import(source: &ImportSource, songs_dir: &Path, options: &ImportOptions) -> io::Result<ImportReport> {
+    match source {
+        ImportSource::Folder(dir) => ingest(dir, songs_dir, true, options),
+        ImportSource::Url(url) => {
+            let unique = format!("{}-{}-{}", listening::now(), process::id(), SCRATCH_COUNTER.fetch_add(1, Ordering::Relaxed));
+            let scratch = songs_dir.with_file_name("downloads").join(unique);
+            let downloaded = download(url, &scratch, options);
+            let result = match (ingest(&scratch, songs_dir, false, options), downloaded) {
+                (Ok(mut report), Err(e)) => {
+                    report.failed.push((url.clone(), e.to_string()));
+                    Ok(report)
+                },
+                (Err(_), Err(e)) | (Err(e), Ok(())) => Err(e),
+                (Ok(report), Ok(())) => Ok(report),
+            };
+            if let Err(e) = fs::remove_dir_all(&scratch) {
+                println!("Failed to clean up {}: {}", scratch.display(), e);
+            }
+            result
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("playset-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Stands in for yt-dlp: writes `abc123.mp3` and its info JSON into the folder of the `-o` template
+    #[cfg(unix)]
+    fn stub_downloader(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let script = dir.join("downloader.sh");
+        fs::write(&script, r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-o" ]; then
+        out=$(dirname "$2")
+    fi
+    shift
+done
+printf 'not really audio' > "$out/abc123.mp3"
+printf '{"id": "abc123", "title": "Video title", "track": "Song", "artist": "Someone", "album": "Record", "release_year": 2020, "uploader": "Channel"}' > "$out/abc123.info.json"
+"#).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn downloads_are_tagged_named_and_cleaned_up() {
+        let dir = test_dir("import");
+        let options = ImportOptions { downloader: stub_downloader(&dir), ..Default::default() };
+
+        let fetched = dir.join("fetched");
+        download("https://example.com/watch?v=abc123", &fetched, &options).unwrap();
+        let file = fetched.join("abc123.mp3");
+        let tags = tags_from(&file, info_json(&file).as_ref());
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Someone"));
+        assert_eq!(tags.album.as_deref(), Some("Record"));
+        assert_eq!(tags.year, Some(2020));
+
+        let songs_dir = dir.join("songs");
+        fs::create_dir_all(&songs_dir).unwrap();
+        fs::write(songs_dir.join("Someone - Song.mp3"), "already here").unwrap();
+        let report = import(&ImportSource::Url(String::from("https://example.com/watch?v=abc123")), &songs_dir, &options).unwrap();
+
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        let names = report.imported.iter().map(|i| i.name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, ["Someone - Song (2).mp3"]);
+        assert_eq!(fs::read_to_string(songs_dir.join("Someone - Song (2).mp3")).unwrap(), "not really audio");
+        assert_eq!(fs::read_dir(dir.join("downloads")).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
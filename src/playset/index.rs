@@ -0,0 +1,183 @@
+use std::{collections::HashMap, fs, io, path::Path, time::UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use super::Song;
+use super::genres::GenreTaxonomy;
+use super::fingerprint::Fingerprint;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    song: Song,
+    /// File size and modification time when the song was scanned, to spot changed files
+    size: u64,
+    modified: u64,
+    /// Genre exactly as tagged, `song.genre` holds it normalized
+    #[serde(default)]
+    genre_tag: Option<String>,
+    #[serde(default)]
+    fingerprint: Option<Fingerprint>,
+    /// Tempo and key analysis ran, even if it found neither
+    #[serde(default)]
+    analyzed: bool,
+}
+
+/// Metadata of every file in the universal set directory, saved between runs
+/// so only new or changed files have to be read again on startup.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LibraryIndex {
+    songs: HashMap<String, IndexEntry>,
+}
+
+/// Which files in the songs directory are read as songs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanOptions {
+    /// Look for new and changed files on startup, otherwise the saved index is trusted
+    pub on_startup: bool,
+    /// Lowercase extensions without the dot, empty to try every file
+    pub extensions: Vec<String>,
+    /// Skip dotfiles like .DS_Store
+    pub skip_hidden: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self { on_startup: true, extensions: vec![], skip_hidden: true }
+    }
+}
+
+impl ScanOptions {
+    pub fn accepts(&self, name: &str) -> bool {
+        if self.skip_hidden && name.starts_with('.') {
+            return false;
+        }
+        let extension = Path::new(name).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        self.extensions.is_empty() || self.extensions.iter().any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&extension))
+    }
+}
+
+fn file_stamp<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let modified = meta.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Ok((meta.len(), modified))
+}
+
+impl LibraryIndex {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let out = serde_json::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, out)
+    }
+
+    /// Brings the index in line with `dir`: new and modified files are read, removed ones dropped
+    pub fn scan<P: AsRef<Path>>(&mut self, dir: P, genres: &GenreTaxonomy, options: &ScanOptions) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut seen = vec![];
+
+        for f in fs::read_dir(dir)?.filter_map(|f| f.ok()).filter(|f| f.file_type().is_ok_and(|t| t.is_file())) {
+            let name = f.file_name().into_string().unwrap();
+            if !options.accepts(&name) {
+                continue;
+            }
+            let (size, modified) = file_stamp(f.path())?;
+            seen.push(name.clone());
+
+            if self.songs.get(&name).is_some_and(|e| e.size == size && e.modified == modified) {
+                continue;
+            }
+            if let Err(e) = self.scan_song(dir, &name, genres) {
+                println!("Skipping {}: {}", name, e);
+            }
+        }
+
+        self.songs.retain(|name, _| seen.contains(name));
+        Ok(())
+    }
+
+    /// (Re)reads a single file, e.g. after its tags were edited
+    pub fn scan_song<P: AsRef<Path>>(&mut self, dir: P, name: &str, genres: &GenreTaxonomy) -> io::Result<Song> {
+        let dir = dir.as_ref();
+        let mut song = Song::from_path(dir, name.to_owned()).map_err(io::Error::other)?;
+        let genre_tag = song.genre.clone();
+        song.genre = genres.normalize(&genre_tag);
+        let (size, modified) = file_stamp(dir.join(name))?;
+        // Retagging changes the file but not the audio, so what was derived from it stays valid while the length does
+        // Ratings and user tags only live in the index, so they are kept no matter what changed
+        if let Some(previous) = self.songs.get(name) {
+            song.rating = previous.song.rating;
+            song.loved = previous.song.loved;
+            song.tags = previous.song.tags.clone();
+        }
+        let previous = self.songs.get(name).filter(|e| e.song.duration == song.duration && e.song.codec == song.codec);
+        let fingerprint = previous.and_then(|e| e.fingerprint.clone());
+        let analyzed = previous.is_some_and(|e| e.analyzed);
+        if let Some(previous) = previous {
+            song.bpm = previous.song.bpm;
+            song.key = previous.song.key.clone();
+        }
+        self.songs.insert(name.to_owned(), IndexEntry { song: song.clone(), size, modified, genre_tag: Some(genre_tag), fingerprint, analyzed });
+        Ok(song)
+    }
+
+    /// Normalizes every genre again from the tags, for when the taxonomy was edited since the last run
+    pub fn normalize_genres(&mut self, genres: &GenreTaxonomy) {
+        for entry in self.songs.values_mut() {
+            let tag = entry.genre_tag.get_or_insert_with(|| entry.song.genre.clone());
+            entry.song.genre = genres.normalize(tag);
+        }
+    }
+
+    pub fn fingerprint(&self, name: &str) -> Option<&Fingerprint> {
+        self.songs.get(name).and_then(|e| e.fingerprint.as_ref())
+    }
+
+    /// Songs that have no fingerprint yet
+    pub fn missing_fingerprints(&self) -> impl Iterator<Item = &str> {
+        self.songs.iter().filter(|(_, e)| e.fingerprint.is_none()).map(|(name, _)| name.as_str())
+    }
+
+    /// Songs the tempo and key analysis has not run on yet
+    pub fn unanalyzed(&self) -> impl Iterator<Item = &str> {
+        self.songs.iter()
+            .filter(|(_, e)| !e.analyzed && e.song.bpm.is_none() && e.song.key.is_none())
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn set_analyzed(&mut self, name: &str) {
+        if let Some(entry) = self.songs.get_mut(name) {
+            entry.analyzed = true;
+        }
+    }
+
+    pub fn set_fingerprint(&mut self, name: &str, fingerprint: Fingerprint) {
+        if let Some(entry) = self.songs.get_mut(name) {
+            entry.fingerprint = Some(fingerprint);
+        }
+    }
+
+    /// Replaces the stored metadata of a song already in the index
+    pub fn update(&mut self, song: &Song) {
+        if let Some(entry) = self.songs.get_mut(&song.name) {
+            entry.song = song.clone();
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.songs.remove(name);
+    }
+
+    pub fn song(&self, name: &str) -> Option<&Song> {
+        self.songs.get(name).map(|e| &e.song)
+    }
+
+    pub fn songs(&self) -> impl Iterator<Item = &Song> {
+        self.songs.values().map(|e| &e.song)
+    }
+}
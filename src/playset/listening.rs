@@ -0,0 +1,200 @@
+use std::{collections::HashMap, fs::{self, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+
+use super::Song;
+
+/// Name the scrobble log gives for the player that wrote it
+const CLIENT: &str = "Play set";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlayKind {
+    Start,
+    /// Played to the end, or far enough to count as listened
+    Finish,
+    /// Stopped or replaced before that
+    Skip,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayEvent {
+    pub song: String,
+    pub kind: PlayKind,
+    /// Seconds since the Unix epoch
+    pub at: u64,
+    /// Seconds into the song when the event happened
+    pub position: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SongStats {
+    pub plays: u32,
+    pub skips: u32,
+    pub last_played: Option<u64>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Whether stopping `song` at `position` seconds counts as having listened to it.
+/// Same rule as Last.fm: half the song, or four minutes of longer ones.
+pub fn listened(song: &Song, position: u64) -> bool {
+    song.duration == 0 || position * 2 >= song.duration || position >= 240
+}
+
+/// Every play event so far, appended one JSON object per line to `listens.jsonl`,
+/// plus the scrobble log other tools can submit to Last.fm or ListenBrainz.
+pub struct ListeningLog {
+    pub events: Vec<PlayEvent>,
+    stats: HashMap<String, SongStats>,
+    path: PathBuf,
+    scrobble_path: PathBuf,
+}
+
+impl ListeningLog {
+    pub fn load<P: AsRef<Path>>(path: P, scrobble_path: P) -> io::Result<Self> {
+        let events = match fs::read_to_string(&path) {
+            Ok(s) => s.lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| serde_json::from_str(l).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .collect::<io::Result<Vec<PlayEvent>>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+
+        let mut log = Self {
+            events: vec![],
+            stats: HashMap::new(),
+            path: path.as_ref().to_path_buf(),
+            scrobble_path: scrobble_path.as_ref().to_path_buf(),
+        };
+        for event in events {
+            log.count(&event);
+            log.events.push(event);
+        }
+        Ok(log)
+    }
+
+    fn count(&mut self, event: &PlayEvent) {
+        let stats = self.stats.entry(event.song.clone()).or_default();
+        match event.kind {
+            PlayKind::Start => {},
+            PlayKind::Finish => {
+                stats.plays += 1;
+                stats.last_played = Some(event.at);
+            },
+            PlayKind::Skip => stats.skips += 1,
+        }
+    }
+
+    /// Appends `event` to the log, finished and skipped songs also go to the scrobble log
+    pub fn record(&mut self, event: PlayEvent, song: &Song) -> io::Result<()> {
+        let line = serde_json::to_string(&event).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+
+        if event.kind != PlayKind::Start {
+            self.scrobble(&event, song)?;
+        }
+        self.count(&event);
+        self.events.push(event);
+        Ok(())
+    }
+
+    /// One line in the Audioscrobbler 1.1 portable player format, timestamped with when the song started
+    fn scrobble(&self, event: &PlayEvent, song: &Song) -> io::Result<()> {
+        let new = !self.scrobble_path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.scrobble_path)?;
+        if new {
+            writeln!(file, "#AUDIOSCROBBLER/1.1")?;
+            writeln!(file, "#TZ/UTC")?;
+            writeln!(file, "#CLIENT/{}", CLIENT)?;
+        }
+        let field = |s: &str| s.replace(['\t', '\n'], " ");
+        let rating = if event.kind == PlayKind::Finish { "L" } else { "S" };
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+            field(&song.artist),
+            field(&song.album),
+            field(song.display_title()),
+            song.track_number.map(|t| t.to_string()).unwrap_or_default(),
+            song.duration,
+            rating,
+            event.at.saturating_sub(event.position),
+        )
+    }
+
+    pub fn stats(&self, song: &str) -> SongStats {
+        self.stats.get(song).copied().unwrap_or_default()
+    }
+
+    /// Artists by number of songs listened to since `since`, most played first
+    pub fn top_artists(&self, since: u64, songs: impl Fn(&str) -> Option<Song>) -> Vec<(String, u32)> {
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for event in self.events.iter().filter(|e| e.kind == PlayKind::Finish && e.at >= since) {
+            let artist = songs(&event.song).map(|s| s.artist).filter(|a| !a.is_empty()).unwrap_or_else(|| String::from("Unknown artist"));
+            *counts.entry(artist).or_default() += 1;
+        }
+        let mut counts = counts.into_iter().collect::<Vec<(String, u32)>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Songs by plays, most played first
+    pub fn top_songs(&self) -> Vec<(String, SongStats)> {
+        let mut songs = self.stats.iter().filter(|(_, s)| s.plays > 0).map(|(n, s)| (n.clone(), *s)).collect::<Vec<(String, SongStats)>>();
+        songs.sort_by(|a, b| b.1.plays.cmp(&a.1.plays).then(a.0.cmp(&b.0)));
+        songs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrobble_lines_follow_the_portable_format() {
+        let dir = std::env::temp_dir().join(format!("playset-listening-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (path, scrobbles) = (dir.join("listens.jsonl"), dir.join("scrobbler.log"));
+        let song = Song {
+            name: String::from("a.mp3"),
+            artist: String::from("The\tBand"),
+            album: String::from("Album"),
+            title: String::from("Song"),
+            track_number: Some(2),
+            duration: 200,
+            ..Default::default()
+        };
+
+        let mut log = ListeningLog::load(&path, &scrobbles).unwrap();
+        log.record(PlayEvent { song: song.name.clone(), kind: PlayKind::Start, at: 1000, position: 0 }, &song).unwrap();
+        log.record(PlayEvent { song: song.name.clone(), kind: PlayKind::Finish, at: 1200, position: 200 }, &song).unwrap();
+        log.record(PlayEvent { song: song.name.clone(), kind: PlayKind::Skip, at: 1230, position: 20 }, &song).unwrap();
+
+        let lines = fs::read_to_string(&scrobbles).unwrap();
+        assert_eq!(lines.lines().collect::<Vec<&str>>(), [
+            "#AUDIOSCROBBLER/1.1",
+            "#TZ/UTC",
+            "#CLIENT/Play set",
+            "The Band\tAlbum\tSong\t2\t200\tL\t1000\t",
+            "The Band\tAlbum\tSong\t2\t200\tS\t1210\t",
+        ]);
+
+        let log = ListeningLog::load(&path, &scrobbles).unwrap();
+        let stats = log.stats("a.mp3");
+        assert_eq!((log.events.len(), stats.plays, stats.skips, stats.last_played), (3, 1, 1, Some(1200)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listened_means_half_or_four_minutes() {
+        let song = |duration| Song { duration, ..Default::default() };
+        assert!(listened(&song(200), 100));
+        assert!(!listened(&song(200), 99));
+        assert!(listened(&song(600), 240));
+        assert!(listened(&song(0), 0));
+    }
+}
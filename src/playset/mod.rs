@@ -2,3 +2,37 @@ pub mod playset;
 pub use playset::*;
 
 pub mod pset_format;
+
+pub mod history;
+
+pub mod sampling;
+
+pub mod tag_writer;
+
+pub mod header;
+
+pub mod index;
+
+pub mod rules;
+
+pub mod covers;
+
+pub mod browse;
+
+pub mod genres;
+
+pub mod fingerprint;
+
+pub mod duplicates;
+
+pub mod waveform;
+
+pub mod analysis;
+
+pub mod listening;
+
+pub mod export;
+
+pub mod sync;
+
+pub mod import;
@@ -1,16 +1,67 @@
 use core::panic;
-use std::{cell::RefCell, collections::{HashMap, HashSet}, fs, io, path::Path, rc::Rc, time::Duration};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, fs, hash::{Hash, Hasher}, io, path::{Path, PathBuf}, rc::Rc, time::Duration};
 use audiotags::Tag;
+use serde::{Deserialize, Serialize};
 
 use super::pset_format;
+use super::history::{Edit, History};
+use super::sampling::Sampler;
+use super::tag_writer::{self, TagEdit};
+use super::header;
+use super::analysis::Analysis;
+use super::index::{LibraryIndex, ScanOptions};
+use super::rules::Rule;
+use super::genres::GenreTaxonomy;
+use super::fingerprint::{DbEntry, Fingerprint, FingerprintDb};
+use super::listening::{self, ListeningLog, PlayEvent, PlayKind};
+use super::sync::{DeviceChanges, ReadBack};
 
-#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+/// Songs are identified by their file name alone, so copies holding older metadata still compare equal
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Song {
     pub name: String,
+    pub title: String,
     pub genre: String,
     pub artist: String,
     pub album: String,
+    pub album_artist: String,
+    pub composer: String,
     pub duration: u64,
+    pub track_number: Option<u16>,
+    pub disc_number: Option<u16>,
+    pub year: Option<i32>,
+    /// kbit/s, averaged over the whole file
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub codec: String,
+    pub file_size: u64,
+    /// Filled in by the tempo and key analysis, see `Library::add_analysis`
+    pub bpm: Option<f32>,
+    /// Camelot notation, e.g. "8A"
+    pub key: Option<String>,
+    /// From the listening history, not the file
+    pub play_count: u32,
+    pub skip_count: u32,
+    /// Seconds since the Unix epoch
+    pub last_played: Option<u64>,
+    /// 0-5 stars, 0 for unrated
+    pub rating: u8,
+    pub loved: bool,
+    /// User defined labels like moods or occasions, normalized by `normalize_tag` and sorted
+    pub tags: Vec<String>,
+}
+
+impl PartialEq for Song {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+impl Eq for Song {}
+impl Hash for Song {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
 }
 
 impl Song {
@@ -18,30 +69,94 @@ impl Song {
         let path = p.as_ref().to_str().unwrap();
         let path = format!("{}/{}", path, name);
         println!("Reading song data from: {}", path);
-        let meta = Tag::new().read_from_path(path)?;
-        
+        let meta = Tag::new().read_from_path(&path)?;
+
+        let file_size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        // Tags rarely carry stream details, the container headers do
+        let stream = header::read_path(&path).unwrap_or_else(|e| {
+            println!("Could not read the stream header of {}: {}", path, e);
+            None
+        });
+        let sample_rate = stream.map(|s| s.sample_rate).unwrap_or(0);
+        let duration = meta.duration()
+            .map(Duration::from_secs_f64)
+            .or_else(|| stream.and_then(|s| s.duration))
+            .unwrap_or_default()
+            .as_secs();
+        let bitrate = (file_size * 8).checked_div(duration).map(|b| (b / 1000) as u32).unwrap_or(0);
+        let codec = Path::new(&name).extension().and_then(|e| e.to_str()).unwrap_or("").to_uppercase();
+
         Ok(Self {
-            name,
+            title: meta.title().unwrap_or("").to_owned(),
             genre: meta.genre().unwrap_or("").to_owned(),
             artist: meta.artist().unwrap_or("").to_owned(),
             album: meta.album().map(|a| a.title).unwrap_or("").to_owned(),
-            duration: Duration::from_secs_f64(meta.duration().unwrap_or(0.0)).as_secs(),
+            album_artist: meta.album_artist().unwrap_or("").to_owned(),
+            composer: meta.composer().unwrap_or("").to_owned(),
+            duration,
+            track_number: meta.track_number(),
+            disc_number: meta.disc_number(),
+            year: meta.year(),
+            bitrate,
+            sample_rate,
+            codec,
+            file_size,
+            bpm: None,
+            key: None,
+            play_count: 0,
+            skip_count: 0,
+            last_played: None,
+            rating: 0,
+            loved: false,
+            tags: vec![],
+            name,
         })
     }
+
+    /// The tagged title, or the file name without its extension when there is none
+    pub fn display_title(&self) -> &str {
+        if !self.title.is_empty() {
+            return &self.title;
+        }
+        self.name.rsplit_once('.').map(|(left, _)| left).unwrap_or(&self.name)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum SongSet {
     Terminal(HashSet<Song>),
-    NonTerminal(String)
+    NonTerminal(String),
+    /// Every song in the library matching the rule
+    Rule(Rule),
+    /// Every song in the library carrying the user tag, written `#tag`
+    Tag(String),
+}
+
+/// Lowercase without a leading `#`, with spaces turned into dashes so tags read as one word
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim()
+        .trim_start_matches('#')
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join("-")
+        .chars()
+        .filter(|c| !c.is_control())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
 }
 
 impl SongSet {
-    pub fn flatten(&self, non_term_map: &HashMap<String, Playset>) -> HashSet<Song> {
+    pub fn flatten(&self, library: &Library) -> HashSet<Song> {
         match self {
             SongSet::Terminal(set) => set.clone(),
             SongSet::NonTerminal(name) => {
-                non_term_map.get(name).unwrap().songs.borrow().flatten(non_term_map)
+                library.sets.get(name).unwrap().songs.borrow().flatten(library)
+            },
+            SongSet::Rule(rule) => {
+                library.index.songs().filter(|s| rule.matches(s, &library.genres)).cloned().collect()
+            },
+            SongSet::Tag(tag) => {
+                library.index.songs().filter(|s| s.tags.contains(tag)).cloned().collect()
             },
         }
     }
@@ -60,6 +175,15 @@ impl SongSet {
                 out = name.clone();
                 out.push(pset_format::SEPERATOR);
             },
+            SongSet::Rule(rule) => {
+                out.push(pset_format::RULE_START);
+                out.push_str(&rule.to_string());
+                out.push(pset_format::RULE_END);
+            },
+            SongSet::Tag(tag) => {
+                out = tag.clone();
+                out.push(pset_format::USER_TAG);
+            },
         }
         out
     }
@@ -68,6 +192,12 @@ impl SongSet {
 #[derive(Debug, Clone)]
 pub enum SongTree {
     Operation(char, SongTreeNode),
+    /// Every song of the universal set that is not in the subtree
+    Complement(Rc<SongTree>),
+    /// Union or intersection over any number of subtrees
+    NAry(char, Vec<Rc<SongTree>>),
+    /// Picks a limited selection out of the subtrees, see `Sampler`
+    Sample(Sampler, Vec<Rc<SongTree>>),
     Set(SongSet),
 }
 
@@ -77,21 +207,146 @@ pub struct SongTreeNode {
     rhs: RefCell<Rc<SongTree>>,
 }
 
+impl SongTreeNode {
+    pub fn new(lhs: Rc<SongTree>, rhs: Rc<SongTree>) -> Self {
+        Self {
+            lhs: RefCell::new(lhs),
+            rhs: RefCell::new(rhs),
+        }
+    }
+
+    pub fn lhs(&self) -> Rc<SongTree> {
+        self.lhs.borrow().clone()
+    }
+    pub fn rhs(&self) -> Rc<SongTree> {
+        self.rhs.borrow().clone()
+    }
+}
+
 impl SongTree {
-    pub fn flatten(&self, non_term_map: &HashMap<String, Playset>) -> HashSet<Song> {
+    pub fn flatten(&self, library: &Library) -> HashSet<Song> {
         match self {
             SongTree::Operation(op, song_tree_node) => {
                 let op = match *op {
                     pset_format::UNION => |l: HashSet<Song>, r| l.union(r).map(|s| s.to_owned()).collect(),
                     pset_format::INTERSECTION => |l: HashSet<Song>, r| l.intersection(r).map(|s| s.to_owned()).collect(),
                     pset_format::DIFFERENCE => |l: HashSet<Song>, r| l.difference(r).map(|s| s.to_owned()).collect(),
+                    pset_format::SYMMETRIC_DIFFERENCE => |l: HashSet<Song>, r| l.symmetric_difference(r).map(|s| s.to_owned()).collect(),
                     _ => unreachable!()
                 };
-                op(song_tree_node.lhs.borrow().flatten(non_term_map), &song_tree_node.rhs.borrow().flatten(non_term_map))
+                op(song_tree_node.lhs.borrow().flatten(library), &song_tree_node.rhs.borrow().flatten(library))
+            },
+            SongTree::Complement(tree) => {
+                let excluded = tree.flatten(library);
+                library.universal_set.songs.borrow().flatten(library).into_iter().filter(|s| !excluded.contains(s)).collect()
+            },
+            SongTree::NAry(op, trees) => {
+                let mut sets = trees.iter().map(|t| t.flatten(library));
+                let first = sets.next().unwrap_or_default();
+                match *op {
+                    pset_format::UNION => sets.fold(first, |mut acc, s| {
+                        acc.extend(s);
+                        acc
+                    }),
+                    pset_format::INTERSECTION => sets.fold(first, |mut acc, s| {
+                        acc.retain(|song| s.contains(song));
+                        acc
+                    }),
+                    _ => unreachable!()
+                }
+            },
+            SongTree::Sample(..) => {
+                self.queue(library).into_iter().collect()
             },
             SongTree::Set(song_set) => {
-                song_set.flatten(non_term_map)
+                song_set.flatten(library)
+            },
+        }
+    }
+
+    /// Songs in play order. Samplers decide their own order, everything else is sorted by name.
+    pub fn queue(&self, library: &Library) -> Vec<Song> {
+        match self {
+            SongTree::Sample(sampler, trees) => {
+                sampler.sample(trees.iter().map(|t| t.queue(library)).collect())
+            },
+            _ => {
+                let mut songs = self.flatten(library).into_iter().collect::<Vec<Song>>();
+                songs.sort_by(|a, b| a.name.cmp(&b.name));
+                songs
+            },
+        }
+    }
+
+    /// Direct subtrees, left to right
+    pub fn children(&self) -> Vec<Rc<SongTree>> {
+        match self {
+            SongTree::Operation(_, song_tree_node) => vec![song_tree_node.lhs(), song_tree_node.rhs()],
+            SongTree::Complement(tree) => vec![tree.clone()],
+            SongTree::NAry(_, trees) | SongTree::Sample(_, trees) => trees.clone(),
+            SongTree::Set(_) => vec![],
+        }
+    }
+
+    /// The same node over different subtrees, leaves are returned unchanged
+    pub fn with_children(&self, mut children: Vec<Rc<SongTree>>) -> SongTree {
+        match self {
+            SongTree::Operation(op, _) => {
+                let rhs = children.pop().unwrap();
+                let lhs = children.pop().unwrap();
+                SongTree::Operation(*op, SongTreeNode::new(lhs, rhs))
             },
+            SongTree::Complement(_) => SongTree::Complement(children.pop().unwrap()),
+            SongTree::NAry(op, _) => SongTree::NAry(*op, children),
+            SongTree::Sample(sampler, _) => SongTree::Sample(sampler.clone(), children),
+            SongTree::Set(song_set) => SongTree::Set(song_set.clone()),
+        }
+    }
+
+    pub fn references(&self, name: &str) -> bool {
+        match self {
+            SongTree::Set(SongSet::NonTerminal(n)) => n == name,
+            SongTree::Set(SongSet::Terminal(_) | SongSet::Rule(_) | SongSet::Tag(_)) => false,
+            _ => self.children().iter().any(|c| c.references(name)),
+        }
+    }
+
+    /// Names of every set referenced directly by this tree
+    pub fn referenced_sets(&self) -> HashSet<String> {
+        match self {
+            SongTree::Set(SongSet::NonTerminal(n)) => HashSet::from([n.clone()]),
+            SongTree::Set(SongSet::Terminal(_) | SongSet::Rule(_) | SongSet::Tag(_)) => HashSet::new(),
+            _ => self.children().iter().flat_map(|c| c.referenced_sets()).collect(),
+        }
+    }
+
+    /// Rebuilds the tree with every reference to `name` swapped out for `with`
+    pub fn replace_reference(&self, name: &str, with: &SongTree) -> SongTree {
+        match self {
+            SongTree::Set(SongSet::NonTerminal(n)) if n == name => with.clone(),
+            _ => self.with_children(self.children().iter().map(|c| Rc::new(c.replace_reference(name, with))).collect()),
+        }
+    }
+
+    /// Rebuilds the tree with any copy of `song` in its terminal sets swapped for the new metadata
+    pub fn replace_song(&self, song: &Song) -> SongTree {
+        match self {
+            SongTree::Set(SongSet::Terminal(set)) if set.iter().any(|s| s.name == song.name) => {
+                SongTree::Set(SongSet::Terminal(set.iter().map(|s| if s.name == song.name { song.clone() } else { s.clone() }).collect()))
+            },
+            _ => self.with_children(self.children().iter().map(|c| Rc::new(c.replace_song(song))).collect()),
+        }
+    }
+
+    /// Rebuilds the tree with every song named in `merged` swapped for `keep` in its terminal sets
+    pub fn merge_songs(&self, merged: &HashSet<String>, keep: &Song) -> SongTree {
+        match self {
+            SongTree::Set(SongSet::Terminal(set)) if set.iter().any(|s| merged.contains(&s.name)) => {
+                let mut set = set.iter().filter(|s| !merged.contains(&s.name)).cloned().collect::<HashSet<Song>>();
+                set.insert(keep.clone());
+                SongTree::Set(SongSet::Terminal(set))
+            },
+            _ => self.with_children(self.children().iter().map(|c| Rc::new(c.merge_songs(merged, keep))).collect()),
         }
     }
 
@@ -105,29 +360,89 @@ impl SongTree {
                     op
                 )
             },
+            SongTree::Complement(tree) => {
+                format!("{}{}", tree.to_pset_string(), pset_format::COMPLEMENT)
+            },
+            SongTree::NAry(op, trees) => {
+                let mut out = String::from(pset_format::GROUP_START);
+                for tree in trees {
+                    out.push_str(&tree.to_pset_string());
+                }
+                out.push(if *op == pset_format::UNION { pset_format::NARY_UNION } else { pset_format::NARY_INTERSECTION });
+                out
+            },
+            SongTree::Sample(sampler, trees) => {
+                let mut out = String::new();
+                if let Sampler::Weighted { .. } = sampler {
+                    out.push(pset_format::GROUP_START);
+                }
+                for tree in trees {
+                    out.push_str(&tree.to_pset_string());
+                }
+                out.push_str(&sampler.params());
+                out.push(sampler.opcode());
+                out
+            },
             SongTree::Set(song_set) => {
                 song_set.to_pset_string()
             },
         }
     }
-    pub fn from_pset_string(s: &str) -> Self {
+    /// Songs of terminal sets are looked up in `index`, names it doesn't know are dropped
+    pub fn from_pset_string(s: &str, index: &LibraryIndex) -> Self {
         println!("from_pset:\n{}", s);
         let mut parse_stack: Vec<SongTree> = vec![];
         let mut set_buffer = HashSet::<Song>::new();
         let mut name_buffer = String::new();
+        let mut group_starts: Vec<usize> = vec![];
 
         let mut collecting_set = false;
+        let mut collecting_rule = false;
 
         for c in s.chars() {
             match c {
+                pset_format::RULE_END if collecting_rule => {
+                    collecting_rule = false;
+                    match Rule::parse(&name_buffer) {
+                        Ok(rule) => parse_stack.push(SongTree::Set(SongSet::Rule(rule))),
+                        Err(e) => {
+                            eprintln!("{} is not a valid rule ({}), using an empty set", name_buffer, e);
+                            parse_stack.push(SongTree::Set(SongSet::Terminal(HashSet::new())));
+                        },
+                    }
+                    name_buffer = String::new();
+                }
+                c if collecting_rule => {
+                    name_buffer.push(c);
+                }
+                pset_format::RULE_START => {
+                    collecting_rule = true;
+                }
+
                 pset_format::SEPERATOR if collecting_set => {
-                    set_buffer.insert(Song::from_path("song_library/U/", name_buffer).unwrap());
+                    match index.song(&name_buffer) {
+                        Some(song) => {
+                            set_buffer.insert(song.clone());
+                        },
+                        None => println!("{} is not in the library, dropping it", name_buffer),
+                    }
                     name_buffer = String::new();
                 }
                 pset_format::SEPERATOR => {
                     parse_stack.push(SongTree::Set(SongSet::NonTerminal(name_buffer)));
                     name_buffer = String::new();
                 }
+                pset_format::USER_TAG => {
+                    // Songs only ever carry normalized tags, a hand edited leaf would otherwise match nothing
+                    let tag = normalize_tag(&name_buffer);
+                    if tag.is_empty() {
+                        eprintln!("{:?} is not a valid tag, using an empty set", name_buffer);
+                        parse_stack.push(SongTree::Set(SongSet::Terminal(HashSet::new())));
+                    } else {
+                        parse_stack.push(SongTree::Set(SongSet::Tag(tag)));
+                    }
+                    name_buffer = String::new();
+                }
 
                 pset_format::SET_START => {
                     collecting_set = true;
@@ -138,7 +453,7 @@ impl SongTree {
                     set_buffer = HashSet::new();
                 }
 
-                pset_format::UNION..=pset_format::DIFFERENCE => {
+                pset_format::UNION..=pset_format::SYMMETRIC_DIFFERENCE => {
                     let right = parse_stack.pop().unwrap();
                     let left = parse_stack.pop().unwrap();
                     parse_stack.push(SongTree::Operation(c, SongTreeNode {
@@ -147,6 +462,53 @@ impl SongTree {
                     }));
                 }
 
+                pset_format::COMPLEMENT => {
+                    let tree = parse_stack.pop().unwrap();
+                    parse_stack.push(SongTree::Complement(Rc::new(tree)));
+                }
+
+                pset_format::GROUP_START => {
+                    group_starts.push(parse_stack.len());
+                }
+                pset_format::NARY_UNION | pset_format::NARY_INTERSECTION => {
+                    let start = group_starts.pop().unwrap();
+                    let trees = parse_stack.split_off(start).into_iter().map(Rc::new).collect();
+                    let op = if c == pset_format::NARY_UNION { pset_format::UNION } else { pset_format::INTERSECTION };
+                    parse_stack.push(SongTree::NAry(op, trees));
+                }
+
+                pset_format::TAKE | pset_format::RANDOM_SAMPLE | pset_format::DURATION_CAP => {
+                    let tree = parse_stack.pop().unwrap();
+                    match Sampler::from_pset(c, &name_buffer) {
+                        Some(sampler) => parse_stack.push(SongTree::Sample(sampler, vec![Rc::new(tree)])),
+                        None => {
+                            eprintln!("{}{} is not a valid sampler, keeping the songs unsampled", name_buffer, c);
+                            parse_stack.push(tree);
+                        },
+                    }
+                    name_buffer = String::new();
+                }
+                pset_format::WEIGHTED_MIX => {
+                    let start = group_starts.pop().unwrap();
+                    let trees: Vec<_> = parse_stack.split_off(start).into_iter().map(Rc::new).collect();
+                    match Sampler::from_pset(c, &name_buffer) {
+                        // The tree editor pairs weights with parts by position, so every part gets exactly one
+                        Some(Sampler::Weighted { count, seed, mut weights }) => {
+                            if weights.len() != trees.len() {
+                                eprintln!("{}{} has {} weights for {} parts, giving missing parts a weight of 1", name_buffer, c, weights.len(), trees.len());
+                                weights.resize(trees.len(), 1);
+                            }
+                            parse_stack.push(SongTree::Sample(Sampler::Weighted { count, seed, weights }, trees));
+                        },
+                        Some(sampler) => parse_stack.push(SongTree::Sample(sampler, trees)),
+                        None => {
+                            eprintln!("{}{} is not a valid sampler, using the union of its parts", name_buffer, c);
+                            parse_stack.push(SongTree::NAry(pset_format::UNION, trees));
+                        },
+                    }
+                    name_buffer = String::new();
+                }
+
                 c => {
                     name_buffer.push(c);
                 }
@@ -165,15 +527,11 @@ pub struct Playset {
     pub songs: RefCell<Rc<SongTree>>,
 }
 impl Playset {
-    /// just put "song_library/" as the param
-    pub fn write_to_file<P: AsRef<Path>>(&self, song_library: P) -> io::Result<()> {
-        let mut output_path = song_library.as_ref().to_str().unwrap().to_owned();
-        output_path.push_str("subsets/");
-        output_path.push_str(&self.name);
-
+    /// Writes the set into `subsets_dir` under its name
+    pub fn write_to_file<P: AsRef<Path>>(&self, subsets_dir: P) -> io::Result<()> {
         let out = self.songs.borrow().to_pset_string();
 
-        fs::write(output_path, out)?;
+        fs::write(subsets_dir.as_ref().join(&self.name), out)?;
 
         Ok(())
     }
@@ -184,30 +542,77 @@ impl Playset {
             songs: RefCell::new(Rc::new(SongTree::Set(SongSet::Terminal(HashSet::new())))),
         }
     }
-    pub fn from_pset_string(s: &str, name: String) -> Self {
+    pub fn from_pset_string(s: &str, name: String, index: &LibraryIndex) -> Self {
         Self {
             name,
-            songs: RefCell::new(Rc::new(SongTree::from_pset_string(s)))
+            songs: RefCell::new(Rc::new(SongTree::from_pset_string(s, index)))
+        }
+    }
+}
+
+fn apply_stats(mut song: Song, listening: &ListeningLog) -> Song {
+    let stats = listening.stats(&song.name);
+    song.play_count = stats.plays;
+    song.skip_count = stats.skips;
+    song.last_played = stats.last_played;
+    song
+}
+
+/// Moves the files `names` from `from` into `to`, putting back the ones already moved if any of them fails
+fn move_files(names: &[String], from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for (i, name) in names.iter().enumerate() {
+        if let Err(e) = fs::rename(from.join(name), to.join(name)) {
+            for moved in &names[..i] {
+                if let Err(e) = fs::rename(to.join(moved), from.join(moved)) {
+                    println!("Failed to move {} back: {}", moved, e);
+                }
+            }
+            return Err(e);
         }
     }
+    Ok(())
 }
 
 pub struct Library {
     pub universal_set: Playset,
-    pub sets: HashMap<String, Playset>
+    pub sets: HashMap<String, Playset>,
+    pub history: History,
+    pub listening: ListeningLog,
+    pub index: LibraryIndex,
+    pub genres: GenreTaxonomy,
+    /// Bumped on every change to the sets or songs, for anything caching what it derived from them
+    pub revision: u64,
+    /// Bumped when play and skip counts change, apart from `revision` so caches survive every song that ends
+    pub stats_revision: u64,
+    universal_dir: PathBuf,
+    subsets_dir: PathBuf,
+    history_path: PathBuf,
+    index_path: PathBuf,
 }
 impl Library {
-    pub fn initialize<P: AsRef<Path>>(universal_set: P, subsets: P) -> io::Result<Self> {
-        let universal_dir = fs::read_dir(universal_set)?;
+    pub fn initialize<P: AsRef<Path>>(universal_set: P, subsets: P, scan: &ScanOptions) -> io::Result<Self> {
+        let universal_path = universal_set.as_ref().to_path_buf();
+        let subsets_dir = subsets.as_ref().to_path_buf();
+        let history_path = subsets_dir.with_file_name("history.json");
+        let index_path = subsets_dir.with_file_name("index.json");
+        let genres = GenreTaxonomy::load(subsets_dir.with_file_name("genres.json"))?;
         let subset_dir = fs::read_dir(subsets)?;
 
-        let universal_set = universal_dir
-            .map(|f| f.unwrap().path())
-            .map(|p| {
-                let dir = p.parent().unwrap();
-                let f_name = p.file_name().unwrap().to_str().unwrap();
-                Song::from_path(dir, f_name.to_owned()).unwrap()
-            }).collect::<HashSet<Song>>();
+        let mut index = LibraryIndex::load(&index_path)?;
+        index.normalize_genres(&genres);
+        if scan.on_startup || index.songs().next().is_none() {
+            index.scan(&universal_path, &genres, scan)?;
+            index.save(&index_path)?;
+        }
+
+        let listening = ListeningLog::load(subsets_dir.with_file_name("listens.jsonl"), subsets_dir.with_file_name(".scrobbler.log"))?;
+        let with_stats = index.songs().map(|s| apply_stats(s.clone(), &listening)).collect::<Vec<Song>>();
+        for song in &with_stats {
+            index.update(song);
+        }
+
+        let universal_set = index.songs().cloned().collect::<HashSet<Song>>();
         let universal_set = Playset {
             name: "U".to_owned(),
             songs: RefCell::new(Rc::new(SongTree::Set(SongSet::Terminal(universal_set))))
@@ -220,20 +625,903 @@ impl Library {
             .filter(|f| f.file_type().unwrap().is_file())
             .map(|f| (f.file_name().into_string().unwrap(), fs::read_to_string(f.path()).unwrap()))
         {
-            sets.insert(name.clone(), Playset::from_pset_string(&file, name));
+            sets.insert(name.clone(), Playset::from_pset_string(&file, name, &index));
         }
 
         println!("Sets:\n{:#?}", sets);
 
+        let history = History::load(&history_path)?;
+
         Ok(Self {
             universal_set,
-            sets
+            sets,
+            history,
+            listening,
+            index,
+            genres,
+            revision: 0,
+            stats_revision: 0,
+            universal_dir: universal_path,
+            subsets_dir,
+            history_path,
+            index_path,
         })
     }
 
     pub fn push_empty_set(&mut self, name: String) {
         self.sets.insert(name.clone(), Playset::empty_terminal(name));
     }
+
+    pub fn song_path(&self, name: &str) -> PathBuf {
+        self.universal_dir.join(name)
+    }
+
+    pub fn songs_dir(&self) -> &Path {
+        &self.universal_dir
+    }
+
+    pub fn song_by_name(&self, name: &str) -> Option<Song> {
+        self.index.song(name).cloned()
+    }
+
+    /// Names of the other sets whose trees reference `name`
+    pub fn referencing_sets(&self, name: &str) -> Vec<String> {
+        let mut out = self.sets
+            .iter()
+            .filter(|(n, p)| n.as_str() != name && p.songs.borrow().references(name))
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<String>>();
+        out.sort();
+        out
+    }
+
+    /// `base`, or `base` with the first number appended that no set is using yet
+    pub fn free_name(&self, base: &str) -> String {
+        let mut name = base.to_owned();
+        let mut n = 2;
+        while self.sets.contains_key(&name) {
+            name = format!("{} {}", base, n);
+            n += 1;
+        }
+        name
+    }
+
+    /// Writes `edit` to the files of `songs` and refreshes their metadata everywhere in the library.
+    /// Sets are evaluated on demand, so anything derived from the songs picks up the change on its next flatten.
+    pub fn edit_tags(&mut self, songs: &[String], edit: &TagEdit) -> io::Result<()> {
+        for name in songs {
+            edit.write(self.universal_dir.join(name))?;
+            self.rescan(name)?;
+        }
+        self.revision += 1;
+        self.index.save(&self.index_path)
+    }
+
+    /// Sets the star rating of `name`, with `write_tag` also into the file's POPM frame where it has one
+    pub fn set_rating(&mut self, name: &str, stars: u8, write_tag: bool) -> io::Result<()> {
+        let Some(mut song) = self.song_by_name(name) else {
+            return Ok(());
+        };
+        song.rating = stars.min(5);
+        self.index.update(&song);
+        self.update_song(&song);
+        if write_tag && tag_writer::write_rating(self.song_path(name), song.rating)? {
+            self.rescan(name)?;
+        }
+        self.revision += 1;
+        self.index.save(&self.index_path)
+    }
+
+    pub fn set_loved(&mut self, name: &str, loved: bool) -> io::Result<()> {
+        let Some(mut song) = self.song_by_name(name) else {
+            return Ok(());
+        };
+        song.loved = loved;
+        self.index.update(&song);
+        self.update_song(&song);
+        self.revision += 1;
+        self.index.save(&self.index_path)
+    }
+
+    /// Adds `tag` to every song in `songs`
+    pub fn add_user_tag(&mut self, songs: &[String], tag: &str) -> io::Result<()> {
+        let tag = normalize_tag(tag);
+        if tag.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Tags can't be empty"));
+        }
+        self.change_user_tags(songs, |tags| {
+            if !tags.contains(&tag) {
+                tags.push(tag.clone());
+                tags.sort();
+            }
+        })
+    }
+
+    pub fn remove_user_tag(&mut self, songs: &[String], tag: &str) -> io::Result<()> {
+        self.change_user_tags(songs, |tags| tags.retain(|t| t != tag))
+    }
+
+    fn change_user_tags(&mut self, songs: &[String], change: impl Fn(&mut Vec<String>)) -> io::Result<()> {
+        for name in songs {
+            let Some(mut song) = self.song_by_name(name) else {
+                continue;
+            };
+            change(&mut song.tags);
+            self.index.update(&song);
+            self.update_song(&song);
+        }
+        self.revision += 1;
+        self.index.save(&self.index_path)
+    }
+
+    /// Every user tag in the library with how many songs carry it, by name
+    pub fn user_tags(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for tag in self.index.songs().flat_map(|s| s.tags.iter()) {
+            *counts.entry(tag.clone()).or_default() += 1;
+        }
+        let mut counts = counts.into_iter().collect::<Vec<(String, usize)>>();
+        counts.sort();
+        counts
+    }
+
+    /// Reads a song's file again after it was written to
+    fn rescan(&mut self, name: &str) -> io::Result<Song> {
+        let song = apply_stats(self.index.scan_song(&self.universal_dir, name, &self.genres)?, &self.listening);
+        self.index.update(&song);
+        self.update_song(&song);
+        Ok(song)
+    }
+
+    /// Logs that playback of `name` started, finished or was skipped `position` seconds in
+    pub fn record_play(&mut self, name: &str, kind: PlayKind, position: u64) -> io::Result<()> {
+        self.record_play_at(name, kind, listening::now(), position)
+    }
+
+    /// Same as `record_play` for a play that happened at `at`, e.g. on a synced device
+    pub fn record_play_at(&mut self, name: &str, kind: PlayKind, at: u64, position: u64) -> io::Result<()> {
+        let Some(song) = self.song_by_name(name) else {
+            return Ok(());
+        };
+        let event = PlayEvent { song: name.to_owned(), kind, at, position };
+        self.listening.record(event, &song)?;
+        if kind != PlayKind::Start {
+            let song = apply_stats(song, &self.listening);
+            self.index.update(&song);
+            self.update_song(&song);
+            self.stats_revision += 1;
+        }
+        Ok(())
+    }
+
+    /// Takes in the plays and ratings `read_device` found on a synced device with one save of the index
+    pub fn add_device_changes(&mut self, changes: DeviceChanges) -> io::Result<ReadBack> {
+        let read_back = ReadBack { plays: changes.plays.len(), ratings: changes.ratings.len() };
+        for play in changes.plays {
+            self.record_play_at(&play.song, play.kind, play.at, play.position)?;
+        }
+        for (name, stars) in changes.ratings {
+            let Some(mut song) = self.song_by_name(&name) else {
+                continue;
+            };
+            song.rating = stars.min(5);
+            self.index.update(&song);
+            self.update_song(&song);
+        }
+        if read_back.ratings > 0 {
+            self.revision += 1;
+        }
+        if read_back.plays + read_back.ratings > 0 {
+            self.index.save(&self.index_path)?;
+        }
+        Ok(read_back)
+    }
+
+    /// Songs without a fingerprint in the index and their files, for `Fingerprint::from_file` to read off the UI thread
+    pub fn missing_fingerprints(&self) -> Vec<(String, PathBuf)> {
+        self.index.missing_fingerprints().map(|name| (name.to_owned(), self.song_path(name))).collect()
+    }
+
+    /// Stores fingerprints computed from `missing_fingerprints`, returns how many were added
+    pub fn add_fingerprints(&mut self, fingerprints: Vec<(String, Fingerprint)>) -> io::Result<usize> {
+        let added = fingerprints.len();
+        for (name, fingerprint) in fingerprints {
+            self.index.set_fingerprint(&name, fingerprint);
+        }
+        if added > 0 {
+            self.revision += 1;
+            self.index.save(&self.index_path)?;
+        }
+        Ok(added)
+    }
+
+    /// Fills in missing tags of songs without a title or artist from the closest match in the
+    /// local fingerprint database. Tagged songs of the library are added to the database first,
+    /// so untagged copies of them are recognized too. Only songs fingerprinted already take part,
+    /// see `missing_fingerprints`. Returns the songs that were identified.
+    pub fn identify_untagged(&mut self) -> io::Result<Vec<String>> {
+        let db_path = self.subsets_dir.with_file_name("fingerprints.json");
+        let mut db = FingerprintDb::load(&db_path)?;
+
+        let mut learned = false;
+        for song in self.index.songs().filter(|s| !s.title.is_empty() && !s.artist.is_empty()) {
+            let known = db.entries.iter().any(|e| e.title == song.title && e.artist == song.artist);
+            if let (false, Some(fingerprint)) = (known, self.index.fingerprint(&song.name)) {
+                db.entries.push(DbEntry {
+                    title: song.title.clone(),
+                    artist: song.artist.clone(),
+                    album: song.album.clone(),
+                    genre: song.genre.clone(),
+                    year: song.year,
+                    track_number: song.track_number,
+                    fingerprint: fingerprint.clone(),
+                });
+                learned = true;
+            }
+        }
+        if learned {
+            db.save(&db_path)?;
+        }
+
+        let untagged = self.index.songs().filter(|s| s.title.is_empty() || s.artist.is_empty()).cloned().collect::<Vec<Song>>();
+        let mut identified = vec![];
+        for song in untagged {
+            let Some(entry) = self.index.fingerprint(&song.name).and_then(|f| db.identify(f)) else {
+                continue;
+            };
+            let missing = |have: &str, found: &str| (have.is_empty() && !found.is_empty()).then(|| found.to_owned());
+            let edit = TagEdit {
+                title: missing(&song.title, &entry.title),
+                artist: missing(&song.artist, &entry.artist),
+                album: missing(&song.album, &entry.album),
+                genre: missing(&song.genre, &entry.genre),
+                track_number: song.track_number.or(entry.track_number).filter(|_| song.track_number.is_none()),
+                year: song.year.or(entry.year).filter(|_| song.year.is_none()),
+                cover: None,
+            };
+            if edit.is_empty() {
+                continue;
+            }
+            println!("Identified {} as {} - {}", song.name, entry.artist, entry.title);
+            self.edit_tags(std::slice::from_ref(&song.name), &edit)?;
+            identified.push(song.name);
+        }
+        Ok(identified)
+    }
+
+    /// Songs the tempo and key analysis has not run on and their files, for `analysis::analyze` to read off the UI thread
+    pub fn unanalyzed_songs(&self) -> Vec<(String, PathBuf)> {
+        self.index.unanalyzed().map(|name| (name.to_owned(), self.song_path(name))).collect()
+    }
+
+    /// Stores the results of `analysis::analyze` for songs from `unanalyzed_songs`, returns how many were analyzed.
+    /// Songs that failed are marked too so they are not decoded again, until their file changes.
+    pub fn add_analysis(&mut self, results: Vec<(String, io::Result<Analysis>)>) -> io::Result<usize> {
+        let mut analyzed = 0;
+        for (name, result) in &results {
+            self.index.set_analyzed(name);
+            let Some(mut song) = self.song_by_name(name) else {
+                continue;
+            };
+            match result {
+                Ok((bpm, key)) => {
+                    println!("{}: {:?} BPM, key {:?}", name, bpm, key);
+                    song.bpm = *bpm;
+                    song.key = key.clone();
+                    self.index.update(&song);
+                    self.update_song(&song);
+                    analyzed += 1;
+                },
+                Err(e) => println!("Failed to analyze {}: {}", name, e),
+            }
+        }
+        if !results.is_empty() {
+            self.revision += 1;
+            self.index.save(&self.index_path)?;
+        }
+        Ok(analyzed)
+    }
+
+    /// Keeps `keep` as the only copy of a song: every terminal set holding one of `others` gets `keep` instead,
+    /// and the files of `others` are moved out of the library into a `duplicates` directory beside it.
+    /// Undo moves the files back and restores the sets.
+    pub fn merge_duplicates(&mut self, keep: &str, others: &[String]) -> io::Result<()> {
+        let keep = self.song_by_name(keep).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the library", keep)))?;
+        let mut merged = others.iter().filter(|s| **s != keep.name).cloned().collect::<Vec<String>>();
+        merged.sort();
+        merged.dedup();
+        let merging = merged.iter().cloned().collect::<HashSet<String>>();
+
+        let mut sets = vec![];
+        for (name, playset) in &self.sets {
+            let tree = playset.songs.borrow().clone();
+            let before = tree.to_pset_string();
+            let after = tree.merge_songs(&merging, &keep).to_pset_string();
+            if after != before {
+                sets.push((name.clone(), before, after));
+            }
+        }
+        self.execute(Edit::MergeDuplicates { merged, sets })
+    }
+
+    fn duplicates_dir(&self) -> PathBuf {
+        self.universal_dir.with_file_name("duplicates")
+    }
+
+    /// Reads files newly put into the songs directory and adds them to the universal set
+    pub fn add_files(&mut self, names: &[String]) -> io::Result<Vec<Song>> {
+        let mut added = vec![];
+        for name in names {
+            let song = apply_stats(self.index.scan_song(&self.universal_dir, name, &self.genres)?, &self.listening);
+            self.index.update(&song);
+            added.push(song);
+        }
+        let universal = self.index.songs().cloned().collect::<HashSet<Song>>();
+        *self.universal_set.songs.borrow_mut() = Rc::new(SongTree::Set(SongSet::Terminal(universal)));
+
+        self.revision += 1;
+        self.index.save(&self.index_path)?;
+        Ok(added)
+    }
+
+    fn update_song(&mut self, song: &Song) {
+        let universal = self.universal_set.songs.borrow().replace_song(song);
+        *self.universal_set.songs.borrow_mut() = Rc::new(universal);
+        for playset in self.sets.values() {
+            let tree = playset.songs.borrow().replace_song(song);
+            *playset.songs.borrow_mut() = Rc::new(tree);
+        }
+    }
+
+    /// Whether `set` is `on` or pulls in `on` through any chain of references.
+    /// Making `on` reference `set` would then create a cycle.
+    pub fn depends_on(&self, set: &str, on: &str) -> bool {
+        if set == on {
+            return true;
+        }
+        match self.sets.get(set) {
+            Some(playset) => playset.songs.borrow().referenced_sets().iter().any(|s| self.depends_on(s, on)),
+            None => false,
+        }
+    }
+
+    pub fn create_set(&mut self, name: String) -> io::Result<()> {
+        self.check_new_name(&name)?;
+        self.execute(Edit::CreateSet { name, contents: None })
+    }
+    /// Creates `name` already holding `tree`, e.g. an album picked in the browser
+    pub fn create_set_with(&mut self, name: String, tree: SongTree) -> io::Result<()> {
+        self.check_new_name(&name)?;
+        self.execute(Edit::CreateSet { name, contents: Some(tree.to_pset_string()) })
+    }
+    /// Refuses to delete a set other sets still reference, see `delete_set_inlined`
+    pub fn delete_set(&mut self, name: &str) -> io::Result<()> {
+        let referenced_by = self.referencing_sets(name);
+        if !referenced_by.is_empty() {
+            return Err(io::Error::other(format!("{} is used by {}", name, referenced_by.join(", "))));
+        }
+        let contents = self.set_contents(name);
+        self.execute(Edit::DeleteSet { name: name.to_owned(), contents, inlined_into: vec![] })
+    }
+    /// Deletes `name`, replacing every reference to it with a copy of its current songs
+    pub fn delete_set_inlined(&mut self, name: &str) -> io::Result<()> {
+        let inlined_into = self.referencing_sets(name)
+            .into_iter()
+            .map(|s| {
+                let contents = self.set_contents(&s);
+                (s, contents)
+            }).collect();
+        let contents = self.set_contents(name);
+        self.execute(Edit::DeleteSet { name: name.to_owned(), contents, inlined_into })
+    }
+    /// Renames `from` and rewrites every set that references it
+    pub fn rename_set(&mut self, from: &str, to: String) -> io::Result<()> {
+        self.check_new_name(&to)?;
+        self.execute(Edit::RenameSet { from: from.to_owned(), to })
+    }
+    pub fn duplicate_set(&mut self, from: &str, to: String) -> io::Result<()> {
+        self.check_new_name(&to)?;
+        self.execute(Edit::DuplicateSet { from: from.to_owned(), to })
+    }
+    pub fn add_songs(&mut self, set: &str, songs: Vec<String>) -> io::Result<()> {
+        let before = self.set_contents(set);
+        self.execute(Edit::AddSongs { set: set.to_owned(), songs, before })
+    }
+    pub fn remove_songs(&mut self, set: &str, songs: Vec<String>) -> io::Result<()> {
+        let before = self.set_contents(set);
+        self.execute(Edit::RemoveSongs { set: set.to_owned(), songs, before })
+    }
+    /// Replaces `set` with `set OP other`
+    pub fn apply_operation(&mut self, set: &str, op: char, other: String) -> io::Result<()> {
+        if self.depends_on(&other, set) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} already depends on {}", other, set)));
+        }
+        let before = self.set_contents(set);
+        self.execute(Edit::ApplyOperation { set: set.to_owned(), op, other, before })
+    }
+
+    /// Replaces the whole tree of `set`, e.g. after editing it in the expression editor
+    pub fn replace_set_tree(&mut self, set: &str, tree: SongTree) -> io::Result<()> {
+        let cycle = tree.referenced_sets().iter().find(|s| self.depends_on(s, set)).cloned();
+        if let Some(other) = cycle {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} already depends on {}", other, set)));
+        }
+        let before = self.set_contents(set);
+        let after = tree.to_pset_string();
+        self.replace_tree(set, tree)?;
+        self.revision += 1;
+        self.history.record(Edit::Tree { set: set.to_owned(), before, after });
+        self.history.save(&self.history_path)
+    }
+
+    /// Returns the edit that was undone, None when there was nothing to undo
+    pub fn undo(&mut self) -> io::Result<Option<Edit>> {
+        let Some(edit) = self.history.pop_undo() else {
+            return Ok(None);
+        };
+        self.revert(&edit)?;
+        self.revision += 1;
+        self.history.push_redo(edit.clone());
+        self.history.save(&self.history_path)?;
+        Ok(Some(edit))
+    }
+    /// Returns the edit that was redone, None when there was nothing to redo
+    pub fn redo(&mut self) -> io::Result<Option<Edit>> {
+        let Some(edit) = self.history.pop_redo() else {
+            return Ok(None);
+        };
+        self.perform(&edit)?;
+        self.revision += 1;
+        self.history.push_undo(edit.clone());
+        self.history.save(&self.history_path)?;
+        Ok(Some(edit))
+    }
+
+    fn execute(&mut self, edit: Edit) -> io::Result<()> {
+        self.perform(&edit)?;
+        self.revision += 1;
+        self.history.record(edit);
+        self.history.save(&self.history_path)
+    }
+
+    fn perform(&mut self, edit: &Edit) -> io::Result<()> {
+        match edit {
+            Edit::CreateSet { name, contents } => {
+                match contents {
+                    Some(contents) => {
+                        self.sets.insert(name.clone(), Playset::from_pset_string(contents, name.clone(), &self.index));
+                    },
+                    None => self.push_empty_set(name.clone()),
+                }
+                self.save_set(name)
+            },
+            Edit::DeleteSet { name, inlined_into, .. } => {
+                if !inlined_into.is_empty() {
+                    let songs = self.sets.get(name).unwrap().songs.borrow().flatten(self);
+                    let songs = SongTree::Set(SongSet::Terminal(songs));
+                    for (set, _) in inlined_into {
+                        let tree = self.sets.get(set).unwrap().songs.borrow().replace_reference(name, &songs);
+                        self.replace_tree(set, tree)?;
+                    }
+                }
+                self.sets.remove(name);
+                self.remove_set_file(name)
+            },
+            Edit::RenameSet { from, to } => {
+                self.move_set(from, to)
+            },
+            Edit::DuplicateSet { from, to } => {
+                let songs = self.sets.get(from).unwrap().songs.borrow().clone();
+                self.sets.insert(to.clone(), Playset { name: to.clone(), songs: RefCell::new(songs) });
+                self.save_set(to)
+            },
+            Edit::AddSongs { set, songs, .. } => {
+                let songs = songs.iter().filter_map(|s| self.song_by_name(s)).collect::<HashSet<Song>>();
+                let tree = self.sets.get(set).unwrap().songs.borrow().clone();
+                let tree = match &*tree {
+                    SongTree::Set(SongSet::Terminal(current)) => {
+                        SongTree::Set(SongSet::Terminal(current.union(&songs).cloned().collect()))
+                    },
+                    _ => SongTree::Operation(pset_format::UNION, SongTreeNode::new(tree, Rc::new(SongTree::Set(SongSet::Terminal(songs))))),
+                };
+                self.replace_tree(set, tree)
+            },
+            Edit::RemoveSongs { set, songs, .. } => {
+                let tree = self.sets.get(set).unwrap().songs.borrow().clone();
+                let tree = match &*tree {
+                    SongTree::Set(SongSet::Terminal(current)) => {
+                        SongTree::Set(SongSet::Terminal(current.iter().filter(|s| !songs.contains(&s.name)).cloned().collect()))
+                    },
+                    _ => {
+                        let songs = songs.iter().filter_map(|s| self.song_by_name(s)).collect::<HashSet<Song>>();
+                        SongTree::Operation(pset_format::DIFFERENCE, SongTreeNode::new(tree, Rc::new(SongTree::Set(SongSet::Terminal(songs)))))
+                    },
+                };
+                self.replace_tree(set, tree)
+            },
+            Edit::ApplyOperation { set, op, other, .. } => {
+                let tree = self.sets.get(set).unwrap().songs.borrow().clone();
+                let other = Rc::new(SongTree::Set(SongSet::NonTerminal(other.clone())));
+                self.replace_tree(set, SongTree::Operation(*op, SongTreeNode::new(tree, other)))
+            },
+            Edit::Tree { set, after, .. } => {
+                self.replace_tree(set, SongTree::from_pset_string(after, &self.index))
+            },
+            // Files first, so a failed move leaves the sets and the index untouched
+            Edit::MergeDuplicates { merged, sets } => {
+                move_files(merged, &self.universal_dir, &self.duplicates_dir())?;
+                for name in merged {
+                    self.index.remove(name);
+                }
+                let universal = self.index.songs().cloned().collect::<HashSet<Song>>();
+                *self.universal_set.songs.borrow_mut() = Rc::new(SongTree::Set(SongSet::Terminal(universal)));
+                self.index.save(&self.index_path)?;
+                for (set, _, after) in sets {
+                    self.replace_tree(set, SongTree::from_pset_string(after, &self.index))?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn revert(&mut self, edit: &Edit) -> io::Result<()> {
+        match edit {
+            Edit::CreateSet { name, .. } => {
+                self.sets.remove(name);
+                self.remove_set_file(name)
+            },
+            Edit::DeleteSet { name, contents, inlined_into } => {
+                self.sets.insert(name.clone(), Playset::from_pset_string(contents, name.clone(), &self.index));
+                self.save_set(name)?;
+                for (set, before) in inlined_into {
+                    self.replace_tree(set, SongTree::from_pset_string(before, &self.index))?;
+                }
+                Ok(())
+            },
+            Edit::RenameSet { from, to } => {
+                self.move_set(to, from)
+            },
+            Edit::DuplicateSet { to, .. } => {
+                self.sets.remove(to);
+                self.remove_set_file(to)
+            },
+            Edit::AddSongs { set, before, .. }
+            | Edit::RemoveSongs { set, before, .. }
+            | Edit::ApplyOperation { set, before, .. }
+            | Edit::Tree { set, before, .. } => {
+                self.replace_tree(set, SongTree::from_pset_string(before, &self.index))
+            },
+            Edit::MergeDuplicates { merged, sets } => {
+                move_files(merged, &self.duplicates_dir(), &self.universal_dir)?;
+                self.add_files(merged)?;
+                for (set, before, _) in sets {
+                    self.replace_tree(set, SongTree::from_pset_string(before, &self.index))?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn check_new_name(&self, name: &str) -> io::Result<()> {
+        if name.is_empty() || name.contains(['/', '\\']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("\"{}\" is not a valid set name", name)));
+        }
+        if self.sets.contains_key(name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", name)));
+        }
+        Ok(())
+    }
+
+    fn set_contents(&self, name: &str) -> String {
+        self.sets.get(name).unwrap().songs.borrow().to_pset_string()
+    }
+
+    fn replace_tree(&mut self, name: &str, tree: SongTree) -> io::Result<()> {
+        *self.sets.get(name).unwrap().songs.borrow_mut() = Rc::new(tree);
+        self.save_set(name)
+    }
+
+    fn move_set(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let mut playset = self.sets.remove(from).unwrap();
+        playset.name = to.to_owned();
+        self.sets.insert(to.to_owned(), playset);
+        fs::rename(self.subsets_dir.join(from), self.subsets_dir.join(to))?;
+
+        let renamed = SongTree::Set(SongSet::NonTerminal(to.to_owned()));
+        for set in self.referencing_sets(from) {
+            let tree = self.sets.get(&set).unwrap().songs.borrow().replace_reference(from, &renamed);
+            self.replace_tree(&set, tree)?;
+        }
+        Ok(())
+    }
+
+    fn save_set(&self, name: &str) -> io::Result<()> {
+        self.sets.get(name).unwrap().write_to_file(&self.subsets_dir)
+    }
+
+    fn remove_set_file(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.subsets_dir.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use audiotags::{AudioTagEdit, AudioTagWrite, Id3v2Tag};
+
+    /// An mp3 holding only an ID3 tag, enough for the library to read it as a song
+    pub fn write_song(path: &Path, artist: &str, title: &str) {
+        fs::write(path, b"").unwrap();
+        let mut tag = Id3v2Tag::new();
+        tag.set_artist(artist);
+        tag.set_title(title);
+        tag.write_to_path(path.to_str().unwrap()).unwrap();
+    }
+
+    /// A library in a fresh temporary folder with a song for every file name, artist and title in `songs`
+    pub fn library(name: &str, songs: &[(&str, &str, &str)]) -> (PathBuf, Library) {
+        let dir = std::env::temp_dir().join(format!("playset-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (songs_dir, sets_dir) = (dir.join("songs"), dir.join("sets"));
+        fs::create_dir_all(&songs_dir).unwrap();
+        fs::create_dir_all(&sets_dir).unwrap();
+        for (file, artist, title) in songs {
+            write_song(&songs_dir.join(file), artist, title);
+        }
+        let library = Library::initialize(&songs_dir, &sets_dir, &ScanOptions::default()).unwrap();
+        (dir, library)
+    }
+
+    fn reopen(dir: &Path) -> Library {
+        Library::initialize(&dir.join("songs"), &dir.join("sets"), &ScanOptions::default()).unwrap()
+    }
+
+    fn names(library: &Library, set: &str) -> Vec<String> {
+        let mut names = library.sets[set].songs.borrow().flatten(library).into_iter().map(|s| s.name).collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn history_survives_a_restart() {
+        let (dir, mut library) = library("history", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two")]);
+        library.create_set(String::from("Mix")).unwrap();
+        library.add_songs("Mix", vec![String::from("a.mp3")]).unwrap();
+        library.add_songs("Mix", vec![String::from("b.mp3")]).unwrap();
+        drop(library);
+
+        let mut library = reopen(&dir);
+        assert_eq!(names(&library, "Mix"), ["a.mp3", "b.mp3"]);
+        assert!(library.undo().unwrap().is_some());
+        assert_eq!(names(&library, "Mix"), ["a.mp3"]);
+        assert_eq!(fs::read_to_string(dir.join("sets/Mix")).unwrap(), library.sets["Mix"].songs.borrow().to_pset_string());
+        drop(library);
+
+        let mut library = reopen(&dir);
+        assert!(library.history.can_redo());
+        assert!(library.redo().unwrap().is_some());
+        assert_eq!(names(&library, "Mix"), ["a.mp3", "b.mp3"]);
+        assert!(library.undo().unwrap().is_some());
+        assert!(library.undo().unwrap().is_some());
+        assert!(library.undo().unwrap().is_some());
+        assert!(!library.sets.contains_key("Mix"));
+        assert!(!dir.join("sets/Mix").exists());
+        assert!(library.undo().unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rename_rewrites_references_and_files() {
+        let (dir, mut library) = library("rename", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two")]);
+        library.create_set(String::from("Base")).unwrap();
+        library.add_songs("Base", vec![String::from("a.mp3")]).unwrap();
+        library.create_set(String::from("Mix")).unwrap();
+        library.add_songs("Mix", vec![String::from("b.mp3")]).unwrap();
+        library.apply_operation("Mix", pset_format::UNION, String::from("Base")).unwrap();
+
+        library.rename_set("Base", String::from("Core")).unwrap();
+        assert!(!dir.join("sets/Base").exists());
+        assert!(dir.join("sets/Core").exists());
+        assert_eq!(library.referencing_sets("Core"), ["Mix"]);
+        let saved = fs::read_to_string(dir.join("sets/Mix")).unwrap();
+        assert!(saved.contains(&format!("Core{}", pset_format::SEPERATOR)));
+        assert!(!saved.contains("Base"));
+        assert_eq!(names(&library, "Mix"), ["a.mp3", "b.mp3"]);
+        assert!(library.rename_set("Mix", String::from("Core")).is_err());
+
+        assert!(library.undo().unwrap().is_some());
+        assert!(dir.join("sets/Base").exists());
+        assert!(fs::read_to_string(dir.join("sets/Mix")).unwrap().contains(&format!("Base{}", pset_format::SEPERATOR)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete_refuses_referenced_sets() {
+        let (dir, mut library) = library("delete", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two")]);
+        library.create_set(String::from("Base")).unwrap();
+        library.add_songs("Base", vec![String::from("a.mp3")]).unwrap();
+        library.create_set(String::from("Mix")).unwrap();
+        library.apply_operation("Mix", pset_format::UNION, String::from("Base")).unwrap();
+
+        assert!(library.delete_set("Base").is_err());
+        assert!(library.sets.contains_key("Base"));
+        assert!(dir.join("sets/Base").exists());
+
+        library.delete_set_inlined("Base").unwrap();
+        assert!(!dir.join("sets/Base").exists());
+        assert!(library.referencing_sets("Base").is_empty());
+        assert_eq!(names(&library, "Mix"), ["a.mp3"]);
+
+        assert!(library.undo().unwrap().is_some());
+        assert_eq!(library.referencing_sets("Base"), ["Mix"]);
+        library.delete_set("Mix").unwrap();
+        library.delete_set("Base").unwrap();
+        assert!(library.sets.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn leaf(song: &str) -> String {
+        format!("{}{}{}{}", pset_format::SET_START, song, pset_format::SEPERATOR, pset_format::SET_END)
+    }
+
+    fn reference(set: &str) -> String {
+        format!("{}{}", set, pset_format::SEPERATOR)
+    }
+
+    fn flattened(tree: &SongTree, library: &Library) -> Vec<String> {
+        let mut names = tree.flatten(library).into_iter().map(|s| s.name).collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn new_operators_round_trip() {
+        let (dir, mut library) = library("operators", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two"), ("c.mp3", "C", "Three")]);
+        library.create_set(String::from("Base")).unwrap();
+        library.add_songs("Base", vec![String::from("b.mp3"), String::from("c.mp3")]).unwrap();
+        let ab = format!("{}{}{}{}", pset_format::GROUP_START, leaf("a.mp3"), leaf("b.mp3"), pset_format::NARY_UNION);
+
+        let cases = [
+            (format!("{}{}{}", ab, reference("Base"), pset_format::SYMMETRIC_DIFFERENCE), vec!["a.mp3", "c.mp3"]),
+            (format!("{}{}", leaf("a.mp3"), pset_format::COMPLEMENT), vec!["b.mp3", "c.mp3"]),
+            (ab.clone(), vec!["a.mp3", "b.mp3"]),
+            (format!("{}{}{}{}", pset_format::GROUP_START, ab, reference("Base"), pset_format::NARY_INTERSECTION), vec!["b.mp3"]),
+            (format!("{}{}{}{}{}", pset_format::GROUP_START, ab, pset_format::GROUP_START, pset_format::NARY_UNION, pset_format::NARY_UNION), vec!["a.mp3", "b.mp3"]),
+        ];
+        for (pset, songs) in cases {
+            let tree = SongTree::from_pset_string(&pset, &library.index);
+            assert_eq!(tree.to_pset_string(), pset);
+            assert_eq!(flattened(&tree, &library), songs);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn old_files_still_parse() {
+        let (dir, library) = library("old-psets", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two"), ("c.mp3", "C", "Three")]);
+        let (start, end, seperator) = (pset_format::SET_START, pset_format::SET_END, pset_format::SEPERATOR);
+        fs::write(dir.join("sets/Base"), format!("{}b.mp3{}c.mp3{}{}", start, seperator, seperator, end)).unwrap();
+        fs::write(dir.join("sets/Old"), format!("{}{}{}{}{}", leaf("a.mp3"), reference("Base"), pset_format::UNION, leaf("c.mp3"), pset_format::DIFFERENCE)).unwrap();
+        drop(library);
+
+        let library = reopen(&dir);
+        assert_eq!(names(&library, "Base"), ["b.mp3", "c.mp3"]);
+        assert_eq!(names(&library, "Old"), ["a.mp3", "b.mp3"]);
+        let pset = format!("{}{}{}", leaf("a.mp3"), reference("Base"), pset_format::INTERSECTION);
+        assert_eq!(SongTree::from_pset_string(&pset, &library.index).to_pset_string(), pset);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn samplers_round_trip() {
+        let (dir, library) = library("samplers", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two"), ("c.mp3", "C", "Three")]);
+        let ab = format!("{}{}{}{}", pset_format::GROUP_START, leaf("a.mp3"), leaf("b.mp3"), pset_format::NARY_UNION);
+        let cases = [
+            (format!("{}1{}", ab, pset_format::TAKE), Sampler::Take(1)),
+            (format!("{}2,42{}", ab, pset_format::RANDOM_SAMPLE), Sampler::Random { count: 2, seed: 42 }),
+            (format!("{}{}{}3,7,2,1{}", pset_format::GROUP_START, ab, leaf("c.mp3"), pset_format::WEIGHTED_MIX), Sampler::Weighted { count: 3, seed: 7, weights: vec![2, 1] }),
+            (format!("{}600{}", ab, pset_format::DURATION_CAP), Sampler::DurationCap(600)),
+        ];
+        for (pset, sampler) in cases {
+            let tree = SongTree::from_pset_string(&pset, &library.index);
+            assert!(matches!(&tree, SongTree::Sample(s, _) if *s == sampler), "{:?}", tree);
+            assert_eq!(tree.to_pset_string(), pset);
+        }
+
+        // Weights are evened out to one per part
+        let short = format!("{}{}{}{}3,7,2{}", pset_format::GROUP_START, leaf("a.mp3"), leaf("b.mp3"), leaf("c.mp3"), pset_format::WEIGHTED_MIX);
+        let tree = SongTree::from_pset_string(&short, &library.index);
+        assert!(matches!(&tree, SongTree::Sample(Sampler::Weighted { weights, .. }, parts) if *weights == [2, 1, 1] && parts.len() == 3));
+        let long = format!("{}{}3,7,2,5,9{}", pset_format::GROUP_START, leaf("a.mp3"), pset_format::WEIGHTED_MIX);
+        let tree = SongTree::from_pset_string(&long, &library.index);
+        assert!(matches!(&tree, SongTree::Sample(Sampler::Weighted { weights, .. }, _) if *weights == [2]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tag_edits_reach_the_files_and_the_index() {
+        let (dir, mut library) = library("tags", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two")]);
+        let edit = TagEdit { album: Some(String::from("Both")), year: Some(1999), ..Default::default() };
+        library.edit_tags(&[String::from("a.mp3"), String::from("b.mp3")], &edit).unwrap();
+        let edit = TagEdit { title: Some(String::from("Uno")), track_number: Some(3), ..Default::default() };
+        library.edit_tags(&[String::from("a.mp3")], &edit).unwrap();
+
+        let tag = Tag::new().read_from_path(dir.join("songs/a.mp3")).unwrap();
+        assert_eq!((tag.title(), tag.artist(), tag.album_title(), tag.year(), tag.track_number()), (Some("Uno"), Some("A"), Some("Both"), Some(1999), Some(3)));
+        let tag = Tag::new().read_from_path(dir.join("songs/b.mp3")).unwrap();
+        assert_eq!((tag.title(), tag.album_title(), tag.year()), (Some("Two"), Some("Both"), Some(1999)));
+
+        let song = reopen(&dir).song_by_name("a.mp3").unwrap();
+        assert_eq!((song.title.as_str(), song.album.as_str(), song.year, song.track_number), ("Uno", "Both", Some(1999), Some(3)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn song_metadata_comes_from_the_tag_and_the_stream_header() {
+        let dir = std::env::temp_dir().join(format!("playset-metadata-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Two seconds of 128 kbit/s, 44.1kHz MPEG-1 layer III frames, silent
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x40];
+        frame.resize(32000, 0);
+        let path = dir.join("song.mp3");
+        fs::write(&path, &frame).unwrap();
+        let mut tag = Id3v2Tag::new();
+        tag.set_title("Title");
+        tag.set_artist("Artist");
+        tag.set_album_title("Album");
+        tag.set_genre("Jazz");
+        tag.set_track_number(4);
+        tag.set_year(2001);
+        tag.write_to_path(path.to_str().unwrap()).unwrap();
+
+        let song = Song::from_path(&dir, String::from("song.mp3")).unwrap();
+        assert_eq!((song.title.as_str(), song.artist.as_str(), song.album.as_str(), song.genre.as_str()), ("Title", "Artist", "Album", "Jazz"));
+        assert_eq!((song.track_number, song.year), (Some(4), Some(2001)));
+        assert_eq!((song.sample_rate, song.duration, song.codec.as_str()), (44100, 2, "MP3"));
+        assert_eq!(song.file_size, fs::metadata(&path).unwrap().len());
+        assert_eq!(song.bitrate as u64, song.file_size * 8 / 2 / 1000);
+
+        // Nothing to read the length from leaves the bitrate at 0 rather than dividing by it
+        fs::write(&path, b"").unwrap();
+        tag.write_to_path(path.to_str().unwrap()).unwrap();
+        let song = Song::from_path(&dir, String::from("song.mp3")).unwrap();
+        assert_eq!((song.duration, song.bitrate, song.sample_rate), (0, 0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(normalize_tag("  #Road   Trip "), "road-trip");
+        assert_eq!(normalize_tag("CHILL"), "chill");
+        assert_eq!(normalize_tag("a\u{7}b"), "ab");
+        assert_eq!(normalize_tag(" # "), "");
+    }
+
+    #[test]
+    fn tag_leaves_match_tagged_songs() {
+        let (dir, mut library) = library("tag-leaves", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two"), ("c.mp3", "C", "Three")]);
+        library.add_user_tag(&[String::from("a.mp3"), String::from("c.mp3")], "#Road Trip").unwrap();
+        assert!(library.add_user_tag(&[String::from("b.mp3")], " # ").is_err());
+
+        let tree = SongTree::from_pset_string(&format!("road-trip{}", pset_format::USER_TAG), &library.index);
+        assert_eq!(tree.to_pset_string(), format!("road-trip{}", pset_format::USER_TAG));
+        assert_eq!(flattened(&tree, &library), ["a.mp3", "c.mp3"]);
+
+        // Hand edited leaves are read the way tags are stored, empty ones match nothing
+        let tree = SongTree::from_pset_string(&format!("Road Trip{}", pset_format::USER_TAG), &library.index);
+        assert_eq!(tree.to_pset_string(), format!("road-trip{}", pset_format::USER_TAG));
+        let tree = SongTree::from_pset_string(&format!(" {}", pset_format::USER_TAG), &library.index);
+        assert!(tree.flatten(&library).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
 
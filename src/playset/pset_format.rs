@@ -5,3 +5,25 @@ pub const SET_END: char = 0x03 as char;
 pub const UNION: char = 0x10 as char;
 pub const INTERSECTION: char = 0x11 as char;
 pub const DIFFERENCE: char = 0x12 as char;
+pub const SYMMETRIC_DIFFERENCE: char = 0x13 as char;
+/// Unary, takes the single tree before it
+pub const COMPLEMENT: char = 0x14 as char;
+
+/// N-ary operators take every tree since the matching GROUP_START
+pub const GROUP_START: char = 0x04 as char;
+pub const NARY_UNION: char = 0x15 as char;
+pub const NARY_INTERSECTION: char = 0x16 as char;
+
+/// Samplers read their comma separated parameters from the text right before the opcode.
+/// WEIGHTED_MIX is n-ary like NARY_UNION, the others take the single tree before them.
+pub const TAKE: char = 0x17 as char;
+pub const RANDOM_SAMPLE: char = 0x18 as char;
+pub const WEIGHTED_MIX: char = 0x19 as char;
+pub const DURATION_CAP: char = 0x1A as char;
+
+/// Rule leaves are stored as their text form between these, see `rules::Rule`
+pub const RULE_START: char = 0x05 as char;
+pub const RULE_END: char = 0x06 as char;
+
+/// Ends a user tag leaf, the tag name is the text before it
+pub const USER_TAG: char = 0x07 as char;
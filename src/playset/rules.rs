@@ -0,0 +1,285 @@
+use std::{cmp::Ordering, fmt};
+
+use super::listening;
+
+use super::Song;
+use super::genres::GenreTaxonomy;
+
+/// A piece of song metadata that can be sorted on or tested by a `Rule`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SongField {
+    File,
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Composer,
+    Genre,
+    Year,
+    Track,
+    Disc,
+    Duration,
+    Bitrate,
+    SampleRate,
+    Codec,
+    Size,
+    Bpm,
+    Key,
+    Plays,
+    Skips,
+    SkipRate,
+    DaysSincePlayed,
+    Rating,
+    Loved,
+}
+
+pub const SONG_FIELDS: [SongField; 23] = [
+    SongField::File,
+    SongField::Title,
+    SongField::Artist,
+    SongField::Album,
+    SongField::AlbumArtist,
+    SongField::Composer,
+    SongField::Genre,
+    SongField::Year,
+    SongField::Track,
+    SongField::Disc,
+    SongField::Duration,
+    SongField::Bitrate,
+    SongField::SampleRate,
+    SongField::Codec,
+    SongField::Size,
+    SongField::Bpm,
+    SongField::Key,
+    SongField::Plays,
+    SongField::Skips,
+    SongField::SkipRate,
+    SongField::DaysSincePlayed,
+    SongField::Rating,
+    SongField::Loved,
+];
+
+pub enum FieldValue {
+    Text(String),
+    Number(Option<f64>),
+}
+
+impl SongField {
+    /// Keyword used for the field in rules
+    pub fn name(&self) -> &'static str {
+        match self {
+            SongField::File => "file",
+            SongField::Title => "title",
+            SongField::Artist => "artist",
+            SongField::Album => "album",
+            SongField::AlbumArtist => "album_artist",
+            SongField::Composer => "composer",
+            SongField::Genre => "genre",
+            SongField::Year => "year",
+            SongField::Track => "track",
+            SongField::Disc => "disc",
+            SongField::Duration => "duration",
+            SongField::Bitrate => "bitrate",
+            SongField::SampleRate => "sample_rate",
+            SongField::Codec => "codec",
+            SongField::Size => "size",
+            SongField::Bpm => "bpm",
+            SongField::Key => "key",
+            SongField::Plays => "plays",
+            SongField::Skips => "skips",
+            SongField::SkipRate => "skip_rate",
+            SongField::DaysSincePlayed => "days_since_played",
+            SongField::Rating => "rating",
+            SongField::Loved => "loved",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SongField::File => "File",
+            SongField::Title => "Title",
+            SongField::Artist => "Artist",
+            SongField::Album => "Album",
+            SongField::AlbumArtist => "Album artist",
+            SongField::Composer => "Composer",
+            SongField::Genre => "Genre",
+            SongField::Year => "Year",
+            SongField::Track => "Track",
+            SongField::Disc => "Disc",
+            SongField::Duration => "Duration",
+            SongField::Bitrate => "Bitrate",
+            SongField::SampleRate => "Sample rate",
+            SongField::Codec => "Codec",
+            SongField::Size => "File size",
+            SongField::Bpm => "BPM",
+            SongField::Key => "Key",
+            SongField::Plays => "Plays",
+            SongField::Skips => "Skips",
+            SongField::SkipRate => "Skip rate (%)",
+            SongField::DaysSincePlayed => "Days since played",
+            SongField::Rating => "Rating",
+            SongField::Loved => "Loved",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        SONG_FIELDS.iter().find(|f| f.name() == name).copied()
+    }
+
+    pub fn value(&self, song: &Song) -> FieldValue {
+        match self {
+            SongField::File => FieldValue::Text(song.name.clone()),
+            SongField::Title => FieldValue::Text(song.display_title().to_owned()),
+            SongField::Artist => FieldValue::Text(song.artist.clone()),
+            SongField::Album => FieldValue::Text(song.album.clone()),
+            SongField::AlbumArtist => FieldValue::Text(song.album_artist.clone()),
+            SongField::Composer => FieldValue::Text(song.composer.clone()),
+            SongField::Genre => FieldValue::Text(song.genre.clone()),
+            SongField::Year => FieldValue::Number(song.year.map(|y| y as f64)),
+            SongField::Track => FieldValue::Number(song.track_number.map(|t| t as f64)),
+            SongField::Disc => FieldValue::Number(song.disc_number.map(|d| d as f64)),
+            SongField::Duration => FieldValue::Number(Some(song.duration as f64)),
+            SongField::Bitrate => FieldValue::Number(Some(song.bitrate as f64)),
+            SongField::SampleRate => FieldValue::Number(Some(song.sample_rate as f64)),
+            SongField::Codec => FieldValue::Text(song.codec.clone()),
+            SongField::Size => FieldValue::Number(Some(song.file_size as f64)),
+            SongField::Bpm => FieldValue::Number(song.bpm.map(|b| b as f64)),
+            SongField::Key => FieldValue::Text(song.key.clone().unwrap_or_default()),
+            SongField::Plays => FieldValue::Number(Some(song.play_count as f64)),
+            SongField::Skips => FieldValue::Number(Some(song.skip_count as f64)),
+            SongField::SkipRate => {
+                let total = song.play_count + song.skip_count;
+                FieldValue::Number((total > 0).then(|| song.skip_count as f64 * 100.0 / total as f64))
+            },
+            // Never played counts as forever ago, so `days_since_played > 30` includes unplayed songs
+            SongField::DaysSincePlayed => FieldValue::Number(Some(match song.last_played {
+                Some(at) => listening::now().saturating_sub(at) as f64 / 86400.0,
+                None => f64::INFINITY,
+            })),
+            SongField::Rating => FieldValue::Number(Some(song.rating as f64)),
+            // Written as `loved is yes`
+            SongField::Loved => FieldValue::Text(String::from(if song.loved { "yes" } else { "no" })),
+        }
+    }
+
+    /// Text sorts case-insensitively, missing numbers sort last
+    pub fn compare(&self, a: &Song, b: &Song) -> Ordering {
+        match (self.value(a), self.value(b)) {
+            (FieldValue::Text(a), FieldValue::Text(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+            (FieldValue::Number(Some(a)), FieldValue::Number(Some(b))) => a.total_cmp(&b),
+            (FieldValue::Number(Some(_)), FieldValue::Number(None)) => Ordering::Less,
+            (FieldValue::Number(None), FieldValue::Number(Some(_))) => Ordering::Greater,
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Is(String),
+    IsNot(String),
+    Contains(String),
+    /// The genre or any of its sub-genres
+    Under(String),
+    Less(f64),
+    AtMost(f64),
+    Greater(f64),
+    AtLeast(f64),
+    Between(f64, f64),
+}
+
+/// A leaf that selects every song of the library whose `field` passes `condition`.
+/// Written as text, e.g. `year >= 2000`, `artist is "Vylet Pony"`, `duration between 120 and 300`
+/// or `genre under "Electronic"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub field: SongField,
+    pub condition: Condition,
+}
+
+impl Rule {
+    pub fn matches(&self, song: &Song, genres: &GenreTaxonomy) -> bool {
+        let value = self.field.value(song);
+        match &self.condition {
+            Condition::Is(text) => value_text(&value).eq_ignore_ascii_case(text),
+            Condition::IsNot(text) => !value_text(&value).eq_ignore_ascii_case(text),
+            Condition::Contains(text) => value_text(&value).to_lowercase().contains(&text.to_lowercase()),
+            Condition::Under(text) => genres.is_within(&value_text(&value), text),
+            Condition::Less(n) => value_number(&value).is_some_and(|v| v < *n),
+            Condition::AtMost(n) => value_number(&value).is_some_and(|v| v <= *n),
+            Condition::Greater(n) => value_number(&value).is_some_and(|v| v > *n),
+            Condition::AtLeast(n) => value_number(&value).is_some_and(|v| v >= *n),
+            Condition::Between(low, high) => value_number(&value).is_some_and(|v| *low <= v && v <= *high),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (field, rest) = s.split_once(char::is_whitespace).ok_or_else(|| format!("\"{}\" is missing a condition", s))?;
+        let field = SongField::from_name(field).ok_or_else(|| format!("\"{}\" is not a field", field))?;
+        let rest = rest.trim();
+
+        let number = |n: &str| n.trim().parse::<f64>().map_err(|_| format!("\"{}\" is not a number", n.trim()));
+        let condition = if let Some(value) = rest.strip_prefix("is not ") {
+            Condition::IsNot(unquote(value))
+        } else if let Some(value) = rest.strip_prefix("is ") {
+            Condition::Is(unquote(value))
+        } else if let Some(value) = rest.strip_prefix("contains ") {
+            Condition::Contains(unquote(value))
+        } else if let Some(value) = rest.strip_prefix("under ") {
+            Condition::Under(unquote(value))
+        } else if let Some(range) = rest.strip_prefix("between ") {
+            let (low, high) = range.split_once(" and ").ok_or_else(|| String::from("between needs \"<low> and <high>\""))?;
+            Condition::Between(number(low)?, number(high)?)
+        } else if let Some(n) = rest.strip_prefix("<=") {
+            Condition::AtMost(number(n)?)
+        } else if let Some(n) = rest.strip_prefix(">=") {
+            Condition::AtLeast(number(n)?)
+        } else if let Some(n) = rest.strip_prefix('<') {
+            Condition::Less(number(n)?)
+        } else if let Some(n) = rest.strip_prefix('>') {
+            Condition::Greater(number(n)?)
+        } else {
+            return Err(format!("\"{}\" is not a condition", rest));
+        };
+
+        Ok(Self { field, condition })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = self.field.name();
+        match &self.condition {
+            Condition::Is(text) => write!(f, "{} is \"{}\"", field, text),
+            Condition::IsNot(text) => write!(f, "{} is not \"{}\"", field, text),
+            Condition::Contains(text) => write!(f, "{} contains \"{}\"", field, text),
+            Condition::Under(text) => write!(f, "{} under \"{}\"", field, text),
+            Condition::Less(n) => write!(f, "{} < {}", field, n),
+            Condition::AtMost(n) => write!(f, "{} <= {}", field, n),
+            Condition::Greater(n) => write!(f, "{} > {}", field, n),
+            Condition::AtLeast(n) => write!(f, "{} >= {}", field, n),
+            Condition::Between(low, high) => write!(f, "{} between {} and {}", field, low, high),
+        }
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value).to_owned()
+}
+
+fn value_text(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Text(text) => text.clone(),
+        FieldValue::Number(Some(n)) => n.to_string(),
+        FieldValue::Number(None) => String::new(),
+    }
+}
+
+fn value_number(value: &FieldValue) -> Option<f64> {
+    match value {
+        FieldValue::Text(text) => text.trim().parse().ok(),
+        FieldValue::Number(n) => *n,
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+
+use super::{pset_format, Song};
+
+/// Picks songs out of the queues of a `SongTree::Sample` node's subtrees.
+/// Anything random is driven by `seed`, so the same tree always yields the same songs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sampler {
+    /// The first `n` songs of the subtree
+    Take(usize),
+    /// `count` songs picked at random
+    Random { count: usize, seed: u64 },
+    /// `count` random songs split between the subtrees proportionally to `weights`
+    Weighted { count: usize, seed: u64, weights: Vec<u32> },
+    /// Songs of the subtree, in order, for as long as they fit in this many seconds
+    DurationCap(u64),
+}
+
+impl Sampler {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sampler::Take(_) => "Take",
+            Sampler::Random { .. } => "Random",
+            Sampler::Weighted { .. } => "Weighted mix",
+            Sampler::DurationCap(_) => "Duration cap",
+        }
+    }
+
+    pub fn opcode(&self) -> char {
+        match self {
+            Sampler::Take(_) => pset_format::TAKE,
+            Sampler::Random { .. } => pset_format::RANDOM_SAMPLE,
+            Sampler::Weighted { .. } => pset_format::WEIGHTED_MIX,
+            Sampler::DurationCap(_) => pset_format::DURATION_CAP,
+        }
+    }
+
+    /// Comma separated parameters written right before the opcode
+    pub fn params(&self) -> String {
+        match self {
+            Sampler::Take(n) => n.to_string(),
+            Sampler::Random { count, seed } => format!("{},{}", count, seed),
+            Sampler::Weighted { count, seed, weights } => {
+                let mut out = format!("{},{}", count, seed);
+                for weight in weights {
+                    out.push_str(&format!(",{}", weight));
+                }
+                out
+            },
+            Sampler::DurationCap(seconds) => seconds.to_string(),
+        }
+    }
+
+    pub fn from_pset(opcode: char, params: &str) -> Option<Self> {
+        let params = params.split(',').map(|p| p.trim().parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?;
+        match (opcode, params.as_slice()) {
+            (pset_format::TAKE, [n]) => Some(Sampler::Take(*n as usize)),
+            (pset_format::RANDOM_SAMPLE, [count, seed]) => Some(Sampler::Random { count: *count as usize, seed: *seed }),
+            (pset_format::WEIGHTED_MIX, [count, seed, weights @ ..]) => Some(Sampler::Weighted {
+                count: *count as usize,
+                seed: *seed,
+                weights: weights.iter().map(|w| *w as u32).collect(),
+            }),
+            (pset_format::DURATION_CAP, [seconds]) => Some(Sampler::DurationCap(*seconds)),
+            _ => None,
+        }
+    }
+
+    /// `inputs` holds the queue of every subtree, in order. A node without subtrees samples nothing.
+    pub fn sample(&self, mut inputs: Vec<Vec<Song>>) -> Vec<Song> {
+        match self {
+            Sampler::Take(n) => {
+                let mut songs = inputs.into_iter().next().unwrap_or_default();
+                songs.truncate(*n);
+                songs
+            },
+            Sampler::Random { count, seed } => {
+                let mut songs = inputs.into_iter().next().unwrap_or_default();
+                Rng::new(*seed).shuffle(&mut songs);
+                songs.truncate(*count);
+                songs
+            },
+            Sampler::Weighted { count, seed, weights } => {
+                let total = weights.iter().map(|w| *w as usize).sum::<usize>().max(1);
+                let mut shares = weights.iter().map(|w| count * *w as usize / total).collect::<Vec<usize>>();
+                // Hand out what rounding down left over, heaviest parts first
+                let mut by_weight = (0..weights.len()).collect::<Vec<usize>>();
+                by_weight.sort_by(|a, b| weights[*b].cmp(&weights[*a]));
+                let mut left = count - shares.iter().sum::<usize>();
+                for &i in by_weight.iter().cycle().take(weights.len() * 2) {
+                    if left == 0 {
+                        break;
+                    }
+                    if weights[i] > 0 {
+                        shares[i] += 1;
+                        left -= 1;
+                    }
+                }
+
+                let mut rng = Rng::new(*seed);
+                for songs in inputs.iter_mut() {
+                    rng.shuffle(songs);
+                }
+                let mut picked = HashSet::new();
+                let mut next = vec![0; inputs.len()];
+                // Parts can overlap, a song only counts for the first part that picked it
+                let mut take = |part: usize, n: usize, out: &mut Vec<Song>| {
+                    let Some(songs) = inputs.get(part) else {
+                        return;
+                    };
+                    let mut taken = 0;
+                    while taken < n && next[part] < songs.len() {
+                        let song = &songs[next[part]];
+                        next[part] += 1;
+                        if picked.insert(song.name.clone()) {
+                            out.push(song.clone());
+                            taken += 1;
+                        }
+                    }
+                };
+                let mut out = vec![];
+                for (part, share) in shares.into_iter().enumerate() {
+                    take(part, share, &mut out);
+                }
+                // Parts with too few songs leave the rest of their share to the others, heaviest first
+                for part in by_weight.into_iter().filter(|i| weights[*i] > 0) {
+                    if out.len() >= *count {
+                        break;
+                    }
+                    take(part, count - out.len(), &mut out);
+                }
+                rng.shuffle(&mut out);
+                out
+            },
+            Sampler::DurationCap(seconds) => {
+                let mut total = 0;
+                inputs.into_iter()
+                    .next()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .take_while(|s| {
+                        total += s.duration;
+                        total <= *seconds
+                    }).collect()
+            },
+        }
+    }
+}
+
+/// Small seedable generator (splitmix64), good enough for shuffling playlists
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn songs(prefix: &str, n: usize) -> Vec<Song> {
+        (0..n).map(|i| Song { name: format!("{}{}.mp3", prefix, i), duration: 60, ..Default::default() }).collect()
+    }
+
+    fn names(songs: &[Song]) -> Vec<&str> {
+        songs.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn params_round_trip() {
+        for sampler in [
+            Sampler::Take(5),
+            Sampler::Random { count: 3, seed: 99 },
+            Sampler::Weighted { count: 10, seed: 1, weights: vec![3, 0, 1] },
+            Sampler::DurationCap(3600),
+        ] {
+            assert_eq!(Sampler::from_pset(sampler.opcode(), &sampler.params()), Some(sampler));
+        }
+        assert_eq!(Sampler::from_pset(pset_format::TAKE, "x"), None);
+        assert_eq!(Sampler::from_pset(pset_format::RANDOM_SAMPLE, "3"), None);
+    }
+
+    #[test]
+    fn seeded_samples_repeat() {
+        let random = Sampler::Random { count: 5, seed: 1234 };
+        let first = random.sample(vec![songs("a", 20)]);
+        assert_eq!(first.len(), 5);
+        assert_eq!(names(&first), names(&random.sample(vec![songs("a", 20)])));
+        assert_ne!(names(&first), names(&Sampler::Random { count: 5, seed: 4321 }.sample(vec![songs("a", 20)])));
+
+        let weighted = Sampler::Weighted { count: 8, seed: 77, weights: vec![3, 1] };
+        let first = weighted.sample(vec![songs("a", 10), songs("b", 10)]);
+        assert_eq!(names(&first), names(&weighted.sample(vec![songs("a", 10), songs("b", 10)])));
+        assert_eq!(first.iter().filter(|s| s.name.starts_with('a')).count(), 6);
+        assert_eq!(first.iter().filter(|s| s.name.starts_with('b')).count(), 2);
+    }
+
+    #[test]
+    fn weighted_shortfall_is_refilled() {
+        let weighted = Sampler::Weighted { count: 6, seed: 5, weights: vec![1, 1] };
+        let picked = weighted.sample(vec![songs("a", 1), songs("b", 10)]);
+        assert_eq!(picked.len(), 6);
+        assert_eq!(picked.iter().filter(|s| s.name.starts_with('a')).count(), 1);
+        assert!(Sampler::Take(3).sample(vec![]).is_empty());
+        assert_eq!(Sampler::DurationCap(150).sample(vec![songs("a", 5)]).len(), 2);
+    }
+}
+
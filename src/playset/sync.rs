@@ -0,0 +1,260 @@
+use std::{collections::{HashMap, HashSet}, fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+
+use super::{Library, Song};
+use super::duplicates::normalize;
+use super::export::{self, ExportOptions, ExportReport, Manifest};
+use super::listening::{self, PlayEvent, PlayKind};
+use super::tag_writer;
+
+/// Rockbox and other players that scrobble offline keep this at the root of the device
+const DEVICE_SCROBBLE_LOG: &str = ".scrobbler.log";
+
+/// Sets mirrored to a mounted player or folder
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncProfile {
+    pub name: String,
+    pub sets: Vec<String>,
+    /// How files are named and converted, `export.target` is where the device is mounted
+    pub export: ExportOptions,
+    /// Bring plays and ratings made on the device back into the library
+    pub read_back: bool,
+}
+
+impl SyncProfile {
+    /// Follows the set `from` to its new name, or drops it when `to` is None. Returns whether it was synced.
+    pub fn rename_set(&mut self, from: &str, to: Option<&str>) -> bool {
+        let Some(i) = self.sets.iter().position(|s| s == from) else {
+            return false;
+        };
+        match to {
+            Some(to) => self.sets[i] = to.to_owned(),
+            None => {
+                self.sets.remove(i);
+            },
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadBack {
+    pub plays: usize,
+    pub ratings: usize,
+}
+
+/// Plays and ratings made on the device since the last sync, found by `read_device`
+#[derive(Debug, Clone, Default)]
+pub struct DeviceChanges {
+    pub plays: Vec<PlayEvent>,
+    /// Song names and their stars
+    pub ratings: Vec<(String, u8)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub export: ExportReport,
+    /// Files removed because no synced set has them anymore
+    pub deleted: usize,
+}
+
+/// Normalized artist and title of every song by name, for `read_device` to match the device's plays against
+pub fn song_titles(library: &Library) -> HashMap<String, (String, String)> {
+    library.index.songs()
+        .map(|song| (song.name.clone(), (normalize(&song.artist), normalize(song.display_title()))))
+        .collect()
+}
+
+/// Reads the device's scrobble log and any ratings changed in its files since the last sync.
+/// Only touches the device, so it runs on the sync thread and the library takes the changes in
+/// afterwards with `Library::add_device_changes`.
+pub fn read_device(profile: &SyncProfile, titles: &HashMap<String, (String, String)>) -> io::Result<DeviceChanges> {
+    let target = &profile.export.target;
+    let mut changes = DeviceChanges::default();
+    if !profile.read_back || !target.is_dir() {
+        return Ok(changes);
+    }
+    let mut manifest = Manifest::load(target, export::SYNC_MANIFEST)?;
+
+    let log_path = target.join(DEVICE_SCROBBLE_LOG);
+    if log_path.exists() {
+        // Matched by artist and title since the device only knows its own file names
+        let synced = manifest.files.values().map(|e| e.song.clone()).collect::<HashSet<String>>();
+        let by_title = synced.into_iter()
+            .filter_map(|name| titles.get(&name).map(|title| (title.clone(), name)))
+            .collect::<HashMap<(String, String), String>>();
+
+        for line in fs::read_to_string(&log_path)?.lines().filter(|l| !l.starts_with('#')) {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            if fields.len() < 7 {
+                continue;
+            }
+            let Some(name) = by_title.get(&(normalize(fields[0]), normalize(fields[2]))) else {
+                continue;
+            };
+            let duration = fields[4].parse().unwrap_or(0);
+            let started = fields[6].parse().unwrap_or(0);
+            let (kind, position) = if fields[5] == "L" { (PlayKind::Finish, duration) } else { (PlayKind::Skip, 0) };
+            changes.plays.push(PlayEvent { song: name.clone(), kind, at: started + position, position });
+        }
+        // The device starts a new log, so the same plays aren't imported twice
+        fs::rename(&log_path, target.join(format!(".scrobbler-{}.log", listening::now())))?;
+    }
+
+    for (relative, entry) in manifest.files.iter_mut() {
+        let Ok(rating) = tag_writer::read_rating(target.join(relative)) else {
+            continue;
+        };
+        if rating != entry.rating {
+            // A file without a rating tag says nothing about the song, it doesn't clear its stars
+            if let Some(stars) = rating {
+                changes.ratings.push((entry.song.clone(), stars));
+            }
+            entry.rating = rating;
+        }
+    }
+    manifest.save(target, export::SYNC_MANIFEST)?;
+    Ok(changes)
+}
+
+/// Removes `path` and then its parents while they are empty, stopping at `root`
+fn remove_with_empty_parents(root: &Path, path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {},
+    }
+    let mut dir = path.parent();
+    while let Some(d) = dir.filter(|d| *d != root && d.starts_with(root)) {
+        if fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Exports every set of the profile, then deletes what earlier syncs put on the device but no set has anymore.
+/// Only files listed in the sync manifest are ever deleted, and none a one-off export into the same folder wrote too.
+/// `progress` gets the set being copied, how many songs of it are done and the current song.
+pub fn sync(profile: &SyncProfile, sets: &[(String, Vec<Song>)], songs_dir: &Path, mut progress: impl FnMut(&str, usize, &str)) -> io::Result<SyncReport> {
+    let target = &profile.export.target;
+    let mut report = SyncReport::default();
+    for (name, songs) in sets {
+        let exported = export::export_recorded_in(export::SYNC_MANIFEST, name, songs, songs_dir, &profile.export, |done, song| progress(name, done, song))?;
+        report.export.add(exported);
+    }
+
+    let wanted = report.export.files.iter().collect::<HashSet<&String>>();
+    // Songs that failed this time keep the copy an earlier sync left
+    let failed = report.export.failed.iter().map(|(song, _)| song).collect::<HashSet<&String>>();
+    let playlists = sets.iter().map(|(name, _)| export::playlist_name(name)).collect::<HashSet<String>>();
+    let mut manifest = Manifest::load(target, export::SYNC_MANIFEST)?;
+    let exported = Manifest::load(target, export::MANIFEST)?;
+    let stale = manifest.files.iter()
+        .filter(|(f, entry)| !wanted.contains(f) && !failed.contains(&entry.song))
+        .map(|(f, _)| f.clone())
+        .collect::<Vec<String>>();
+    for relative in stale {
+        if !exported.files.contains_key(&relative) {
+            remove_with_empty_parents(target, &target.join(&relative))?;
+            report.deleted += 1;
+        }
+        manifest.files.remove(&relative);
+    }
+    for playlist in manifest.playlists.iter().filter(|p| !playlists.contains(*p) || !profile.export.m3u) {
+        if !exported.playlists.contains(playlist) {
+            remove_with_empty_parents(target, &target.join(playlist))?;
+        }
+    }
+    manifest.playlists.retain(|p| playlists.contains(p) && profile.export.m3u);
+    manifest.save(target, export::SYNC_MANIFEST)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playset::tests::library;
+
+    fn profile(target: &Path) -> SyncProfile {
+        let export = ExportOptions { target: target.to_path_buf(), template: String::from("{name}"), ..Default::default() };
+        SyncProfile { name: String::from("Player"), sets: vec![String::from("Mix")], export, read_back: true }
+    }
+
+    fn songs(library: &Library, names: &[&str]) -> Vec<Song> {
+        names.iter().map(|n| library.song_by_name(n).unwrap()).collect()
+    }
+
+    #[test]
+    fn only_synced_files_are_deleted() {
+        let (dir, library) = library("sync-stale", &[("a.mp3", "A", "One"), ("b.mp3", "B", "Two"), ("c.mp3", "C", "Three")]);
+        let device = dir.join("device");
+        let profile = profile(&device);
+        let songs_dir = library.songs_dir().to_path_buf();
+
+        let report = sync(&profile, &[(String::from("Mix"), songs(&library, &["a.mp3", "b.mp3"]))], &songs_dir, |_, _, _| {}).unwrap();
+        assert_eq!((report.export.copied, report.deleted), (2, 0));
+        // Put there by hand and by a one-off export
+        fs::write(device.join("notes.txt"), b"mine").unwrap();
+        export::export("Other", &songs(&library, &["c.mp3"]), &songs_dir, &profile.export, |_, _| {}).unwrap();
+
+        let report = sync(&profile, &[(String::from("Mix"), songs(&library, &["a.mp3", "c.mp3"]))], &songs_dir, |_, _, _| {}).unwrap();
+        assert_eq!((report.export.copied, report.export.skipped, report.deleted), (1, 1, 1));
+        assert!(!device.join("b.mp3").exists() && device.join("Mix.m3u").exists());
+        assert!(device.join("a.mp3").exists() && device.join("notes.txt").exists());
+
+        // c.mp3 is the export's too, so the sync leaves it when the set drops it
+        let report = sync(&profile, &[(String::from("Mix"), songs(&library, &["a.mp3"]))], &songs_dir, |_, _, _| {}).unwrap();
+        assert_eq!(report.deleted, 0);
+        assert!(device.join("c.mp3").exists() && device.join("Other.m3u").exists());
+
+        // Nor does a song that merely failed to copy this time lose its earlier copy
+        fs::remove_file(songs_dir.join("a.mp3")).unwrap();
+        let report = sync(&profile, &[(String::from("Mix"), songs(&library, &["a.mp3"]))], &songs_dir, |_, _, _| {}).unwrap();
+        assert_eq!((report.export.failed.len(), report.deleted), (1, 0));
+        assert!(device.join("a.mp3").exists());
+        assert!(Manifest::load(&device, export::SYNC_MANIFEST).unwrap().files.contains_key("a.mp3"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plays_and_ratings_come_back_from_the_device() {
+        let (dir, library) = library("sync-read-back", &[("a.mp3", "The Band", "One (Live)"), ("b.mp3", "B", "Two"), ("c.mp3", "C", "Three")]);
+        let device = dir.join("device");
+        let profile = profile(&device);
+        let titles = song_titles(&library);
+        sync(&profile, &[(String::from("Mix"), songs(&library, &["a.mp3", "b.mp3"]))], library.songs_dir(), |_, _, _| {}).unwrap();
+        assert!(read_device(&profile, &titles).unwrap().plays.is_empty());
+
+        fs::write(device.join(DEVICE_SCROBBLE_LOG), [
+            "#AUDIOSCROBBLER/1.1",
+            "#TZ/UTC",
+            "#CLIENT/Rockbox",
+            "the band\tAlbum\tOne\t1\t200\tL\t5000\t",
+            "B\t\tTwo\t\t180\tS\t6000\t",
+            // Not synced, so not matched even though the library has it
+            "C\t\tThree\t\t100\tL\t7000\t",
+            "Someone\t\tElse\t\t100\tL\t8000\t",
+            "too\tfew\tfields",
+        ].join("\n")).unwrap();
+        tag_writer::write_rating(device.join("a.mp3"), 4).unwrap();
+
+        let changes = read_device(&profile, &titles).unwrap();
+        let plays = changes.plays.iter().map(|p| (p.song.as_str(), p.kind, p.at, p.position)).collect::<Vec<_>>();
+        assert_eq!(plays, [("a.mp3", PlayKind::Finish, 5200, 200), ("b.mp3", PlayKind::Skip, 6000, 0)]);
+        // b.mp3 has no rating tag at all, which isn't read as zero stars
+        assert_eq!(changes.ratings, [(String::from("a.mp3"), 4)]);
+
+        // The log is set aside and the rating remembered, so nothing comes back twice
+        assert!(!device.join(DEVICE_SCROBBLE_LOG).exists());
+        let changes = read_device(&profile, &titles).unwrap();
+        assert!(changes.plays.is_empty() && changes.ratings.is_empty());
+
+        let off = SyncProfile { read_back: false, ..profile };
+        tag_writer::write_rating(device.join("a.mp3"), 2).unwrap();
+        assert!(read_device(&off, &titles).unwrap().ratings.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
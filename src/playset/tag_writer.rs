@@ -0,0 +1,167 @@
+use std::{fs, io, path::{Path, PathBuf}};
+use audiotags::{MimeType, Picture, Tag};
+use id3::TagLike;
+
+/// Rating tags are shared with other players, which mostly identify themselves like this
+const POPM_USER: &str = "Windows Media Player 9 Series";
+
+/// Tag changes to write to one or more audio files.
+/// Fields left as `None` keep whatever each file already has, so the same edit works for batches.
+#[derive(Debug, Clone, Default)]
+pub struct TagEdit {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub track_number: Option<u16>,
+    pub year: Option<i32>,
+    /// Image file to embed as the album cover
+    pub cover: Option<PathBuf>,
+}
+
+impl TagEdit {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.artist.is_none()
+            && self.album.is_none()
+            && self.genre.is_none()
+            && self.track_number.is_none()
+            && self.year.is_none()
+            && self.cover.is_none()
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_str().unwrap();
+        let mut tag = Tag::new().read_from_path(path).map_err(io::Error::other)?;
+
+        if let Some(title) = &self.title {
+            tag.set_title(title);
+        }
+        if let Some(artist) = &self.artist {
+            tag.set_artist(artist);
+        }
+        if let Some(album) = &self.album {
+            tag.set_album_title(album);
+        }
+        if let Some(genre) = &self.genre {
+            tag.set_genre(genre);
+        }
+        if let Some(track_number) = self.track_number {
+            tag.set_track_number(track_number);
+        }
+        if let Some(year) = self.year {
+            tag.set_year(year);
+        }
+        if let Some(cover) = &self.cover {
+            let mime_type = match cover.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+                Some("png") => MimeType::Png,
+                Some("jpg") | Some("jpeg") => MimeType::Jpeg,
+                Some("bmp") => MimeType::Bmp,
+                Some("gif") => MimeType::Gif,
+                Some("tif") | Some("tiff") => MimeType::Tiff,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a supported image", cover.display()))),
+            };
+            let data = fs::read(cover)?;
+            tag.set_album_cover(Picture::new(&data, mime_type));
+        }
+
+        tag.write_to_path(path).map_err(io::Error::other)
+    }
+}
+
+/// The 0-5 star rating in the first POPM frame of an MP3 file, whichever player wrote it.
+/// `None` for other formats and files without one.
+pub fn read_rating<P: AsRef<Path>>(path: P) -> io::Result<Option<u8>> {
+    let path = path.as_ref();
+    if !path.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("mp3")) {
+        return Ok(None);
+    }
+
+    let tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => return Ok(None),
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let rating = tag.frames().find_map(|f| match f.content() {
+        id3::frame::Content::Popularimeter(p) => Some(p.rating),
+        _ => None,
+    });
+    Ok(rating.map(|r| match r {
+        0 => 0,
+        1..=31 => 1,
+        32..=95 => 2,
+        96..=159 => 3,
+        160..=223 => 4,
+        _ => 5,
+    }))
+}
+
+/// Stores a 0-5 star rating in the POPM frame of an MP3 file, using the byte values most players read.
+/// Returns false for formats without ID3 tags, which are left alone.
+pub fn write_rating<P: AsRef<Path>>(path: P, stars: u8) -> io::Result<bool> {
+    let path = path.as_ref();
+    if !path.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("mp3")) {
+        return Ok(false);
+    }
+
+    let mut tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => id3::Tag::new(),
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let rating = match stars {
+        0 => 0,
+        1 => 1,
+        2 => 64,
+        3 => 128,
+        4 => 196,
+        _ => 255,
+    };
+    tag.remove("POPM");
+    let popularimeter = id3::frame::Popularimeter { user: POPM_USER.to_owned(), rating, counter: 0 };
+    tag.add_frame(id3::Frame::with_content("POPM", id3::frame::Content::Popularimeter(popularimeter)));
+    tag.write_to_path(path, id3::Version::Id3v24).map_err(io::Error::other)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn popm(path: &Path, rating: u8) {
+        let mut tag = id3::Tag::new();
+        let popularimeter = id3::frame::Popularimeter { user: String::from("someone@example.com"), rating, counter: 3 };
+        tag.add_frame(id3::Frame::with_content("POPM", id3::frame::Content::Popularimeter(popularimeter)));
+        tag.write_to_path(path, id3::Version::Id3v24).unwrap();
+    }
+
+    #[test]
+    fn stars_round_trip_through_popm() {
+        let dir = std::env::temp_dir().join(format!("playset-rating-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.mp3");
+        fs::write(&path, b"").unwrap();
+        assert_eq!(read_rating(&path).unwrap(), None);
+
+        for stars in 0..=5 {
+            assert!(write_rating(&path, stars).unwrap());
+            assert_eq!(read_rating(&path).unwrap(), Some(stars));
+        }
+        // Only one POPM frame is kept, under the name other players look for
+        let tag = id3::Tag::read_from_path(&path).unwrap();
+        assert_eq!(tag.frames().filter(|f| f.id() == "POPM").count(), 1);
+        assert!(matches!(tag.get("POPM").unwrap().content(), id3::frame::Content::Popularimeter(p) if p.user == POPM_USER && p.rating == 255));
+
+        // Other players' byte values fall into the nearest star
+        for (byte, stars) in [(0, 0), (1, 1), (31, 1), (32, 2), (64, 2), (96, 3), (128, 3), (160, 4), (196, 4), (224, 5), (255, 5)] {
+            popm(&path, byte);
+            assert_eq!(read_rating(&path).unwrap(), Some(stars), "{}", byte);
+        }
+
+        let flac = dir.join("song.flac");
+        fs::write(&flac, b"").unwrap();
+        assert_eq!(read_rating(&flac).unwrap(), None);
+        assert!(!write_rating(&flac, 4).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
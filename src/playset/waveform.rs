@@ -0,0 +1,132 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, BufReader}, path::{Path, PathBuf}, sync::{mpsc, Arc}, thread};
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+
+use super::Song;
+use super::covers::fnv1a;
+
+/// Columns in a summary, whatever the length of the song
+const BUCKETS: usize = 1000;
+
+/// Loudness of a song over time, scaled to 0-255 so cache files stay small
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Waveform {
+    pub peaks: Vec<u8>,
+    pub rms: Vec<u8>,
+}
+
+impl Waveform {
+    /// Decodes the whole file once, `duration` (in seconds) decides how many samples go into each column
+    pub fn from_file<P: AsRef<Path>>(path: P, duration: u64) -> io::Result<Self> {
+        let decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(io::Error::other)?;
+        let samples = decoder.sample_rate() as u64 * decoder.channels() as u64 * duration.max(1);
+        let per_bucket = (samples / BUCKETS as u64).max(1);
+
+        let mut waveform = Self::default();
+        let (mut peak, mut square, mut n) = (0.0f32, 0.0f32, 0);
+        let push = |peak: f32, square: f32, n: u64, waveform: &mut Self| {
+            waveform.peaks.push((peak.min(1.0) * 255.0) as u8);
+            waveform.rms.push(((square / n as f32).sqrt().min(1.0) * 255.0) as u8);
+        };
+        for sample in decoder.convert_samples::<f32>() {
+            peak = peak.max(sample.abs());
+            square += sample * sample;
+            n += 1;
+            if n == per_bucket {
+                push(peak, square, n, &mut waveform);
+                (peak, square, n) = (0.0, 0.0, 0);
+            }
+        }
+        if n > 0 {
+            push(peak, square, n, &mut waveform);
+        }
+        Ok(waveform)
+    }
+}
+
+struct Job {
+    name: String,
+    song_path: PathBuf,
+    cache_path: PathBuf,
+    duration: u64,
+}
+
+/// Waveforms of songs, computed one at a time on a background thread and kept on disk
+pub struct WaveformCache {
+    dir: PathBuf,
+    /// Songs looked up this run, `None` when they could not be decoded
+    known: HashMap<String, Option<Arc<Waveform>>>,
+    pending: HashSet<String>,
+    jobs: mpsc::Sender<Job>,
+    done: mpsc::Receiver<(String, Option<Waveform>)>,
+}
+
+impl WaveformCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        let (done_tx, done) = mpsc::channel();
+        thread::spawn(move || {
+            for job in job_rx {
+                let waveform = match Waveform::from_file(&job.song_path, job.duration) {
+                    Ok(waveform) => {
+                        let saved = serde_json::to_string(&waveform).map_err(io::Error::other).and_then(|s| fs::write(&job.cache_path, s));
+                        if let Err(e) = saved {
+                            println!("Failed to cache waveform of {}: {}", job.name, e);
+                        }
+                        Some(waveform)
+                    },
+                    Err(e) => {
+                        println!("Failed to decode waveform of {}: {}", job.name, e);
+                        None
+                    },
+                };
+                if done_tx.send((job.name, waveform)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            known: HashMap::new(),
+            pending: HashSet::new(),
+            jobs,
+            done,
+        })
+    }
+
+    /// The waveform of `song` if it is ready, otherwise it is queued and `None` returned until it is
+    pub fn get<P: AsRef<Path>>(&mut self, song_path: P, song: &Song) -> Option<Arc<Waveform>> {
+        for (name, waveform) in self.done.try_iter() {
+            self.pending.remove(&name);
+            self.known.insert(name, waveform.map(Arc::new));
+        }
+        if let Some(known) = self.known.get(&song.name) {
+            return known.clone();
+        }
+        if self.pending.contains(&song.name) {
+            return None;
+        }
+
+        let hash = fnv1a(format!("{}{}", song.name, song.file_size).as_bytes());
+        let cache_path = self.dir.join(format!("{:016x}.json", hash));
+        if let Some(waveform) = fs::read_to_string(&cache_path).ok().and_then(|s| serde_json::from_str::<Waveform>(&s).ok()) {
+            let waveform = Arc::new(waveform);
+            self.known.insert(song.name.clone(), Some(waveform.clone()));
+            return Some(waveform);
+        }
+
+        self.pending.insert(song.name.clone());
+        let job = Job {
+            name: song.name.clone(),
+            song_path: song_path.as_ref().to_path_buf(),
+            cache_path,
+            duration: song.duration,
+        };
+        if self.jobs.send(job).is_err() {
+            println!("Waveform worker stopped, not drawing {}", song.name);
+        }
+        None
+    }
+}
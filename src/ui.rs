@@ -1,15 +1,56 @@
 use eframe::egui;
 use egui::TextBuffer;
 use egui::{Color32, CornerRadius};
-use crate::playset::{self, pset_format, SongTree};
+use crate::playset::{self, pset_format};
 use crate::music_player;
 use crate::playset::Library;
-use std::cell::{RefCell, RefMut};
+use crate::playset::rules::{SongField, SONG_FIELDS};
+use crate::playset::covers::{CoverCache, CoverSize};
+use crate::playset::waveform::WaveformCache;
+use crate::playset::analysis;
+use crate::playset::fingerprint::Fingerprint;
+use crate::playset::listening::{self, PlayKind};
+use crate::playset::history::Edit;
+use std::cell::RefMut;
 use std::io::BufReader;
-use std::rc::Rc;
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+use rodio::{Decoder, Sink};
 use std::fs::File;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use session::{Repeat, Session};
+use crate::config::{AudioConfig, Config, Theme};
+
+mod tree_editor;
+mod tag_editor;
+mod covers;
+mod browser;
+mod duplicates;
+mod waveform;
+mod spectrum;
+mod stats;
+mod user_tags;
+mod session;
+mod settings;
+mod render;
+mod export;
+mod sync;
+mod import;
+mod batch;
+
+/// Five clickable stars, returns the new rating when one is clicked. Clicking the current rating clears it.
+fn rating_stars(ui: &mut egui::Ui, rating: u8) -> Option<u8> {
+    let mut clicked = None;
+    ui.horizontal(|ui| {
+        ui.spacing_mut().item_spacing.x = 0.0;
+        for star in 1..=5 {
+            let (text, color) = if star <= rating { ("★", Color32::from_rgb(230, 180, 40)) } else { ("☆", Color32::GRAY) };
+            if ui.add(egui::Label::new(egui::RichText::new(text).color(color).size(16.0)).sense(egui::Sense::click())).clicked() {
+                clicked = Some(if star == rating { 0 } else { star });
+            }
+        }
+    });
+    clicked
+}
 
 struct ButtonStyle {
     base_color: Color32,
@@ -64,57 +105,688 @@ fn button(ui: &mut egui::Ui, button_style: &ButtonStyle, text: &str, dims: egui:
     response
 }
 
+/// One line summary of the song's numbering and stream details
+fn song_details(song: &playset::Song) -> String {
+    let mut parts = vec![];
+    if let Some(track) = song.track_number {
+        parts.push(format!("Track {}", track));
+    }
+    if let Some(disc) = song.disc_number {
+        parts.push(format!("Disc {}", disc));
+    }
+    if let Some(year) = song.year {
+        parts.push(year.to_string());
+    }
+    if !song.composer.is_empty() {
+        parts.push(format!("Composed by {}", song.composer));
+    }
+    parts.push(format!("{}:{:02}", song.duration / 60, song.duration % 60));
+    if let Some(bpm) = song.bpm {
+        parts.push(format!("{:.0} BPM", bpm));
+    }
+    if let Some(key) = &song.key {
+        parts.push(format!("Key {}", key));
+    }
+    parts.push(format!("{} {} kbps {:.1} kHz", song.codec, song.bitrate, song.sample_rate as f32 / 1000.0));
+    parts.push(format!("{:.1} MB", song.file_size as f32 / 1_000_000.0));
+    parts.join(" · ")
+}
+
+/// Fisher-Yates with a xorshift seeded from the clock, plenty for a play order
+fn shuffle<T>(items: &mut [T]) {
+    let mut state = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0) | 1;
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+/// The queue entry being listened to, for logging when it ends
+struct NowPlaying {
+    /// Which queue the entry belongs to, see `queue_generation`
+    generation: u64,
+    index: usize,
+    song: playset::Song,
+    position: u64,
+}
+
 struct MyEguiApp {
     display_menu: bool,
     library_name: String,
-    stream: Option<OutputStream>,
-    stream_handle: Option<OutputStreamHandle>,
+    output: music_player::Output,
     sink: Sink,
     library: playset::Library,
-    songs_to_show: HashSet<playset::Song>,
-    editing_this_set: Option<RefCell<Rc<SongTree>>>,
+    songs_to_show: Vec<playset::Song>,
+    queue: Vec<playset::Song>,
+    editing_this_set: Option<String>,
     show_songs: bool,
     playing: String,
     display_set_menu: bool,
     selected_set: playset::Playset,
     selected_transformation: String,
+    renaming_set: Option<String>,
+    rename_buffer: String,
+    deleting_set: Option<String>,
+    show_tree_editor: bool,
+    selected_songs: HashSet<String>,
+    tag_form: Option<tag_editor::TagForm>,
+    user_tag_form: Option<user_tags::UserTagForm>,
+    /// `None` keeps the play order of the set
+    sort_by: Option<SongField>,
+    covers: CoverCache,
+    cover_textures: covers::CoverTextures,
+    /// Covers shown on each playset tile, valid for `set_covers_revision` of the library
+    set_covers: HashMap<String, Vec<PathBuf>>,
+    set_covers_revision: u64,
+    browser: Option<browser::Browser>,
+    duplicates: Option<duplicates::DuplicateView>,
+    waveforms: WaveformCache,
+    tap: music_player::Tap,
+    spectrum: spectrum::Spectrum,
+    show_spectrum: bool,
+    /// Bumped whenever the queue is replaced, so replaying the same song counts as a new play
+    queue_generation: u64,
+    now_playing: Option<NowPlaying>,
+    show_stats: bool,
+    stats_period: u64,
+    /// Also store ratings in the files' POPM tags
+    write_rating_tags: bool,
+    volume: f32,
+    shuffle: bool,
+    repeat: Repeat,
+    /// Outer position and inner size of the window, see `Session::window`
+    window: Option<[f32; 4]>,
+    last_session_save: f64,
+    config: Config,
+    config_path: PathBuf,
+    settings: Option<settings::SettingsForm>,
+    render: Option<render::RenderForm>,
+    export: Option<export::ExportForm>,
+    sync: Option<sync::SyncForm>,
+    /// Deleted sets and the names of the sync profiles they were taken out of, to put back on undo
+    unsynced_sets: HashMap<String, Vec<String>>,
+    import: Option<import::ImportForm>,
+    /// Fingerprints being read, with whether to identify untagged songs once they are all in
+    fingerprinting: Option<(batch::Batch<Fingerprint>, bool)>,
+    /// Tempo and key found for each song being analyzed
+    analyzing: Option<batch::Batch<analysis::Analysis>>,
+}
+
+fn apply_theme(ctx: &egui::Context, theme: Theme) {
+    ctx.set_theme(match theme {
+        Theme::Dark => egui::ThemePreference::Dark,
+        Theme::Light => egui::ThemePreference::Light,
+        Theme::System => egui::ThemePreference::System,
+    });
 }
 
 impl MyEguiApp {
-    fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        let (stream, handle) = OutputStream::try_default().unwrap();
-        let library = Library::initialize("./song_library/U", "./song_library/subsets").unwrap();
+    fn new(cc: &eframe::CreationContext<'_>, config: Config, config_path: PathBuf) -> Self {
+        apply_theme(&cc.egui_ctx, config.theme);
+        let (output, sink) = music_player::open_output(&config.audio.output, config.audio.device.as_deref());
+        for dir in [config.songs_dir(), config.subsets_dir()] {
+            if let Err(e) = std::fs::create_dir_all(&dir) {
+                println!("Failed to create {}: {}", dir.display(), e);
+            }
+        }
+        let library = Library::initialize(config.songs_dir(), config.subsets_dir(), &config.scanning).unwrap();
         let set = library.universal_set.clone();
-        Self {
+        let mut app = Self {
             display_menu: false,
-            stream: Some(stream),
-            sink: Sink::try_new(&handle).unwrap(),
-            stream_handle: Some(handle),
+            output,
+            sink,
             library,
             selected_set: set,
             library_name: "".to_string(),
-            songs_to_show: HashSet::new(),
+            songs_to_show: vec![],
+            queue: vec![],
             editing_this_set: None,
             show_songs: false,
             playing: String::new(),
             display_set_menu: false,
             selected_transformation: String::from("Union"),
+            renaming_set: None,
+            rename_buffer: String::new(),
+            deleting_set: None,
+            show_tree_editor: false,
+            selected_songs: HashSet::new(),
+            tag_form: None,
+            user_tag_form: None,
+            sort_by: None,
+            covers: CoverCache::new(config.covers_dir()).unwrap(),
+            cover_textures: covers::CoverTextures::default(),
+            set_covers: HashMap::new(),
+            set_covers_revision: 0,
+            browser: None,
+            duplicates: None,
+            waveforms: WaveformCache::new(config.waveforms_dir()).unwrap(),
+            tap: music_player::Tap::default(),
+            spectrum: spectrum::Spectrum::default(),
+            show_spectrum: false,
+            queue_generation: 0,
+            now_playing: None,
+            show_stats: false,
+            stats_period: 30,
+            write_rating_tags: false,
+            volume: 1.0,
+            shuffle: false,
+            repeat: Repeat::Off,
+            window: None,
+            last_session_save: 0.0,
+            config,
+            config_path,
+            settings: None,
+            render: None,
+            export: None,
+            sync: None,
+            unsynced_sets: HashMap::new(),
+            import: None,
+            fingerprinting: None,
+            analyzing: None,
+        };
+        if app.config.autosave.session {
+            app.restore(Session::load(app.config.session_path()));
+        }
+        app
+    }
+
+    /// Puts back the queue paused at the saved position, along with the rest of the session
+    fn restore(&mut self, session: Session) {
+        self.volume = session.volume.clamp(0.0, 1.0);
+        self.sink.set_volume(self.volume);
+        self.shuffle = session.shuffle;
+        self.repeat = session.repeat;
+        self.show_spectrum = session.show_spectrum;
+        self.show_stats = session.show_stats;
+        self.window = session.window;
+        if let Some(name) = session.open_set.filter(|n| self.library.sets.contains_key(n)) {
+            self.open_set(name);
+        }
+
+        // Songs deleted since are dropped, keeping the saved track where it was
+        let mut track = 0;
+        for (i, name) in session.queue.iter().enumerate() {
+            if let Some(song) = self.library.song_by_name(name) {
+                if i < session.track {
+                    track += 1;
+                }
+                self.queue.push(song);
+            }
+        }
+        let Some(song) = self.queue.get(track) else {
+            self.queue.clear();
+            return;
+        };
+        self.playing = song.display_title().to_string();
+        self.resume_at(track, std::time::Duration::from_secs_f32(session.position.max(0.0)), true);
+    }
+
+    /// Loads the queue from `index` into the sink. Songs whose files can't be played are dropped from the queue,
+    /// returns false if the one at `index` was among them.
+    fn load_queue(&mut self, index: usize) -> bool {
+        let paths = self.queue[index..].iter().map(|s| self.library.song_path(&s.name)).collect::<Vec<PathBuf>>();
+        let skipped = music_player::queue_music(&paths, &self.sink, &self.tap);
+        for i in skipped.iter().rev() {
+            self.queue.remove(index + i);
+        }
+        skipped.first() != Some(&0)
+    }
+
+    /// Loads the queue from `track` into the sink and seeks to `position`, without counting a new play
+    fn resume_at(&mut self, track: usize, position: std::time::Duration, paused: bool) {
+        // The position belongs to the song at `track`, not to one that moved up after it was dropped
+        if self.load_queue(track) && !position.is_zero() && let Err(e) = self.sink.try_seek(position) {
+            println!("Failed to seek: {}", e);
+        }
+        if !paused {
+            self.sink.play();
+        }
+    }
+
+    /// Moves playback to the output `audio` names, carrying on from the same spot in the same song
+    fn switch_output(&mut self, audio: AudioConfig) {
+        let resume = self.current_song().is_some().then(|| (self.queue.len() - self.sink.len(), self.sink.get_pos(), self.sink.is_paused()));
+        self.sink.stop();
+        let (output, sink) = music_player::open_output(&audio.output, audio.device.as_deref());
+        self.output = output;
+        self.sink = sink;
+        self.sink.set_volume(self.volume);
+        if let Some((track, position, paused)) = resume {
+            self.resume_at(track, position, paused);
+        }
+        self.config.audio = audio;
+    }
+
+    fn session(&self) -> Session {
+        let playing = self.current_song().is_some();
+        Session {
+            queue: if playing { self.queue.iter().map(|s| s.name.clone()).collect() } else { vec![] },
+            track: if playing { self.queue.len() - self.sink.len() } else { 0 },
+            position: if playing { self.sink.get_pos().as_secs_f32() } else { 0.0 },
+            volume: self.volume,
+            shuffle: self.shuffle,
+            repeat: self.repeat,
+            open_set: if self.show_songs { self.editing_this_set.clone() } else { None },
+            show_spectrum: self.show_spectrum,
+            show_stats: self.show_stats,
+            window: self.window,
+        }
+    }
+
+    fn save_session(&mut self, time: f64) {
+        self.last_session_save = time;
+        if !self.config.autosave.session {
+            return;
+        }
+        if let Err(e) = self.session().save(self.config.session_path()) {
+            println!("Failed to save session: {}", e);
+        }
+    }
+}
+
+impl MyEguiApp {
+    fn refresh_songs(&mut self) {
+        let Some(name) = &self.editing_this_set else {
+            return;
+        };
+        match self.library.sets.get(name) {
+            Some(playset) => {
+                self.songs_to_show = playset.songs.borrow().queue(&self.library);
+                if let Some(field) = self.sort_by {
+                    self.songs_to_show.sort_by(|a, b| field.compare(a, b));
+                }
+            },
+            None => {
+                self.editing_this_set = None;
+                self.show_songs = false;
+                self.songs_to_show.clear();
+            }
+        }
+    }
+
+    fn current_song(&self) -> Option<&playset::Song> {
+        if self.sink.empty() || self.sink.len() > self.queue.len() {
+            return None;
+        }
+        self.queue.get(self.queue.len() - self.sink.len())
+    }
+
+    /// Covers of up to four different albums in the set
+    fn set_covers(&mut self, name: &str) -> Vec<PathBuf> {
+        if self.set_covers_revision != self.library.revision {
+            self.set_covers.clear();
+            self.set_covers_revision = self.library.revision;
+        }
+        if let Some(covers) = self.set_covers.get(name) {
+            return covers.clone();
+        }
+
+        let songs = self.library.sets.get(name).map(|p| p.songs.borrow().queue(&self.library)).unwrap_or_default();
+        let mut albums = HashSet::new();
+        let mut covers = vec![];
+        for song in songs {
+            if covers.len() == 4 {
+                break;
+            }
+            if !albums.insert((song.artist.clone(), song.album.clone())) {
+                continue;
+            }
+            if let Some(cover) = self.covers.cover(self.library.song_path(&song.name), &song, CoverSize::Thumbnail) {
+                covers.push(cover);
+            }
+        }
+        self.set_covers.insert(name.to_owned(), covers.clone());
+        covers
+    }
+
+    fn play_songs(&mut self, songs: Vec<playset::Song>) {
+        if songs.is_empty() {
+            return;
+        }
+        self.queue = songs;
+        self.play_from(0);
+    }
+
+    /// Same as `play_songs`, but in random order when shuffle is on
+    fn play_shuffled(&mut self, mut songs: Vec<playset::Song>) {
+        if self.shuffle {
+            shuffle(&mut songs);
+        }
+        self.play_songs(songs);
+    }
+
+    /// Starts the queue over from `index`, counting as a new play of each song
+    fn play_from(&mut self, index: usize) {
+        self.queue_generation += 1;
+        self.load_queue(index);
+        self.sink.play();
+    }
+
+    /// Replays the song that just ended, or the whole queue once it ran out
+    fn apply_repeat(&mut self) {
+        let Some(playing) = &self.now_playing else {
+            return;
+        };
+        if playing.generation != self.queue_generation || self.sink.is_paused() {
+            return;
+        }
+        let index = playing.index;
+        let moved_on = self.sink.len() < self.queue.len() - index;
+        match self.repeat {
+            Repeat::One if moved_on => self.play_from(index),
+            Repeat::All if self.sink.empty() && !self.queue.is_empty() => self.play_from(0),
+            _ => {},
+        }
+    }
+
+    fn open_set(&mut self, name: String) {
+        self.editing_this_set = Some(name);
+        self.show_songs = true;
+        self.refresh_songs();
+    }
+
+    /// Logs a start when a queue entry begins and a finish or skip when it is left
+    fn track_listening(&mut self) {
+        let current = self.current_song().cloned().map(|song| (self.queue_generation, self.queue.len() - self.sink.len(), song));
+        let position = self.sink.get_pos().as_secs();
+
+        if let Some(playing) = &self.now_playing {
+            if current.as_ref().is_some_and(|(generation, index, _)| *generation == playing.generation && *index == playing.index) {
+                self.now_playing.as_mut().unwrap().position = position;
+                return;
+            }
+            self.end_now_playing();
+        }
+
+        if let Some((generation, index, song)) = current {
+            if let Err(e) = self.library.record_play(&song.name, PlayKind::Start, 0) {
+                println!("Failed to log play: {}", e);
+            }
+            self.now_playing = Some(NowPlaying { generation, index, song, position });
+        }
+    }
+
+    /// Logs the song that was playing as finished or skipped, depending on how far it got
+    fn end_now_playing(&mut self) {
+        let Some(playing) = self.now_playing.take() else {
+            return;
+        };
+        let kind = if listening::listened(&playing.song, playing.position) { PlayKind::Finish } else { PlayKind::Skip };
+        if let Err(e) = self.library.record_play(&playing.song.name, kind, playing.position) {
+            println!("Failed to log play: {}", e);
+        }
+    }
+
+    /// Fingerprints every song that has none yet on a background thread, `identify` runs `identify_untagged` afterwards
+    fn fingerprint_songs(&mut self, identify: bool) {
+        if let Some((_, then_identify)) = &mut self.fingerprinting {
+            *then_identify |= identify;
+            return;
+        }
+        let missing = self.library.missing_fingerprints();
+        if missing.is_empty() {
+            self.finish_fingerprinting(vec![], identify);
+            return;
+        }
+        self.fingerprinting = Some((batch::Batch::start("Fingerprinting", missing, |path| Fingerprint::from_file(path)), identify));
+    }
+
+    fn finish_fingerprinting(&mut self, results: Vec<(String, std::io::Result<Fingerprint>)>, identify: bool) {
+        let mut fingerprints = vec![];
+        for (name, result) in results {
+            match result {
+                Ok(fingerprint) => fingerprints.push((name, fingerprint)),
+                Err(e) => println!("Failed to fingerprint {}: {}", name, e),
+            }
+        }
+        match self.library.add_fingerprints(fingerprints) {
+            Ok(added) => println!("Fingerprinted {} songs", added),
+            Err(e) => println!("Failed to save fingerprints: {}", e),
+        }
+        if identify {
+            match self.library.identify_untagged() {
+                Ok(identified) => println!("Identified {} songs", identified.len()),
+                Err(e) => println!("Failed to identify songs: {}", e),
+            }
+        }
+    }
+
+    /// Keeps the sync profiles pointing at a renamed set, `to` None drops a deleted one and remembers from where
+    fn rename_synced_set(&mut self, from: &str, to: Option<&str>) {
+        let mut synced_by = vec![];
+        for profile in &mut self.config.sync {
+            if profile.rename_set(from, to) {
+                synced_by.push(profile.name.clone());
+            }
+        }
+        if let Some(form) = &mut self.sync {
+            for profile in &mut form.profiles {
+                profile.rename_set(from, to);
+            }
+        }
+        if synced_by.is_empty() {
+            return;
+        }
+        if to.is_none() {
+            self.unsynced_sets.insert(from.to_owned(), synced_by);
+        }
+        self.save_sync_profiles();
+    }
+
+    /// Puts a set whose deletion was undone back into the profiles that synced it
+    fn resync_set(&mut self, name: &str) {
+        let Some(synced_by) = self.unsynced_sets.remove(name) else {
+            return;
+        };
+        let profiles = self.config.sync.iter_mut().chain(self.sync.iter_mut().flat_map(|form| form.profiles.iter_mut()));
+        for profile in profiles.filter(|p| synced_by.contains(&p.name) && !p.sets.iter().any(|s| s == name)) {
+            profile.sets.push(name.to_owned());
+        }
+        self.save_sync_profiles();
+    }
+
+    /// Sync profiles follow renames and deletes that are undone or redone, like the sets referencing them
+    fn follow_in_sync(&mut self, edit: &Edit, undone: bool) {
+        match (edit, undone) {
+            (Edit::RenameSet { from, to }, true) => self.rename_synced_set(to, Some(from)),
+            (Edit::RenameSet { from, to }, false) => self.rename_synced_set(from, Some(to)),
+            (Edit::DeleteSet { name, .. }, true) => self.resync_set(name),
+            (Edit::DeleteSet { name, .. }, false) => self.rename_synced_set(name, None),
+            _ => {},
+        }
+    }
+
+    fn save_sync_profiles(&self) {
+        save_config_field(&self.config_path, |file| file.sync = self.config.sync.clone());
+    }
+
+    fn undo(&mut self) {
+        match self.library.undo() {
+            Ok(edit) => {
+                if let Some(edit) = edit {
+                    self.follow_in_sync(&edit, true);
+                }
+                self.refresh_songs();
+            },
+            Err(e) => println!("Failed to undo: {}", e),
+        }
+    }
+    fn redo(&mut self) {
+        match self.library.redo() {
+            Ok(edit) => {
+                if let Some(edit) = edit {
+                    self.follow_in_sync(&edit, false);
+                }
+                self.refresh_songs();
+            },
+            Err(e) => println!("Failed to redo: {}", e),
         }
     }
 }
 
 impl eframe::App for MyEguiApp {
    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // Text fields keep Ctrl+Z for their own undo
+        if !ctx.wants_keyboard_input() {
+            // Check redo first, consume_shortcut ignores the extra shift on Ctrl+Shift+Z
+            let redo = ctx.input_mut(|i| i.consume_shortcut(&egui::KeyboardShortcut::new(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::Z)));
+            let undo = ctx.input_mut(|i| i.consume_shortcut(&egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::Z)));
+            if redo {
+                self.redo();
+            } else if undo {
+                self.undo();
+            }
+        }
+
+        self.apply_repeat();
+        self.track_listening();
+        if let Some(results) = self.fingerprinting.as_mut().and_then(|(batch, _)| batch.poll()) {
+            let (_, identify) = self.fingerprinting.take().unwrap();
+            self.finish_fingerprinting(results, identify);
+        }
+        if let Some(results) = self.analyzing.as_mut().and_then(|batch| batch.poll()) {
+            self.analyzing = None;
+            match self.library.add_analysis(results) {
+                Ok(analyzed) => println!("Analyzed {} songs", analyzed),
+                Err(e) => println!("Failed to save analysis: {}", e),
+            }
+        }
+
+        let (time, window) = ctx.input(|i| (i.time, i.viewport().outer_rect.zip(i.viewport().inner_rect)));
+        if let Some((outer, inner)) = window {
+            self.window = Some([outer.min.x, outer.min.y, inner.width(), inner.height()]);
+        }
+        if time - self.last_session_save > self.config.autosave.interval as f64 {
+            self.save_session(time);
+        }
+
+        // The sink drops songs as they finish, so whatever it still holds is the tail of the queue
+        if let Some(song) = self.current_song() {
+            self.playing = song.display_title().to_string();
+        }
+
+        // Tiles are drawn again with the covers that came in
+        if self.covers.poll() {
+            self.set_covers.clear();
+        }
+        if self.covers.is_pending() {
+            ctx.request_repaint_after(std::time::Duration::from_millis(200));
+        }
+
+        let current = self.current_song().cloned();
+        let cover = current.as_ref()
+            .and_then(|song| self.covers.cover(self.library.song_path(&song.name), song, CoverSize::Large))
+            .and_then(|path| self.cover_textures.get(ctx, &path));
+        let waveform = current.as_ref().and_then(|song| self.waveforms.get(self.library.song_path(&song.name), song));
+        if current.is_some() && !self.sink.is_paused() {
+            // Keeps the played part of the waveform moving
+            ctx.request_repaint_after(std::time::Duration::from_millis(100));
+        }
+        egui::TopBottomPanel::bottom("player").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if let Some(cover) = &cover {
+                    covers::mosaic(ui, std::slice::from_ref(cover), 120.0);
+                }
+                ui.vertical(|ui| {
+                    ui.label(&self.playing);
+                    if let Some(song) = &current {
+                        let duration = song.duration.max(1) as f32;
+                        let position = self.sink.get_pos().as_secs_f32();
+                        ui.horizontal(|ui| {
+                            let seek = waveform::seek_bar(ui, waveform.as_deref(), position / duration, egui::Vec2::new(400.0, 40.0));
+                            if let Some(fraction) = seek
+                                && let Err(e) = self.sink.try_seek(std::time::Duration::from_secs_f32(fraction * duration))
+                            {
+                                println!("Failed to seek: {}", e);
+                            }
+                            ui.label(format!("{}:{:02} / {}:{:02}", position as u64 / 60, position as u64 % 60, song.duration / 60, song.duration % 60));
+                        });
+                    }
+                });
+
+                let text = if self.sink.is_paused() { "Play" } else { "Pause" };
+                if button(ui, &GLOBAL_BUTTON_STYLE, text, egui::Vec2::new(100.0, 30.0)).clicked() {
+                    if self.sink.is_paused() {
+                        self.sink.play();
+                    } else {
+                        self.sink.pause();
+                    }
+                }
+                if ui.add(egui::Slider::new(&mut self.volume, 0.0..=1.0).show_value(false)).on_hover_text("Volume").changed() {
+                    self.sink.set_volume(self.volume);
+                }
+                ui.toggle_value(&mut self.shuffle, "Shuffle").on_hover_text("Play sets and albums in random order");
+                if button(ui, &GLOBAL_BUTTON_STYLE, self.repeat.label(), egui::Vec2::new(90.0, 30.0)).clicked() {
+                    self.repeat = self.repeat.next();
+                }
+                let text = if self.show_spectrum { "Hide spectrum" } else { "Spectrum" };
+                if button(ui, &GLOBAL_BUTTON_STYLE, text, egui::Vec2::new(100.0, 30.0)).clicked() {
+                    self.show_spectrum = !self.show_spectrum;
+                }
+            });
+        });
+        if self.show_spectrum {
+            if !self.sink.is_paused() && !self.sink.empty() {
+                ctx.request_repaint();
+            }
+            egui::TopBottomPanel::bottom("spectrum").show(ctx, |ui| self.spectrum.show(ui, &self.tap));
+        }
+
         egui::TopBottomPanel::top("header").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.label(egui::RichText::new("Play set").color(Color32::from_rgb(200, 50, 180)).size(50.0));
+                if let Some((batch, _)) = &self.fingerprinting {
+                    batch.show(ui);
+                }
+                if let Some(batch) = &self.analyzing {
+                    batch.show(ui);
+                }
 
                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                    if self.show_songs {
+                    if self.show_songs || self.browser.is_some() || self.duplicates.is_some() {
                     } else {
                         if button(ui, &GLOBAL_BUTTON_STYLE, "Create Play set!", egui::Vec2::new(100.0, 30.0)).clicked() {
                             self.display_menu = true;
                         }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Browse library", egui::Vec2::new(100.0, 30.0)).clicked() {
+                            self.browser = Some(browser::Browser::new(&self.library));
+                        }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Find duplicates", egui::Vec2::new(100.0, 30.0)).clicked() {
+                            self.duplicates = Some(duplicates::DuplicateView::default());
+                        }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Settings", egui::Vec2::new(70.0, 30.0)).clicked() {
+                            // Edits what's in the file, not the command line overrides
+                            match Config::load(&self.config_path) {
+                                Ok(config) => self.settings = Some(settings::SettingsForm::new(config, self.config_path.clone(), music_player::output_devices())),
+                                Err(e) => println!("Failed to read {}: {}", self.config_path.display(), e),
+                            }
+                        }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Import", egui::Vec2::new(70.0, 30.0)).on_hover_text("Download with yt-dlp or bring in a folder of files").clicked() {
+                            self.import = Some(import::ImportForm::new(self.config.import.clone(), self.library.songs_dir().to_path_buf()));
+                        }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Sync devices", egui::Vec2::new(90.0, 30.0)).clicked() {
+                            self.sync = Some(sync::SyncForm::new(self.config.sync.clone()));
+                        }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Listening stats", egui::Vec2::new(100.0, 30.0)).clicked() {
+                            self.show_stats = true;
+                        }
+                        if self.analyzing.is_none() && button(ui, &GLOBAL_BUTTON_STYLE, "Analyze tempo & key", egui::Vec2::new(130.0, 30.0)).clicked() {
+                            self.analyzing = Some(batch::Batch::start("Analyzing", self.library.unanalyzed_songs(), |path| analysis::analyze(path)));
+                        }
+                        if button(ui, &GLOBAL_BUTTON_STYLE, "Identify untagged", egui::Vec2::new(110.0, 30.0))
+                            .on_hover_text("Fill in missing tags by matching audio against fingerprints.json in the library")
+                            .clicked()
+                        {
+                            self.fingerprint_songs(true);
+                        }
+                    }
+                    if self.library.history.can_redo() && button(ui, &GLOBAL_BUTTON_STYLE, "Redo", egui::Vec2::new(50.0, 30.0)).clicked() {
+                        self.redo();
+                    }
+                    if self.library.history.can_undo() && button(ui, &GLOBAL_BUTTON_STYLE, "Undo", egui::Vec2::new(50.0, 30.0)).clicked() {
+                        self.undo();
                     }
                 });
             });
@@ -125,7 +797,9 @@ impl eframe::App for MyEguiApp {
                         ui.label("Play set name");
                         let response = ui.add(egui::TextEdit::singleline(&mut self.library_name));
                         if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) || button(ui, &GLOBAL_BUTTON_STYLE, "Add", egui::Vec2::new(50.0, 30.0)).clicked() {
-                            Library::push_empty_set(&mut self.library, self.library_name.clone()); 
+                            if let Err(e) = self.library.create_set(self.library_name.clone()) {
+                                println!("Failed to create set: {}", e);
+                            }
                             self.library_name.clear();
                             self.display_menu = false;
                         }
@@ -133,8 +807,260 @@ impl eframe::App for MyEguiApp {
                 });
             }
 
+            if let Some(renaming) = self.renaming_set.clone() {
+                egui::Window::new("Rename").resizable([false, false]).default_size((400.0, 400.0)).show(ctx, |ui| {
+                    ui.vertical(|ui| {
+                        ui.label(format!("New name for {}", renaming));
+                        let response = ui.add(egui::TextEdit::singleline(&mut self.rename_buffer));
+                        ui.horizontal(|ui| {
+                            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) || button(ui, &GLOBAL_BUTTON_STYLE, "Rename", egui::Vec2::new(60.0, 30.0)).clicked() {
+                                match self.library.rename_set(&renaming, self.rename_buffer.clone()) {
+                                    Ok(()) => self.rename_synced_set(&renaming, Some(&self.rename_buffer.clone())),
+                                    Err(e) => println!("Failed to rename set: {}", e),
+                                }
+                                self.rename_buffer.clear();
+                                self.renaming_set = None;
+                            }
+                            if button(ui, &GLOBAL_BUTTON_STYLE, "Cancel", egui::Vec2::new(60.0, 30.0)).clicked() {
+                                self.rename_buffer.clear();
+                                self.renaming_set = None;
+                            }
+                        });
+                    })
+                });
+            }
+
+            if let Some(deleting) = self.deleting_set.clone() {
+                let referenced_by = self.library.referencing_sets(&deleting);
+                egui::Window::new("Delete").resizable([false, false]).default_size((400.0, 400.0)).show(ctx, |ui| {
+                    ui.vertical(|ui| {
+                        if referenced_by.is_empty() {
+                            ui.label(format!("Delete {}?", deleting));
+                        } else {
+                            ui.label(format!("{} is used by {}.", deleting, referenced_by.join(", ")));
+                            ui.label("Deleting it will copy its current songs into those sets.");
+                        }
+                        ui.horizontal(|ui| {
+                            let text = if referenced_by.is_empty() { "Delete" } else { "Inline and delete" };
+                            if button(ui, &GLOBAL_BUTTON_STYLE, text, egui::Vec2::new(120.0, 30.0)).clicked() {
+                                let result = if referenced_by.is_empty() {
+                                    self.library.delete_set(&deleting)
+                                } else {
+                                    self.library.delete_set_inlined(&deleting)
+                                };
+                                match result {
+                                    Ok(()) => self.rename_synced_set(&deleting, None),
+                                    Err(e) => println!("Failed to delete set: {}", e),
+                                }
+                                self.deleting_set = None;
+                            }
+                            if button(ui, &GLOBAL_BUTTON_STYLE, "Cancel", egui::Vec2::new(60.0, 30.0)).clicked() {
+                                self.deleting_set = None;
+                            }
+                        });
+                    })
+                });
+            }
+
+            if let (true, Some(editing)) = (self.show_tree_editor, self.editing_this_set.clone()) {
+                let mut open = true;
+                let edited = egui::Window::new(format!("Expression of {}", editing))
+                    .open(&mut open)
+                    .default_size((500.0, 400.0))
+                    .show(ctx, |ui| tree_editor::show(ui, &self.library, &editing))
+                    .and_then(|r| r.inner)
+                    .flatten();
+                self.show_tree_editor = open;
+                if let Some(tree) = edited {
+                    if let Err(e) = self.library.replace_set_tree(&editing, tree) {
+                        println!("Failed to edit set: {}", e);
+                    }
+                    self.refresh_songs();
+                }
+            }
+
+            if let Some(form) = &mut self.settings {
+                let action = egui::Window::new("Settings")
+                    .resizable([false, false])
+                    .default_size((450.0, 400.0))
+                    .show(ctx, |ui| settings::show(ui, form, &self.output.name))
+                    .and_then(|r| r.inner)
+                    .flatten();
+                match action {
+                    Some(settings::SettingsAction::Save) => {
+                        let draft = form.draft.clone();
+                        save_config_field(&form.path, |file| form.patch(file));
+                        // Library paths need a restart, the rest applies now
+                        apply_theme(ctx, draft.theme);
+                        self.config.theme = draft.theme;
+                        self.config.autosave = draft.autosave;
+                        self.config.scanning = draft.scanning;
+                        // Compared with the file rather than the running output, so a --device or --output
+                        // override keeps playing until the audio settings themselves are changed
+                        if form.audio_changed() {
+                            self.switch_output(draft.audio);
+                        }
+                        self.settings = None;
+                    },
+                    Some(settings::SettingsAction::Cancel) => self.settings = None,
+                    None => {},
+                }
+            }
+
+            if let Some(form) = &mut self.render {
+                let close = egui::Window::new("Render")
+                    .resizable([false, false])
+                    .show(ctx, |ui| render::show(ui, form))
+                    .and_then(|r| r.inner)
+                    .unwrap_or(false);
+                if close {
+                    self.render = None;
+                }
+            }
+
+            if let Some(form) = &mut self.export {
+                let close = egui::Window::new("Export")
+                    .resizable([false, false])
+                    .show(ctx, |ui| export::show(ui, form))
+                    .and_then(|r| r.inner)
+                    .unwrap_or(false);
+                if close {
+                    self.config.export = form.options.clone();
+                    save_config_field(&self.config_path, |file| file.export = form.options.clone());
+                    self.export = None;
+                }
+            }
+
+            if let Some(form) = &mut self.import {
+                let mut sets = self.library.sets.keys().cloned().collect::<Vec<String>>();
+                sets.sort();
+                let action = egui::Window::new("Import")
+                    .resizable([false, false])
+                    .show(ctx, |ui| import::show(ui, form, &sets))
+                    .and_then(|r| r.inner)
+                    .flatten();
+                match action {
+                    Some(import::ImportAction::Imported(names, add_to)) => {
+                        let result = self.library.add_files(&names).and_then(|_| match add_to {
+                            Some(set) if !names.is_empty() => self.library.add_songs(&set, names),
+                            _ => Ok(()),
+                        });
+                        if let Err(e) = result {
+                            println!("Failed to add imported songs: {}", e);
+                        }
+                        self.refresh_songs();
+                    },
+                    Some(import::ImportAction::Close) => {
+                        if form.options != self.config.import {
+                            self.config.import = form.options.clone();
+                            save_config_field(&self.config_path, |file| file.import = form.options.clone());
+                        }
+                        self.import = None;
+                    },
+                    None => {},
+                }
+            }
+
+            let mut refresh = false;
+            if let Some(form) = &mut self.sync {
+                let mut sets = self.library.sets.keys().cloned().collect::<Vec<String>>();
+                sets.sort();
+                let action = egui::Window::new("Sync devices")
+                    .resizable([false, false])
+                    .default_size((450.0, 400.0))
+                    .show(ctx, |ui| sync::show(ui, form, &sets))
+                    .and_then(|r| r.inner)
+                    .flatten();
+                if let Some(changes) = form.take_device_changes() {
+                    match self.library.add_device_changes(changes) {
+                        Ok(read_back) => form.read_back(read_back),
+                        Err(e) => form.failed(e.to_string()),
+                    }
+                    refresh = true;
+                }
+                match action {
+                    // A set missing from the sync would have its files deleted from the device
+                    Some(sync::SyncAction::Sync(profile)) if profile.sets.iter().any(|name| !self.library.sets.contains_key(name)) => {
+                        let missing = profile.sets.iter().filter(|name| !self.library.sets.contains_key(*name)).cloned().collect::<Vec<String>>();
+                        form.failed(format!("{} no longer exist, choose the sets again", missing.join(", ")));
+                    },
+                    Some(sync::SyncAction::Sync(profile)) => {
+                        let sets = profile.sets.iter()
+                            .map(|name| (name.clone(), self.library.sets[name].songs.borrow().queue(&self.library)))
+                            .collect();
+                        let songs_dir = self.library.songs_dir().to_path_buf();
+                        form.start(profile, playset::sync::song_titles(&self.library), sets, songs_dir);
+                    },
+                    Some(sync::SyncAction::Close) => {
+                        self.config.sync = form.profiles.clone();
+                        self.save_sync_profiles();
+                        self.sync = None;
+                    },
+                    None => {},
+                }
+            }
+            if refresh {
+                self.refresh_songs();
+            }
+
+            if self.show_stats {
+                egui::Window::new("Listening stats")
+                    .open(&mut self.show_stats)
+                    .default_size((400.0, 500.0))
+                    .show(ctx, |ui| stats::show(ui, &self.library, &mut self.stats_period));
+            }
+
+            if let Some(form) = &mut self.tag_form {
+                let result = egui::Window::new("Edit tags")
+                    .resizable([false, false])
+                    .default_size((400.0, 300.0))
+                    .show(ctx, |ui| tag_editor::show(ui, form))
+                    .and_then(|r| r.inner)
+                    .flatten();
+                match result {
+                    Some(tag_editor::TagFormResult::Apply(edit)) => {
+                        let songs = form.songs.clone();
+                        for song in &songs {
+                            self.covers.forget(song);
+                        }
+                        if !edit.is_empty() && let Err(e) = self.library.edit_tags(&songs, &edit) {
+                            println!("Failed to write tags: {}", e);
+                        }
+                        self.tag_form = None;
+                        self.refresh_songs();
+                    },
+                    Some(tag_editor::TagFormResult::Cancel) => self.tag_form = None,
+                    None => {},
+                }
+            }
+
+            if let Some(form) = &mut self.user_tag_form {
+                let action = egui::Window::new("User tags")
+                    .resizable([false, false])
+                    .default_size((350.0, 200.0))
+                    .show(ctx, |ui| user_tags::show(ui, form, &self.library))
+                    .and_then(|r| r.inner)
+                    .flatten();
+                let songs = form.songs.clone();
+                let result = match action {
+                    Some(user_tags::UserTagAction::Add(tag)) => Some(self.library.add_user_tag(&songs, &tag)),
+                    Some(user_tags::UserTagAction::Remove(tag)) => Some(self.library.remove_user_tag(&songs, &tag)),
+                    Some(user_tags::UserTagAction::Close) => {
+                        self.user_tag_form = None;
+                        None
+                    },
+                    None => None,
+                };
+                if let Some(result) = result {
+                    if let Err(e) = result {
+                        println!("Failed to change tags: {}", e);
+                    }
+                    self.refresh_songs();
+                }
+            }
+
             if self.display_set_menu {
-                let transformations = vec!["Union", "Difference", "Intersection"];
+                let transformations = vec!["Union", "Difference", "Intersection", "Symmetric Difference"];
                 egui::Window::new("Setup your sets").resizable([false, false]).default_size((400.0, 400.0)).show(ctx, |ui| {
                     egui::ComboBox::from_label("Select Set").selected_text(format!("{}", self.selected_set.name)).show_ui(ui, |ui| {
                         for option in &self.library.sets {
@@ -151,21 +1077,18 @@ impl eframe::App for MyEguiApp {
                     if button(ui, &GLOBAL_BUTTON_STYLE, "Transform Set", egui::Vec2::new(100.0, 15.0)).clicked() {
                         let to_edit = self.editing_this_set.as_ref().unwrap().clone();
 
-                        let mut s = to_edit.borrow().to_pset_string();
-                        s.push_str(&self.selected_set.name);
-                        s.push(pset_format::SEPERATOR);
-                        s.push(match self.selected_transformation.as_str() {
+                        let op = match self.selected_transformation.as_str() {
                             "Union" => pset_format::UNION,
                             "Difference" => pset_format::DIFFERENCE,
                             "Intersection" => pset_format::INTERSECTION,
+                            "Symmetric Difference" => pset_format::SYMMETRIC_DIFFERENCE,
                             _ => panic!(),
-                        });
-                        *to_edit.borrow_mut() = Rc::new(SongTree::from_pset_string(&s));
-                        println!("?????\n{:#?}", to_edit);
-                        self.editing_this_set = Some(to_edit);
-                        println!("?????Q?@??!??!?\n{:#?}", self.editing_this_set);
+                        };
+                        if let Err(e) = self.library.apply_operation(&to_edit, op, self.selected_set.name.clone()) {
+                            println!("Failed to transform set: {}", e);
+                        }
+                        self.refresh_songs();
 
-                        
                         self.selected_transformation = String::from("Union");
                         self.selected_set = self.library.universal_set.clone();
                         self.display_set_menu = false;
@@ -174,28 +1097,128 @@ impl eframe::App for MyEguiApp {
             }
         });
 
+        if let Some(mut browser) = self.browser.take() {
+            let action = egui::CentralPanel::default().show(ctx, |ui| browser.show(ui, &self.library)).inner;
+            self.browser = Some(browser);
+            match action {
+                Some(browser::BrowseAction::Play(songs)) => self.play_shuffled(songs),
+                Some(browser::BrowseAction::CreateSet(name, tree)) => match self.library.create_set_with(name.clone(), tree) {
+                    Ok(_) => {
+                        self.browser = None;
+                        self.open_set(name);
+                    },
+                    Err(e) => println!("Failed to create set: {}", e),
+                },
+                Some(browser::BrowseAction::Close) => self.browser = None,
+                None => {},
+            }
+            return;
+        }
+
+        if let Some(mut view) = self.duplicates.take() {
+            let action = egui::CentralPanel::default().show(ctx, |ui| view.show(ui, &self.library)).inner;
+            self.duplicates = Some(view);
+            match action {
+                Some(duplicates::DuplicateAction::Merge { keep, others }) => {
+                    if let Err(e) = self.library.merge_duplicates(&keep, &others) {
+                        println!("Failed to merge duplicates: {}", e);
+                    }
+                },
+                Some(duplicates::DuplicateAction::Fingerprint) => self.fingerprint_songs(false),
+                Some(duplicates::DuplicateAction::Close) => self.duplicates = None,
+                None => {},
+            }
+            return;
+        }
+
         if self.show_songs {
             egui::CentralPanel::default().show(ctx, |ui| {
                 ui.horizontal(|ui| {
                     if button(ui, &GLOBAL_BUTTON_STYLE, "Back to Play set's", egui::Vec2::new(110.0, 30.0)).clicked() {
                         self.show_songs = false;
+                        self.show_tree_editor = false;
+                        self.selected_songs.clear();
                         self.songs_to_show.clear();
                         return;
                     }
                     if button(ui, &GLOBAL_BUTTON_STYLE, "Add Set Connection", egui::Vec2::new(120.0, 30.0)).clicked() {
                         self.display_set_menu = true; 
                     }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Edit Expression", egui::Vec2::new(110.0, 30.0)).clicked() {
+                        self.show_tree_editor = true;
+                    }
+                    let sort_by = self.sort_by;
+                    egui::ComboBox::from_label("Sort by").selected_text(self.sort_by.map(|f| f.label()).unwrap_or("Play order")).show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.sort_by, None, "Play order");
+                        for field in SONG_FIELDS {
+                            ui.selectable_value(&mut self.sort_by, Some(field), field.label());
+                        }
+                    });
+                    if sort_by != self.sort_by {
+                        self.refresh_songs();
+                    }
+                    if !self.selected_songs.is_empty() && button(ui, &GLOBAL_BUTTON_STYLE, "Edit tags", egui::Vec2::new(80.0, 30.0)).clicked() {
+                        let songs = self.songs_to_show.iter().filter(|s| self.selected_songs.contains(&s.name)).cloned().collect::<Vec<playset::Song>>();
+                        self.tag_form = Some(tag_editor::TagForm::new(&songs));
+                    }
+                    if !self.selected_songs.is_empty() && button(ui, &GLOBAL_BUTTON_STYLE, "Tag selected", egui::Vec2::new(90.0, 30.0)).clicked() {
+                        self.user_tag_form = Some(user_tags::UserTagForm::new(self.selected_songs.iter().cloned().collect()));
+                    }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Play all", egui::Vec2::new(70.0, 30.0)).clicked() {
+                        self.play_shuffled(self.songs_to_show.clone());
+                    }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Harmonic mix", egui::Vec2::new(90.0, 30.0))
+                        .on_hover_text("Play in an order where each song's key and tempo fit the previous one")
+                        .clicked()
+                    {
+                        self.play_songs(analysis::harmonic_order(self.songs_to_show.clone()));
+                    }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Render", egui::Vec2::new(70.0, 30.0)).on_hover_text("Mix the set into one WAV or FLAC file").clicked() {
+                        let songs = self.songs_to_show.iter().map(|s| self.library.song_path(&s.name)).collect();
+                        let name = self.editing_this_set.clone().unwrap_or_default();
+                        self.render = Some(render::RenderForm::new(name, songs, self.config.export.ffmpeg.clone()));
+                    }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Export", egui::Vec2::new(70.0, 30.0)).on_hover_text("Copy or transcode the set to a folder, phone or USB stick").clicked() {
+                        let name = self.editing_this_set.clone().unwrap_or_default();
+                        let songs_dir = self.library.songs_dir().to_path_buf();
+                        self.export = Some(export::ExportForm::new(name, self.songs_to_show.clone(), songs_dir, self.config.export.clone()));
+                    }
                     // if button(ui, &GLOBAL_BUTTON_STYLE, "Add song", egui::Vec2::new(120.0, 30.0)).clicked() {
                     // }
                 });
+                ui.checkbox(&mut self.write_rating_tags, "Save ratings to files").on_hover_text("Writes star ratings into the POPM tag of MP3 files too");
+                let mut to_remove = None;
+                let mut to_rate = None;
+                let mut to_love = None;
                 ui.vertical(|ui| {
                     for song in &self.songs_to_show {
                         ui.group(|ui| {
+                            let mut selected = self.selected_songs.contains(&song.name);
+                            if ui.checkbox(&mut selected, "").changed() {
+                                if selected {
+                                    self.selected_songs.insert(song.name.clone());
+                                } else {
+                                    self.selected_songs.remove(&song.name);
+                                }
+                            }
                             ui.vertical(|ui| {
-                                ui.label(&song.name);
+                                ui.label(song.display_title());
                                 ui.label(&song.album);
                                 ui.label(&song.artist);
-                                //ui.label(song.duration.to_string());
+                                ui.label(egui::RichText::new(song_details(song)).small());
+                                if !song.tags.is_empty() {
+                                    let tags = song.tags.iter().map(|t| format!("#{}", t)).collect::<Vec<String>>().join(" ");
+                                    ui.label(egui::RichText::new(tags).small().color(Color32::from_rgb(200, 50, 180)));
+                                }
+                                ui.horizontal(|ui| {
+                                    if let Some(stars) = rating_stars(ui, song.rating) {
+                                        to_rate = Some((song.name.clone(), stars));
+                                    }
+                                    let (heart, color) = if song.loved { ("♥", Color32::from_rgb(220, 40, 80)) } else { ("♡", Color32::GRAY) };
+                                    if ui.add(egui::Label::new(egui::RichText::new(heart).color(color).size(16.0)).sense(egui::Sense::click())).on_hover_text("Loved").clicked() {
+                                        to_love = Some((song.name.clone(), !song.loved));
+                                    }
+                                });
                             });
                             ui.separator();
  
@@ -205,24 +1228,60 @@ impl eframe::App for MyEguiApp {
                             }
                             if button(ui, &GLOBAL_BUTTON_STYLE, text, egui::Vec2::new(75.0, 30.0)).clicked() {
                                 if self.sink.empty() {
-                                    music_player::play_music(&format!("song_library/U/{}", song.name), &self.stream_handle, &self.sink);
+                                    music_player::play_music(&self.library.song_path(&song.name), &self.output.handle, &self.sink, &self.tap);
                                     self.sink.play();
-                                    let (left, _) = song.name.rsplit_once('.').unwrap();
-                                    self.playing = left.to_string();
+                                    self.queue = vec![song.clone()];
+                                    self.queue_generation += 1;
                                 } else if self.sink.is_paused() {
                                     self.sink.play();
                                 } else {
                                     self.sink.pause();
                                 }
                             }
+                            if button(ui, &GLOBAL_BUTTON_STYLE, "Remove", egui::Vec2::new(75.0, 30.0)).clicked() {
+                                to_remove = Some(song.name.clone());
+                            }
+                            if button(ui, &GLOBAL_BUTTON_STYLE, "Tags", egui::Vec2::new(50.0, 30.0)).clicked() {
+                                self.tag_form = Some(tag_editor::TagForm::new(std::slice::from_ref(song)));
+                            }
+                            if button(ui, &GLOBAL_BUTTON_STYLE, "#", egui::Vec2::new(30.0, 30.0)).on_hover_text("User tags").clicked() {
+                                self.user_tag_form = Some(user_tags::UserTagForm::new(vec![song.name.clone()]));
+                            }
                         });
                     }
                 });
+                if let Some((song, stars)) = to_rate {
+                    if let Err(e) = self.library.set_rating(&song, stars, self.write_rating_tags) {
+                        println!("Failed to rate song: {}", e);
+                    }
+                    self.refresh_songs();
+                }
+                if let Some((song, loved)) = to_love {
+                    if let Err(e) = self.library.set_loved(&song, loved) {
+                        println!("Failed to love song: {}", e);
+                    }
+                    self.refresh_songs();
+                }
+                if let (Some(song), Some(set)) = (to_remove, &self.editing_this_set) {
+                    if let Err(e) = self.library.remove_songs(set, vec![song]) {
+                        println!("Failed to remove song: {}", e);
+                    }
+                    self.refresh_songs();
+                }
             });
 
             return;
         }
 
+        let mut names = self.library.sets.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        let tile_covers = names
+            .into_iter()
+            .map(|name| {
+                let covers = self.set_covers(&name).iter().filter_map(|p| self.cover_textures.get(ctx, p)).collect::<Vec<egui::TextureHandle>>();
+                (name, covers)
+            }).collect::<HashMap<String, Vec<egui::TextureHandle>>>();
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.label(egui::RichText::new("Music").color(Color32::from_rgb(200, 50, 180)).size(20.0));
             ui.add_space(10.0);
@@ -234,6 +1293,8 @@ impl eframe::App for MyEguiApp {
 
             let vecified: Vec<(&String, &playset::Playset)> = sets.iter().collect();
 
+            let mut to_open = None;
+            let mut to_duplicate = None;
             let mut i = 0;
             while i < sets_len {
                 ui.horizontal(|ui| {
@@ -246,40 +1307,71 @@ impl eframe::App for MyEguiApp {
                         let (name, playset) = vecified[index];
                         ui.group(|ui| {
                             ui.vertical(|ui| {
+                                covers::mosaic(ui, tile_covers.get(name).map(|c| c.as_slice()).unwrap_or_default(), 64.0);
                                 ui.label(name);
                             });
                             ui.separator();
 
                             if button(ui, &GLOBAL_BUTTON_STYLE, "Open", egui::Vec2::new(50.0, 30.0)).clicked() {
-                                self.songs_to_show = playset.songs.borrow().flatten(&self.library.sets);
-                                self.editing_this_set = Some(RefCell::clone(&playset.songs));
-                                self.show_songs = true;
+                                to_open = Some(name.clone());
                             }
+                            ui.horizontal(|ui| {
+                                if button(ui, &GLOBAL_BUTTON_STYLE, "Rename", egui::Vec2::new(60.0, 20.0)).clicked() {
+                                    self.rename_buffer = name.clone();
+                                    self.renaming_set = Some(name.clone());
+                                }
+                                if button(ui, &GLOBAL_BUTTON_STYLE, "Duplicate", egui::Vec2::new(70.0, 20.0)).clicked() {
+                                    to_duplicate = Some(name.clone());
+                                }
+                                if button(ui, &GLOBAL_BUTTON_STYLE, "Delete", egui::Vec2::new(60.0, 20.0)).clicked() {
+                                    self.deleting_set = Some(name.clone());
+                                }
+                            });
                         });
                     }
                 });
                 i += items_per_row;
             }
-        });
-
-        egui::TopBottomPanel::bottom("player").show(ctx, |ui| {
-            ui.horizontal(|ui| {
-                ui.label(&self.playing);
 
-                let text = if self.sink.is_paused() { "Play" } else { "Pause" };
-                if button(ui, &GLOBAL_BUTTON_STYLE, text, egui::Vec2::new(100.0, 30.0)).clicked() {
-                    if self.sink.is_paused() {
-                        self.sink.play();
-                    } else {
-                        self.sink.pause();
-                    }
+            if let Some(name) = to_open {
+                self.open_set(name);
+            }
+            if let Some(name) = to_duplicate {
+                let copy = self.library.free_name(&format!("{} copy", name));
+                if let Err(e) = self.library.duplicate_set(&name, copy) {
+                    println!("Failed to duplicate set: {}", e);
                 }
-            });
+            }
         });
    }
+
+   fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.save_session(0.0);
+        if let Some(playing) = &mut self.now_playing {
+            playing.position = self.sink.get_pos().as_secs();
+        }
+        self.end_now_playing();
+   }
 }
 
-pub fn run() {
-    let native_options = eframe::NativeOptions::default();
-    let _ = eframe::run_native("Play set", native_options, Box::new(|cc| Ok(Box::new(MyEguiApp::new(cc)))));
+/// Loads the config file, lets `patch` change the part of it the caller owns and writes it back.
+/// Saving the running config instead would write command line overrides into the file.
+fn save_config_field(path: &Path, patch: impl FnOnce(&mut Config)) {
+    match Config::load(path) {
+        Ok(mut file) => {
+            patch(&mut file);
+            if let Err(e) = file.save(path) {
+                println!("Failed to save {}: {}", path.display(), e);
+            }
+        },
+        Err(e) => println!("Failed to read {}: {}", path.display(), e),
+    }
+}
+
+pub fn run(config: Config, config_path: PathBuf) {
+    let mut native_options = eframe::NativeOptions::default();
+    if let Some([x, y, width, height]) = Session::load(config.session_path()).window.filter(|_| config.autosave.session) {
+        native_options.viewport = native_options.viewport.with_position([x, y]).with_inner_size([width, height]);
+    }
+    let _ = eframe::run_native("Play set", native_options, Box::new(|cc| Ok(Box::new(MyEguiApp::new(cc, config, config_path)))));
 }
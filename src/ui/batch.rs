@@ -0,0 +1,45 @@
+use std::{io, path::{Path, PathBuf}, sync::mpsc, thread, time::Duration};
+use eframe::egui;
+
+/// Work over many song files on a background thread, one song at a time.
+/// Results are held back until every song is done so the library takes them in with one save.
+pub struct Batch<T> {
+    label: &'static str,
+    total: usize,
+    results: Vec<(String, io::Result<T>)>,
+    done: mpsc::Receiver<(String, io::Result<T>)>,
+}
+
+impl<T: Send + 'static> Batch<T> {
+    /// Runs `work` on the file of every song in `songs`, given as name and path
+    pub fn start(label: &'static str, songs: Vec<(String, PathBuf)>, work: impl Fn(&Path) -> io::Result<T> + Send + 'static) -> Self {
+        let total = songs.len();
+        let (tx, done) = mpsc::channel();
+        thread::spawn(move || {
+            for (name, path) in songs {
+                let result = work(&path);
+                if tx.send((name, result)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { label, total, results: vec![], done }
+    }
+
+    /// Takes in what the worker finished, returns the result of every song once all are done
+    pub fn poll(&mut self) -> Option<Vec<(String, io::Result<T>)>> {
+        loop {
+            match self.done.try_recv() {
+                Ok(result) => self.results.push(result),
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => return Some(std::mem::take(&mut self.results)),
+            }
+        }
+    }
+
+    pub fn show(&self, ui: &mut egui::Ui) {
+        ui.add(egui::Spinner::new());
+        ui.label(format!("{} {} / {}", self.label, self.results.len(), self.total));
+        ui.ctx().request_repaint_after(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,193 @@
+use eframe::egui;
+use crate::playset::{Library, Song, SongTree};
+use crate::playset::browse::{self, Album, Artist, SetKind};
+use super::{button, song_details, GLOBAL_BUTTON_STYLE};
+
+/// Artists → albums → tracks, rebuilt whenever the library changes
+pub struct Browser {
+    artists: Vec<Artist>,
+    revision: u64,
+    artist: Option<String>,
+    album: Option<String>,
+    filter: String,
+}
+
+pub enum BrowseAction {
+    Play(Vec<Song>),
+    CreateSet(String, SongTree),
+    Close,
+}
+
+fn set_name(library: &Library, base: &str) -> String {
+    library.free_name(&base.replace(['/', '\\'], "-"))
+}
+
+fn minutes(seconds: u64) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// The buttons that turn an album or artist into a playset
+fn convert_buttons(ui: &mut egui::Ui, library: &Library, name: &str, tree: impl Fn(SetKind) -> SongTree, action: &mut Option<BrowseAction>) {
+    if button(ui, &GLOBAL_BUTTON_STYLE, "Save as set", egui::Vec2::new(85.0, 20.0))
+        .on_hover_text("A set with the songs it has now")
+        .clicked()
+    {
+        *action = Some(BrowseAction::CreateSet(set_name(library, name), tree(SetKind::Terminal)));
+    }
+    if button(ui, &GLOBAL_BUTTON_STYLE, "Save as rule", egui::Vec2::new(85.0, 20.0))
+        .on_hover_text("A set that also picks up songs added later")
+        .clicked()
+    {
+        *action = Some(BrowseAction::CreateSet(set_name(library, name), tree(SetKind::Rule)));
+    }
+}
+
+impl Browser {
+    pub fn new(library: &Library) -> Self {
+        Self {
+            artists: browse::artists(library),
+            revision: library.revision,
+            artist: None,
+            album: None,
+            filter: String::new(),
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, library: &Library) -> Option<BrowseAction> {
+        if self.revision != library.revision {
+            self.artists = browse::artists(library);
+            self.revision = library.revision;
+        }
+
+        let mut action = None;
+        ui.horizontal(|ui| {
+            let back = if self.artist.is_some() { "Back" } else { "Back to Play set's" };
+            if button(ui, &GLOBAL_BUTTON_STYLE, back, egui::Vec2::new(110.0, 30.0)).clicked() {
+                if self.album.is_some() {
+                    self.album = None;
+                } else if self.artist.is_some() {
+                    self.artist = None;
+                } else {
+                    action = Some(BrowseAction::Close);
+                }
+            }
+            if self.artist.is_none() {
+                ui.label("Filter");
+                ui.text_edit_singleline(&mut self.filter);
+            }
+        });
+        ui.add_space(10.0);
+
+        let artist = self.artist.as_ref().and_then(|name| self.artists.iter().find(|a| &a.name == name));
+        let album = artist.zip(self.album.as_ref()).and_then(|(artist, title)| artist.albums.iter().find(|a| &a.title == title));
+        // Whatever was open disappeared from the library, e.g. after retagging its songs
+        if self.artist.is_some() && artist.is_none() || self.album.is_some() && album.is_none() {
+            self.artist = artist.map(|a| a.name.clone());
+            self.album = None;
+            return action;
+        }
+
+        let (open_artist, open_album) = egui::ScrollArea::vertical().show(ui, |ui| {
+            match (artist, album) {
+                (Some(_), Some(album)) => {
+                    Self::tracks(ui, library, album, &mut action);
+                    (None, None)
+                },
+                (Some(artist), None) => (None, Self::albums(ui, library, artist, &mut action)),
+                _ => (self.artist_list(ui, library, &mut action), None),
+            }
+        }).inner;
+        if open_artist.is_some() {
+            self.artist = open_artist;
+        }
+        if open_album.is_some() {
+            self.album = open_album;
+        }
+        action
+    }
+
+    fn artist_list(&self, ui: &mut egui::Ui, library: &Library, action: &mut Option<BrowseAction>) -> Option<String> {
+        let filter = self.filter.to_lowercase();
+        let mut open = None;
+        for artist in self.artists.iter().filter(|a| a.name.to_lowercase().contains(&filter)) {
+            ui.group(|ui| {
+                ui.horizontal(|ui| {
+                    ui.vertical(|ui| {
+                        ui.label(&artist.name);
+                        let songs = artist.albums.iter().map(|a| a.songs.len()).sum::<usize>();
+                        ui.label(egui::RichText::new(format!("{} albums · {} songs", artist.albums.len(), songs)).small());
+                    });
+                    ui.separator();
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Open", egui::Vec2::new(50.0, 20.0)).clicked() {
+                        open = Some(artist.name.clone());
+                    }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Play", egui::Vec2::new(50.0, 20.0)).clicked() {
+                        *action = Some(BrowseAction::Play(artist.songs()));
+                    }
+                    convert_buttons(ui, library, &artist.name, |kind| artist.to_tree(kind), action);
+                });
+            });
+        }
+        open
+    }
+
+    fn albums(ui: &mut egui::Ui, library: &Library, artist: &Artist, action: &mut Option<BrowseAction>) -> Option<String> {
+        ui.label(egui::RichText::new(&artist.name).size(20.0));
+        ui.horizontal(|ui| convert_buttons(ui, library, &artist.name, |kind| artist.to_tree(kind), action));
+        ui.add_space(10.0);
+
+        let mut open = None;
+        for album in &artist.albums {
+            ui.group(|ui| {
+                ui.horizontal(|ui| {
+                    ui.vertical(|ui| {
+                        ui.label(&album.title);
+                        let year = album.year.map(|y| format!("{} · ", y)).unwrap_or_default();
+                        ui.label(egui::RichText::new(format!("{}{} songs · {}", year, album.songs.len(), minutes(album.duration()))).small());
+                    });
+                    ui.separator();
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Open", egui::Vec2::new(50.0, 20.0)).clicked() {
+                        open = Some(album.title.clone());
+                    }
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Play", egui::Vec2::new(50.0, 20.0)).clicked() {
+                        *action = Some(BrowseAction::Play(album.songs.clone()));
+                    }
+                    convert_buttons(ui, library, &format!("{} - {}", artist.name, album.title), |kind| album.to_tree(kind), action);
+                });
+            });
+        }
+        open
+    }
+
+    fn tracks(ui: &mut egui::Ui, library: &Library, album: &Album, action: &mut Option<BrowseAction>) {
+        ui.label(egui::RichText::new(&album.title).size(20.0));
+        ui.label(&album.artist);
+        ui.horizontal(|ui| {
+            if button(ui, &GLOBAL_BUTTON_STYLE, "Play all", egui::Vec2::new(70.0, 20.0)).clicked() {
+                *action = Some(BrowseAction::Play(album.songs.clone()));
+            }
+            convert_buttons(ui, library, &format!("{} - {}", album.artist, album.title), |kind| album.to_tree(kind), action);
+        });
+        ui.add_space(10.0);
+
+        let discs = album.songs.iter().filter_map(|s| s.disc_number).max().unwrap_or(1);
+        let mut disc = None;
+        for (i, song) in album.songs.iter().enumerate() {
+            if discs > 1 && disc != Some(song.disc_number) {
+                disc = Some(song.disc_number);
+                ui.label(egui::RichText::new(song.disc_number.map(|d| format!("Disc {}", d)).unwrap_or_else(|| String::from("No disc"))).strong());
+            }
+            ui.horizontal(|ui| {
+                let number = song.track_number.map(|t| t.to_string()).unwrap_or_default();
+                ui.label(format!("{:>3}", number));
+                ui.vertical(|ui| {
+                    ui.label(song.display_title());
+                    ui.label(egui::RichText::new(song_details(song)).small());
+                });
+                if button(ui, &GLOBAL_BUTTON_STYLE, "Play", egui::Vec2::new(50.0, 20.0)).clicked() {
+                    *action = Some(BrowseAction::Play(album.songs[i..].to_vec()));
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use eframe::egui;
+use egui::Color32;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Cover images uploaded to the GPU, keyed by their file in the cover cache
+#[derive(Default)]
+pub struct CoverTextures {
+    textures: HashMap<PathBuf, Option<egui::TextureHandle>>,
+}
+
+impl CoverTextures {
+    pub fn get(&mut self, ctx: &egui::Context, path: &Path) -> Option<egui::TextureHandle> {
+        self.textures.entry(path.to_path_buf()).or_insert_with(|| {
+            let image = image::open(path).ok()?.to_rgba8();
+            let size = [image.width() as usize, image.height() as usize];
+            let image = egui::ColorImage::from_rgba_unmultiplied(size, image.as_flat_samples().as_slice());
+            Some(ctx.load_texture(path.to_string_lossy(), image, egui::TextureOptions::LINEAR))
+        }).clone()
+    }
+}
+
+/// Draws one cover filling a `size` square, or up to four of them as a 2x2 grid
+pub fn mosaic(ui: &mut egui::Ui, covers: &[egui::TextureHandle], size: f32) -> egui::Response {
+    let (rect, response) = ui.allocate_exact_size(egui::Vec2::splat(size), egui::Sense::hover());
+    let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+
+    match covers {
+        [] => {
+            ui.painter().rect_filled(rect, 3.0, Color32::from_gray(60));
+            ui.painter().text(rect.center(), egui::Align2::CENTER_CENTER, "♪", egui::FontId::proportional(size / 2.0), Color32::from_gray(140));
+        },
+        [cover] => {
+            ui.painter().image(cover.id(), rect, uv, Color32::WHITE);
+        },
+        covers => {
+            let cell = size / 2.0;
+            for (i, cover) in covers.iter().take(4).enumerate() {
+                let min = rect.min + egui::vec2((i % 2) as f32 * cell, (i / 2) as f32 * cell);
+                ui.painter().image(cover.id(), egui::Rect::from_min_size(min, egui::Vec2::splat(cell)), uv, Color32::WHITE);
+            }
+        },
+    }
+    response
+}
@@ -0,0 +1,86 @@
+use eframe::egui;
+use std::collections::HashMap;
+use crate::playset::{Library, Song};
+use crate::playset::duplicates;
+use super::{button, song_details, GLOBAL_BUTTON_STYLE};
+
+/// Lists duplicate candidates side by side so one copy of each can be kept
+#[derive(Default)]
+pub struct DuplicateView {
+    groups: Vec<Vec<Song>>,
+    /// Which song of each group is kept, by group index
+    keep: HashMap<usize, String>,
+    by_fingerprint: bool,
+    revision: Option<u64>,
+}
+
+pub enum DuplicateAction {
+    Merge { keep: String, others: Vec<String> },
+    /// Audio comparison was switched on and needs the fingerprints of every song
+    Fingerprint,
+    Close,
+}
+
+impl DuplicateView {
+    fn search(&mut self, library: &Library) {
+        self.groups = duplicates::find(library, self.by_fingerprint);
+        // Suggest the best encoded copy
+        self.keep = self.groups.iter().enumerate().map(|(i, group)| {
+            let best = group.iter().max_by_key(|s| (s.bitrate, s.file_size)).unwrap();
+            (i, best.name.clone())
+        }).collect();
+        self.revision = Some(library.revision);
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, library: &Library) -> Option<DuplicateAction> {
+        if self.revision != Some(library.revision) {
+            self.search(library);
+        }
+
+        let mut action = None;
+        ui.horizontal(|ui| {
+            if button(ui, &GLOBAL_BUTTON_STYLE, "Back to Play set's", egui::Vec2::new(110.0, 30.0)).clicked() {
+                action = Some(DuplicateAction::Close);
+            }
+            if ui.checkbox(&mut self.by_fingerprint, "Compare audio").on_hover_text("Also finds copies with different tags, reads every file the first time").changed() {
+                if self.by_fingerprint {
+                    action = Some(DuplicateAction::Fingerprint);
+                }
+                self.search(library);
+            }
+        });
+        ui.label(format!("{} groups of duplicates. Merging keeps the selected copy in every set and moves the others to a duplicates folder next to the songs.", self.groups.len()));
+        ui.add_space(10.0);
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for (i, group) in self.groups.iter().enumerate() {
+                ui.group(|ui| {
+                    ui.horizontal_top(|ui| {
+                        for song in group {
+                            ui.vertical(|ui| {
+                                ui.set_width(220.0);
+                                let keep = self.keep.get(&i) == Some(&song.name);
+                                if ui.radio(keep, "Keep").clicked() {
+                                    self.keep.insert(i, song.name.clone());
+                                }
+                                ui.label(egui::RichText::new(&song.name).strong());
+                                ui.label(song.display_title());
+                                ui.label(&song.artist);
+                                ui.label(&song.album);
+                                ui.label(egui::RichText::new(song_details(song)).small());
+                            });
+                            ui.separator();
+                        }
+                        if let Some(keep) = self.keep.get(&i)
+                            && button(ui, &GLOBAL_BUTTON_STYLE, "Merge", egui::Vec2::new(60.0, 30.0)).clicked()
+                        {
+                            let others = group.iter().filter(|s| &s.name != keep).map(|s| s.name.clone()).collect();
+                            action = Some(DuplicateAction::Merge { keep: keep.clone(), others });
+                        }
+                    });
+                });
+            }
+        });
+        action
+    }
+}
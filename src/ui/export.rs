@@ -0,0 +1,137 @@
+use std::{path::PathBuf, sync::mpsc, thread, time::Duration};
+use eframe::egui;
+use crate::playset::Song;
+use crate::playset::export::{self, ExportOptions, ExportReport, Format};
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+enum Progress {
+    Song(usize, String),
+    Finished(Result<ExportReport, String>),
+}
+
+/// Copies or transcodes a set into a folder on a background thread
+pub struct ExportForm {
+    pub set: String,
+    songs: Vec<Song>,
+    songs_dir: PathBuf,
+    pub options: ExportOptions,
+    done: usize,
+    current: String,
+    running: Option<mpsc::Receiver<Progress>>,
+    message: Option<String>,
+}
+
+impl ExportForm {
+    /// `options` are the ones used last
+    pub fn new(set: String, songs: Vec<Song>, songs_dir: PathBuf, options: ExportOptions) -> Self {
+        Self { set, songs, songs_dir, options, done: 0, current: String::new(), running: None, message: None }
+    }
+
+    fn start(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        let songs = self.songs.clone();
+        let songs_dir = self.songs_dir.clone();
+        let options = self.options.clone();
+        let set = self.set.clone();
+        thread::spawn(move || {
+            let progress = tx.clone();
+            let result = export::export(&set, &songs, &songs_dir, &options, |done, current| {
+                let _ = progress.send(Progress::Song(done, current.to_owned()));
+            });
+            let _ = tx.send(Progress::Finished(result.map_err(|e| e.to_string())));
+        });
+        self.done = 0;
+        self.message = None;
+        self.running = Some(rx);
+    }
+
+    fn poll(&mut self) {
+        let Some(rx) = &self.running else {
+            return;
+        };
+        for progress in rx.try_iter() {
+            match progress {
+                Progress::Song(done, current) => {
+                    self.done = done;
+                    self.current = current;
+                },
+                Progress::Finished(Ok(report)) => {
+                    let mut message = format!("{} copied, {} transcoded, {} up to date", report.copied, report.transcoded, report.skipped);
+                    for (song, e) in report.failed {
+                        message.push_str(&format!("\n{} failed: {}", song, e));
+                    }
+                    self.message = Some(message);
+                },
+                Progress::Finished(Err(e)) => self.message = Some(format!("Failed: {}", e)),
+            }
+        }
+        if self.message.is_some() {
+            self.running = None;
+        }
+    }
+}
+
+/// Returns true when the window should close
+pub fn show(ui: &mut egui::Ui, form: &mut ExportForm) -> bool {
+    form.poll();
+    ui.label(format!("{} songs from {}", form.songs.len(), form.set));
+
+    ui.add_enabled_ui(form.running.is_none(), |ui| {
+        egui::Grid::new("export_options").num_columns(2).show(ui, |ui| {
+            ui.label("Folder");
+            ui.horizontal(|ui| {
+                let target = if form.options.target.as_os_str().is_empty() { String::from("None chosen") } else { form.options.target.display().to_string() };
+                ui.label(target);
+                if button(ui, &GLOBAL_BUTTON_STYLE, "Choose…", egui::Vec2::new(70.0, 20.0)).clicked()
+                    && let Some(dir) = rfd::FileDialog::new().pick_folder()
+                {
+                    form.options.target = dir;
+                }
+            });
+            ui.end_row();
+
+            ui.label("File names");
+            ui.text_edit_singleline(&mut form.options.template)
+                .on_hover_text("{artist} {album_artist} {album} {title} {track} {disc} {year} {genre} {name}, / makes folders");
+            ui.end_row();
+
+            ui.label("Format");
+            ui.horizontal(|ui| {
+                for format in Format::ALL {
+                    ui.selectable_value(&mut form.options.format, format, format.label());
+                }
+            });
+            ui.end_row();
+
+            if form.options.format != Format::Copy {
+                ui.label("Bitrate");
+                ui.add(egui::Slider::new(&mut form.options.bitrate, 64..=320).suffix(" kbps"));
+                ui.end_row();
+            }
+        });
+        ui.checkbox(&mut form.options.m3u, "Write an M3U playlist");
+        if let Some(song) = form.songs.first() {
+            ui.label(egui::RichText::new(format!("e.g. {}", export::target_path(&form.options.template, song, form.options.format))).small());
+        }
+    });
+
+    if form.running.is_some() {
+        ui.add(egui::ProgressBar::new(form.done as f32 / form.songs.len().max(1) as f32).text(format!("{} / {} {}", form.done, form.songs.len(), form.current)));
+        ui.ctx().request_repaint_after(Duration::from_millis(200));
+    } else if let Some(message) = &form.message {
+        ui.label(message);
+    }
+
+    let mut close = false;
+    ui.horizontal(|ui| {
+        let ready = form.running.is_none() && !form.options.target.as_os_str().is_empty();
+        if ready && button(ui, &GLOBAL_BUTTON_STYLE, "Export", egui::Vec2::new(70.0, 30.0)).clicked() {
+            form.start();
+        }
+        // Exporting carries on in the background if closed early
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Close", egui::Vec2::new(60.0, 30.0)).clicked() {
+            close = true;
+        }
+    });
+    close
+}
@@ -0,0 +1,136 @@
+use std::{path::PathBuf, sync::mpsc, thread, time::Duration};
+use eframe::egui;
+use crate::playset::import::{self, ImportOptions, ImportReport, ImportSource};
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+/// Downloads or copies media into the library on a background thread
+pub struct ImportForm {
+    pub options: ImportOptions,
+    songs_dir: PathBuf,
+    from_folder: bool,
+    url: String,
+    folder: Option<PathBuf>,
+    /// Set the imported songs are added to
+    add_to: Option<String>,
+    running: Option<mpsc::Receiver<Result<ImportReport, String>>>,
+    message: Option<String>,
+}
+
+pub enum ImportAction {
+    /// Files now in the songs directory, for the library to read and optionally add to a set
+    Imported(Vec<String>, Option<String>),
+    Close,
+}
+
+impl ImportForm {
+    pub fn new(options: ImportOptions, songs_dir: PathBuf) -> Self {
+        Self { options, songs_dir, from_folder: false, url: String::new(), folder: None, add_to: None, running: None, message: None }
+    }
+
+    fn start(&mut self) {
+        let source = if self.from_folder {
+            ImportSource::Folder(self.folder.clone().unwrap_or_default())
+        } else {
+            ImportSource::Url(self.url.trim().to_owned())
+        };
+        let (tx, rx) = mpsc::channel();
+        let songs_dir = self.songs_dir.clone();
+        let options = self.options.clone();
+        thread::spawn(move || {
+            let _ = tx.send(import::import(&source, &songs_dir, &options).map_err(|e| e.to_string()));
+        });
+        self.message = None;
+        self.running = Some(rx);
+    }
+
+    fn poll(&mut self) -> Option<ImportAction> {
+        let result = self.running.as_ref()?.try_recv().ok()?;
+        self.running = None;
+        match result {
+            Ok(report) => {
+                let mut message = format!("Imported {} files", report.imported.len());
+                for file in &report.imported {
+                    if let Some(warning) = &file.warning {
+                        message.push_str(&format!("\n{}: {}", file.name, warning));
+                    }
+                }
+                for (file, e) in &report.failed {
+                    message.push_str(&format!("\n{} failed: {}", file, e));
+                }
+                self.message = Some(message);
+                if report.failed.is_empty() {
+                    self.url.clear();
+                }
+                Some(ImportAction::Imported(report.imported.into_iter().map(|i| i.name).collect(), self.add_to.clone()))
+            },
+            Err(e) => {
+                self.message = Some(format!("Failed: {}", e));
+                None
+            },
+        }
+    }
+}
+
+pub fn show(ui: &mut egui::Ui, form: &mut ImportForm, sets: &[String]) -> Option<ImportAction> {
+    let mut action = form.poll();
+    let running = form.running.is_some();
+
+    ui.add_enabled_ui(!running, |ui| {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut form.from_folder, false, "Download");
+            ui.selectable_value(&mut form.from_folder, true, "From a folder");
+        });
+        if form.from_folder {
+            ui.horizontal(|ui| {
+                ui.label(form.folder.as_ref().map(|f| f.display().to_string()).unwrap_or_else(|| String::from("None chosen")));
+                if button(ui, &GLOBAL_BUTTON_STYLE, "Choose…", egui::Vec2::new(70.0, 20.0)).clicked()
+                    && let Some(dir) = rfd::FileDialog::new().pick_folder()
+                {
+                    form.folder = Some(dir);
+                }
+            });
+            ui.label(egui::RichText::new("Audio files are copied and videos have their audio taken out, tags come from .info.json files next to them").small());
+        } else {
+            ui.add(egui::TextEdit::singleline(&mut form.url).hint_text("URL of a video, song or playlist").desired_width(350.0));
+            ui.horizontal(|ui| {
+                ui.label("Downloader");
+                let mut downloader = form.options.downloader.display().to_string();
+                if ui.text_edit_singleline(&mut downloader).changed() {
+                    form.options.downloader = PathBuf::from(downloader);
+                }
+            });
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Add to set");
+            egui::ComboBox::from_id_salt("import_set").selected_text(form.add_to.clone().unwrap_or_else(|| String::from("None"))).show_ui(ui, |ui| {
+                ui.selectable_value(&mut form.add_to, None, "None");
+                for set in sets {
+                    ui.selectable_value(&mut form.add_to, Some(set.clone()), set);
+                }
+            });
+        });
+    });
+
+    if running {
+        ui.horizontal(|ui| {
+            ui.add(egui::Spinner::new());
+            ui.label(if form.from_folder { "Copying…" } else { "Downloading…" });
+        });
+        ui.ctx().request_repaint_after(Duration::from_millis(200));
+    } else if let Some(message) = &form.message {
+        ui.label(message);
+    }
+
+    ui.horizontal(|ui| {
+        let ready = !running && if form.from_folder { form.folder.is_some() } else { !form.url.trim().is_empty() };
+        if ready && button(ui, &GLOBAL_BUTTON_STYLE, "Import", egui::Vec2::new(70.0, 30.0)).clicked() {
+            form.start();
+        }
+        // Importing finishes in the background if closed early, but the library only picks the files up on the next start
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Close", egui::Vec2::new(60.0, 30.0)).clicked() {
+            action = Some(ImportAction::Close);
+        }
+    });
+    action
+}
@@ -0,0 +1,97 @@
+use std::{path::PathBuf, sync::mpsc, thread, time::Duration};
+use eframe::egui;
+use crate::music_player;
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+enum Progress {
+    Songs(usize),
+    Finished(Result<(), String>),
+}
+
+/// Mixes a set into one WAV or FLAC file on a background thread
+pub struct RenderForm {
+    pub set: String,
+    songs: Vec<PathBuf>,
+    crossfade: f32,
+    flac: bool,
+    /// Encodes FLAC, the program set in the export options
+    ffmpeg: PathBuf,
+    done: usize,
+    running: Option<mpsc::Receiver<Progress>>,
+    message: Option<String>,
+}
+
+impl RenderForm {
+    pub fn new(set: String, songs: Vec<PathBuf>, ffmpeg: PathBuf) -> Self {
+        Self { set, songs, crossfade: 3.0, flac: false, ffmpeg, done: 0, running: None, message: None }
+    }
+
+    fn start(&mut self, out: PathBuf) {
+        let (tx, rx) = mpsc::channel();
+        let songs = self.songs.clone();
+        let crossfade = Duration::from_secs_f32(self.crossfade);
+        let ffmpeg = self.ffmpeg.clone();
+        thread::spawn(move || {
+            let progress = tx.clone();
+            let result = music_player::render_to_file(&songs, crossfade, &out, &ffmpeg, |done| {
+                let _ = progress.send(Progress::Songs(done));
+            });
+            let _ = tx.send(Progress::Finished(result.map_err(|e| e.to_string())));
+        });
+        self.done = 0;
+        self.message = None;
+        self.running = Some(rx);
+    }
+
+    fn poll(&mut self) {
+        let Some(rx) = &self.running else {
+            return;
+        };
+        for progress in rx.try_iter() {
+            match progress {
+                Progress::Songs(done) => self.done = done,
+                Progress::Finished(Ok(())) => self.message = Some(String::from("Done")),
+                Progress::Finished(Err(e)) => self.message = Some(format!("Failed: {}", e)),
+            }
+        }
+        if self.message.is_some() {
+            self.running = None;
+        }
+    }
+}
+
+/// Returns true when the window should close
+pub fn show(ui: &mut egui::Ui, form: &mut RenderForm) -> bool {
+    form.poll();
+    ui.label(format!("{} songs from {}", form.songs.len(), form.set));
+    ui.add_enabled(form.running.is_none(), egui::Slider::new(&mut form.crossfade, 0.0..=12.0).text("seconds of crossfade"));
+    ui.add_enabled_ui(form.running.is_none(), |ui| {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut form.flac, false, "WAV");
+            ui.selectable_value(&mut form.flac, true, "FLAC").on_hover_text("Encoded with the ffmpeg set in the export options");
+        });
+    });
+
+    if form.running.is_some() {
+        ui.add(egui::ProgressBar::new(form.done as f32 / form.songs.len().max(1) as f32).text(format!("{} / {}", form.done, form.songs.len())));
+        ui.ctx().request_repaint_after(Duration::from_millis(200));
+    } else if let Some(message) = &form.message {
+        ui.label(message);
+    }
+
+    let mut close = false;
+    ui.horizontal(|ui| {
+        let (filter, extension) = if form.flac { ("FLAC", "flac") } else { ("WAV", "wav") };
+        if form.running.is_none()
+            && button(ui, &GLOBAL_BUTTON_STYLE, "Render…", egui::Vec2::new(70.0, 30.0)).clicked()
+            && let Some(out) = rfd::FileDialog::new().add_filter(filter, &[extension]).set_file_name(format!("{}.{}", form.set, extension)).save_file()
+        {
+            form.start(out);
+        }
+        // Rendering carries on in the background if closed early
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Close", egui::Vec2::new(60.0, 30.0)).clicked() {
+            close = true;
+        }
+    });
+    close
+}
@@ -0,0 +1,142 @@
+use std::{fs, io, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Repeat {
+    #[default]
+    Off,
+    /// Starts the queue over when it runs out
+    All,
+    /// Plays the current song again instead of moving on
+    One,
+}
+
+impl Repeat {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Repeat::Off => "Repeat off",
+            Repeat::All => "Repeat all",
+            Repeat::One => "Repeat one",
+        }
+    }
+
+    pub fn next(&self) -> Repeat {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+/// What was playing and how the window looked when the app last closed, kept in `session.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Song names in play order
+    #[serde(default)]
+    pub queue: Vec<String>,
+    /// Index into `queue` of the song that was playing
+    #[serde(default)]
+    pub track: usize,
+    /// Seconds into that song
+    #[serde(default)]
+    pub position: f32,
+    #[serde(default = "full_volume")]
+    pub volume: f32,
+    #[serde(default)]
+    pub shuffle: bool,
+    #[serde(default)]
+    pub repeat: Repeat,
+    #[serde(default)]
+    pub open_set: Option<String>,
+    #[serde(default)]
+    pub show_spectrum: bool,
+    #[serde(default)]
+    pub show_stats: bool,
+    /// Outer position and inner size of the window, in points
+    #[serde(default)]
+    pub window: Option<[f32; 4]>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            queue: vec![],
+            track: 0,
+            position: 0.0,
+            volume: full_volume(),
+            shuffle: false,
+            repeat: Repeat::Off,
+            open_set: None,
+            show_spectrum: false,
+            show_stats: false,
+            window: None,
+        }
+    }
+}
+
+impl Session {
+    /// A missing or unreadable file starts a fresh session
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                eprintln!("Ignoring saved session: {}", e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Written beside and renamed over, so a crash mid write keeps the old session
+        let tmp = PathBuf::from(format!("{}.tmp", path.as_ref().display()));
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_sessions_round_trip() {
+        let path = std::env::temp_dir().join(format!("playset-session-{}.json", std::process::id()));
+        let session = Session {
+            queue: vec![String::from("a.mp3"), String::from("b.mp3")],
+            track: 1,
+            position: 42.5,
+            volume: 0.3,
+            shuffle: true,
+            repeat: Repeat::One,
+            open_set: Some(String::from("Mix")),
+            show_spectrum: true,
+            show_stats: false,
+            window: Some([10.0, 20.0, 800.0, 600.0]),
+        };
+        session.save(&path).unwrap();
+        let loaded = Session::load(&path);
+        assert_eq!((loaded.queue, loaded.track, loaded.position, loaded.volume), (session.queue, 1, 42.5, 0.3));
+        assert_eq!((loaded.shuffle, loaded.repeat, loaded.open_set.as_deref()), (true, Repeat::One, Some("Mix")));
+        assert_eq!((loaded.show_spectrum, loaded.show_stats, loaded.window), (true, false, session.window));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_fields_take_their_defaults() {
+        let session: Session = serde_json::from_str(r#"{"queue": ["a.mp3"], "repeat": "All"}"#).unwrap();
+        assert_eq!((session.queue.len(), session.track, session.position), (1, 0, 0.0));
+        assert_eq!((session.volume, session.repeat, session.window), (1.0, Repeat::All, None));
+
+        let path = std::env::temp_dir().join(format!("playset-session-bad-{}.json", std::process::id()));
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(Session::load(&path).volume, 1.0);
+        fs::remove_file(&path).unwrap();
+        assert!(Session::load(&path).queue.is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use std::path::PathBuf;
+use eframe::egui;
+use crate::config::{AudioConfig, Config, Theme};
+use crate::music_player::Backend;
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+/// Edits the config file, separate from the running config so command line overrides aren't saved
+pub struct SettingsForm {
+    pub draft: Config,
+    pub path: PathBuf,
+    songs: String,
+    subsets: String,
+    /// Output devices found when the form opened
+    devices: Vec<String>,
+    extensions: String,
+    /// Recording file, kept while another output is selected
+    wav: String,
+    /// The audio section as the file had it when the form opened
+    saved_audio: AudioConfig,
+}
+
+pub enum SettingsAction {
+    Save,
+    Cancel,
+}
+
+fn optional_path(s: &str) -> Option<PathBuf> {
+    Some(s.trim()).filter(|s| !s.is_empty()).map(PathBuf::from)
+}
+
+impl SettingsForm {
+    pub fn new(draft: Config, path: PathBuf, devices: Vec<String>) -> Self {
+        let shown = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+        Self {
+            songs: shown(&draft.library.songs),
+            subsets: shown(&draft.library.subsets),
+            devices,
+            extensions: draft.scanning.extensions.join(", "),
+            wav: match &draft.audio.output {
+                Backend::Wav(path) => path.display().to_string(),
+                _ => String::new(),
+            },
+            saved_audio: draft.audio.clone(),
+            draft,
+            path,
+        }
+    }
+
+    /// Copies what this form edits from `draft` into `file`, anything else in the file may have
+    /// been saved by another window since the form opened
+    pub fn patch(&self, file: &mut Config) {
+        file.library = self.draft.library.clone();
+        file.scanning = self.draft.scanning.clone();
+        file.audio = self.draft.audio.clone();
+        file.autosave = self.draft.autosave.clone();
+        file.theme = self.draft.theme;
+    }
+
+    /// Whether saving changes the output, rather than only rewriting what the file already had
+    pub fn audio_changed(&self) -> bool {
+        self.draft.audio != self.saved_audio
+    }
+
+    /// Moves the text fields into `draft`
+    fn finish(&mut self) {
+        self.draft.library.songs = optional_path(&self.songs);
+        self.draft.library.subsets = optional_path(&self.subsets);
+        self.draft.scanning.extensions = self.extensions.split(',').map(|e| e.trim().trim_start_matches('.').to_lowercase()).filter(|e| !e.is_empty()).collect();
+        if let Backend::Wav(path) = &mut self.draft.audio.output {
+            *path = PathBuf::from(self.wav.trim());
+        }
+    }
+}
+
+pub fn show(ui: &mut egui::Ui, form: &mut SettingsForm, playing_through: &str) -> Option<SettingsAction> {
+    let mut action = None;
+    ui.label(egui::RichText::new(form.path.display().to_string()).small());
+
+    ui.heading("Library");
+    let mut root = form.draft.library.root.display().to_string();
+    egui::Grid::new("settings_library").num_columns(2).show(ui, |ui| {
+        ui.label("Library root");
+        if ui.text_edit_singleline(&mut root).changed() {
+            form.draft.library.root = PathBuf::from(&root);
+        }
+        ui.end_row();
+        ui.label("Songs");
+        ui.add(egui::TextEdit::singleline(&mut form.songs).hint_text(form.draft.library.root.join("U").display().to_string()));
+        ui.end_row();
+        ui.label("Play sets");
+        ui.add(egui::TextEdit::singleline(&mut form.subsets).hint_text(form.draft.library.root.join("subsets").display().to_string()));
+        ui.end_row();
+    });
+    ui.label(egui::RichText::new("Library changes apply after a restart").small());
+    ui.separator();
+
+    ui.heading("Scanning");
+    ui.checkbox(&mut form.draft.scanning.on_startup, "Scan for new and changed songs on startup");
+    ui.checkbox(&mut form.draft.scanning.skip_hidden, "Skip hidden files");
+    ui.horizontal(|ui| {
+        ui.label("Extensions");
+        ui.add(egui::TextEdit::singleline(&mut form.extensions).hint_text("any file, or e.g. mp3, flac"));
+    });
+    ui.separator();
+
+    ui.heading("Playback");
+    ui.horizontal(|ui| {
+        ui.label("Play into");
+        let output = &mut form.draft.audio.output;
+        if ui.selectable_label(*output == Backend::Device, "Sound card").clicked() {
+            *output = Backend::Device;
+        }
+        if ui.selectable_label(*output == Backend::Null, "Nothing").clicked() {
+            *output = Backend::Null;
+        }
+        if ui.selectable_label(matches!(output, Backend::Wav(_)), "WAV file").clicked() {
+            *output = Backend::Wav(PathBuf::from(form.wav.trim()));
+        }
+    });
+    match form.draft.audio.output {
+        Backend::Device => {
+            ui.horizontal(|ui| {
+                ui.label("Output device");
+                let selected = form.draft.audio.device.clone().unwrap_or_else(|| String::from("System default"));
+                egui::ComboBox::from_id_salt("settings_device").selected_text(selected).show_ui(ui, |ui| {
+                    ui.selectable_value(&mut form.draft.audio.device, None, "System default");
+                    for device in &form.devices {
+                        ui.selectable_value(&mut form.draft.audio.device, Some(device.clone()), device);
+                    }
+                });
+            });
+            if form.draft.audio.device.as_ref().is_some_and(|d| !form.devices.contains(d)) {
+                ui.label(egui::RichText::new("This device isn't connected, the default is used until it is").small());
+            }
+        },
+        Backend::Null => {
+            ui.label(egui::RichText::new("Songs still play in real time, without sound").small());
+        },
+        Backend::Wav(_) => {
+            ui.horizontal(|ui| {
+                ui.label("Record to");
+                ui.add(egui::TextEdit::singleline(&mut form.wav).hint_text("recording.wav"));
+                if button(ui, &GLOBAL_BUTTON_STYLE, "Choose", egui::Vec2::new(60.0, 20.0)).clicked()
+                    && let Some(path) = rfd::FileDialog::new().add_filter("WAV", &["wav"]).save_file()
+                {
+                    form.wav = path.display().to_string();
+                }
+            });
+        },
+    }
+    ui.label(egui::RichText::new(format!("Playing through {}", playing_through)).small());
+    ui.checkbox(&mut form.draft.autosave.session, "Resume where I left off");
+    ui.add_enabled(form.draft.autosave.session, egui::Slider::new(&mut form.draft.autosave.interval, 1..=120).text("seconds between saves"));
+    ui.separator();
+
+    ui.heading("Appearance");
+    ui.horizontal(|ui| {
+        for theme in Theme::ALL {
+            ui.selectable_value(&mut form.draft.theme, theme, theme.label());
+        }
+    });
+    ui.separator();
+
+    ui.horizontal(|ui| {
+        // A recording needs somewhere to go
+        let can_save = !matches!(form.draft.audio.output, Backend::Wav(_)) || !form.wav.trim().is_empty();
+        if ui.add_enabled_ui(can_save, |ui| button(ui, &GLOBAL_BUTTON_STYLE, "Save", egui::Vec2::new(60.0, 30.0))).inner.clicked() {
+            form.finish();
+            action = Some(SettingsAction::Save);
+        }
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Cancel", egui::Vec2::new(60.0, 30.0)).clicked() {
+            action = Some(SettingsAction::Cancel);
+        }
+    });
+    action
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_audio_edits_switch_the_output() {
+        let mut form = SettingsForm::new(Config::default(), PathBuf::from("config.toml"), vec![]);
+        form.draft.theme = Theme::Light;
+        form.finish();
+        assert!(!form.audio_changed());
+
+        form.draft.audio.output = Backend::Wav(PathBuf::new());
+        form.wav = String::from(" take.wav ");
+        form.finish();
+        assert!(form.audio_changed());
+        assert_eq!(form.draft.audio.output, Backend::Wav(PathBuf::from("take.wav")));
+
+        let mut file = Config::default();
+        file.sync.push(Default::default());
+        form.patch(&mut file);
+        assert_eq!((file.theme, file.audio.output, file.sync.len()), (Theme::Light, Backend::Wav(PathBuf::from("take.wav")), 1));
+    }
+}
@@ -0,0 +1,101 @@
+use eframe::egui;
+use egui::Color32;
+use std::f32::consts::PI;
+use crate::music_player::Tap;
+use crate::playset::fingerprint::fft;
+
+const WINDOW: usize = 2048;
+const BANDS: usize = 48;
+const MIN_FREQUENCY: f32 = 30.0;
+const MAX_FREQUENCY: f32 = 16000.0;
+/// Quietest level shown, in dBFS
+const FLOOR: f32 = -70.0;
+/// How far bars fall per second, as a share of the full height
+const FALL: f32 = 1.5;
+
+fn level(db: f32) -> f32 {
+    ((db - FLOOR) / -FLOOR).clamp(0.0, 1.0)
+}
+
+fn decibels(amplitude: f32) -> f32 {
+    20.0 * amplitude.max(1e-6).log10()
+}
+
+/// Spectrum bars and a VU meter over the samples the player is tapping
+#[derive(Default)]
+pub struct Spectrum {
+    bands: Vec<f32>,
+    rms: f32,
+    peak: f32,
+}
+
+impl Spectrum {
+    /// Reads the newest window from `tap`, bars rise at once and fall off smoothly
+    fn update(&mut self, tap: &Tap, dt: f32) {
+        let (samples, sample_rate) = {
+            let tap = tap.lock().unwrap();
+            let skip = tap.samples.len().saturating_sub(WINDOW);
+            (tap.samples.iter().skip(skip).copied().collect::<Vec<f32>>(), tap.sample_rate)
+        };
+        self.bands.resize(BANDS, 0.0);
+
+        let mut bands = [0.0; BANDS];
+        let (mut rms, mut peak) = (0.0, 0.0);
+        if samples.len() == WINDOW && sample_rate > 0 {
+            let mut re = samples.iter().enumerate().map(|(i, s)| s * (0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW as f32).cos())).collect::<Vec<f32>>();
+            let mut im = vec![0.0; WINDOW];
+            fft(&mut re, &mut im);
+
+            // Bands are spaced logarithmically like pitch, each shows its loudest bin
+            let bin_width = sample_rate as f32 / WINDOW as f32;
+            for (band, value) in bands.iter_mut().enumerate() {
+                let low = MIN_FREQUENCY * (MAX_FREQUENCY / MIN_FREQUENCY).powf(band as f32 / BANDS as f32);
+                let high = MIN_FREQUENCY * (MAX_FREQUENCY / MIN_FREQUENCY).powf((band + 1) as f32 / BANDS as f32);
+                let from = (low / bin_width) as usize;
+                let to = ((high / bin_width) as usize).max(from + 1).min(WINDOW / 2);
+                let magnitude = (from..to).map(|k| (re[k] * re[k] + im[k] * im[k]).sqrt()).fold(0.0, f32::max);
+                // A full scale sine peaks at a quarter of the window after the Hann window
+                *value = level(decibels(magnitude / (WINDOW as f32 / 4.0)));
+            }
+
+            // The VU meter looks at the last 50 ms only
+            let recent = &samples[WINDOW.saturating_sub(sample_rate as usize / 20)..];
+            rms = level(decibels((recent.iter().map(|s| s * s).sum::<f32>() / recent.len() as f32).sqrt()));
+            peak = level(decibels(recent.iter().map(|s| s.abs()).fold(0.0, f32::max)));
+        }
+
+        let fall = FALL * dt;
+        for (shown, new) in self.bands.iter_mut().zip(bands) {
+            *shown = new.max(*shown - fall);
+        }
+        self.rms = rms.max(self.rms - fall);
+        self.peak = peak.max(self.peak - fall);
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, tap: &Tap) {
+        self.update(tap, ui.input(|i| i.stable_dt).min(0.1));
+
+        ui.horizontal(|ui| {
+            let (rect, _) = ui.allocate_exact_size(egui::Vec2::new(480.0, 100.0), egui::Sense::hover());
+            let painter = ui.painter_at(rect);
+            painter.rect_filled(rect, 3.0, Color32::from_gray(25));
+            let width = rect.width() / BANDS as f32;
+            for (i, band) in self.bands.iter().enumerate() {
+                let left = rect.left() + i as f32 * width;
+                let bar = egui::Rect::from_min_max(egui::pos2(left + 1.0, rect.bottom() - band * rect.height()), egui::pos2(left + width - 1.0, rect.bottom()));
+                painter.rect_filled(bar, 0.0, Color32::from_rgb(200, 50, 180));
+            }
+
+            ui.add_space(10.0);
+            let (rect, _) = ui.allocate_exact_size(egui::Vec2::new(24.0, 100.0), egui::Sense::hover());
+            let painter = ui.painter_at(rect);
+            painter.rect_filled(rect, 3.0, Color32::from_gray(25));
+            let color = if self.peak > level(-3.0) { Color32::RED } else if self.rms > level(-12.0) { Color32::YELLOW } else { Color32::GREEN };
+            let bar = egui::Rect::from_min_max(egui::pos2(rect.left() + 4.0, rect.bottom() - self.rms * rect.height()), egui::pos2(rect.right() - 4.0, rect.bottom()));
+            painter.rect_filled(bar, 0.0, color);
+            let peak = rect.bottom() - self.peak * rect.height();
+            painter.line_segment([egui::pos2(rect.left(), peak), egui::pos2(rect.right(), peak)], (2.0, Color32::WHITE));
+            ui.label(format!("{:.0} dB", FLOOR * (1.0 - self.rms)));
+        });
+    }
+}
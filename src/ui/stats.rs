@@ -0,0 +1,47 @@
+use eframe::egui;
+use crate::playset::Library;
+use crate::playset::listening::{self, PlayKind};
+
+const PERIODS: [(u64, &str); 4] = [(7, "Last 7 days"), (30, "Last 30 days"), (365, "Last year"), (0, "All time")];
+const SHOWN: usize = 10;
+
+/// Top artists over `period` days (0 for all time), most played songs and recent plays
+pub fn show(ui: &mut egui::Ui, library: &Library, period: &mut u64) {
+    let since = if *period == 0 { 0 } else { listening::now().saturating_sub(*period * 86400) };
+    let period_name = PERIODS.iter().find(|(days, _)| days == period).map(|(_, name)| *name).unwrap_or("");
+
+    ui.horizontal(|ui| {
+        ui.label(egui::RichText::new("Top artists").strong());
+        egui::ComboBox::from_id_salt("stats_period").selected_text(period_name).show_ui(ui, |ui| {
+            for (days, name) in PERIODS {
+                ui.selectable_value(period, days, name);
+            }
+        });
+    });
+    let artists = library.listening.top_artists(since, |name| library.song_by_name(name));
+    if artists.is_empty() {
+        ui.label("Nothing played yet");
+    }
+    for (artist, plays) in artists.iter().take(SHOWN) {
+        ui.label(format!("{} · {} plays", artist, plays));
+    }
+    ui.separator();
+
+    ui.label(egui::RichText::new("Most played").strong());
+    for (name, stats) in library.listening.top_songs().iter().take(SHOWN) {
+        let title = library.song_by_name(name).map(|s| s.display_title().to_owned()).unwrap_or_else(|| name.clone());
+        let total = stats.plays + stats.skips;
+        ui.label(format!("{} · {} plays · {:.0}% skipped", title, stats.plays, stats.skips as f32 * 100.0 / total.max(1) as f32));
+    }
+    ui.separator();
+
+    ui.label(egui::RichText::new("Recently played").strong());
+    let now = listening::now();
+    for event in library.listening.events.iter().rev().filter(|e| e.kind != PlayKind::Start).take(SHOWN) {
+        let title = library.song_by_name(&event.song).map(|s| s.display_title().to_owned()).unwrap_or_else(|| event.song.clone());
+        let ago = now.saturating_sub(event.at);
+        let ago = if ago < 3600 { format!("{} min ago", ago / 60) } else if ago < 86400 { format!("{} h ago", ago / 3600) } else { format!("{} days ago", ago / 86400) };
+        let skipped = if event.kind == PlayKind::Skip { " (skipped)" } else { "" };
+        ui.label(format!("{}{} · {}", title, skipped, ago));
+    }
+}
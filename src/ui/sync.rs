@@ -0,0 +1,218 @@
+use std::{collections::HashMap, path::PathBuf, sync::mpsc, thread, time::Duration};
+use eframe::egui;
+use crate::playset::Song;
+use crate::playset::export::Format;
+use crate::playset::sync::{self, DeviceChanges, ReadBack, SyncProfile, SyncReport};
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+enum Progress {
+    /// Read from the device before any copying
+    Device(DeviceChanges),
+    Song(String, usize, String),
+    Finished(Result<SyncReport, String>),
+}
+
+/// Edits sync profiles and runs them, the copying happens on a background thread
+pub struct SyncForm {
+    pub profiles: Vec<SyncProfile>,
+    selected: usize,
+    progress: String,
+    running: Option<mpsc::Receiver<Progress>>,
+    /// Still reading plays and ratings from the device, the form stays open until they are in
+    reading: bool,
+    device_changes: Option<DeviceChanges>,
+    message: Option<String>,
+}
+
+pub enum SyncAction {
+    /// Call `start` with the songs of every set
+    Sync(SyncProfile),
+    Close,
+}
+
+impl SyncForm {
+    pub fn new(profiles: Vec<SyncProfile>) -> Self {
+        Self { profiles, selected: 0, progress: String::new(), running: None, reading: false, device_changes: None, message: None }
+    }
+
+    /// Reads back from the device, then copies `sets`. `titles` come from `sync::song_titles`.
+    pub fn start(&mut self, profile: SyncProfile, titles: HashMap<String, (String, String)>, sets: Vec<(String, Vec<Song>)>, songs_dir: PathBuf) {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            match sync::read_device(&profile, &titles) {
+                Ok(changes) => {
+                    let _ = tx.send(Progress::Device(changes));
+                },
+                Err(e) => {
+                    let _ = tx.send(Progress::Finished(Err(e.to_string())));
+                    return;
+                },
+            }
+            let progress = tx.clone();
+            let result = sync::sync(&profile, &sets, &songs_dir, |set, done, song| {
+                let _ = progress.send(Progress::Song(set.to_owned(), done, song.to_owned()));
+            });
+            let _ = tx.send(Progress::Finished(result.map_err(|e| e.to_string())));
+        });
+        self.message = None;
+        self.progress = String::from("Reading the device");
+        self.reading = true;
+        self.running = Some(rx);
+    }
+
+    /// Plays and ratings read from the device, once, for `Library::add_device_changes`
+    pub fn take_device_changes(&mut self) -> Option<DeviceChanges> {
+        self.device_changes.take()
+    }
+
+    /// Shows what the library took in from the device
+    pub fn read_back(&mut self, read_back: ReadBack) {
+        if read_back.plays + read_back.ratings > 0 {
+            let imported = format!("Imported {} plays and {} ratings from the device", read_back.plays, read_back.ratings);
+            self.message = Some(self.message.take().map(|m| format!("{}\n{}", imported, m)).unwrap_or(imported));
+        }
+    }
+
+    pub fn failed(&mut self, e: String) {
+        self.message = Some(format!("Failed: {}", e));
+    }
+
+    fn poll(&mut self) {
+        let Some(rx) = &self.running else {
+            return;
+        };
+        let mut finished = false;
+        for progress in rx.try_iter() {
+            match progress {
+                Progress::Device(changes) => {
+                    self.device_changes = Some(changes);
+                    self.reading = false;
+                },
+                Progress::Song(set, done, song) => self.progress = format!("{}: {} done, {}", set, done, song),
+                Progress::Finished(result) => {
+                    let summary = match result {
+                        Ok(report) => {
+                            let export = report.export;
+                            let mut summary = format!("{} copied, {} transcoded, {} up to date, {} deleted", export.copied, export.transcoded, export.skipped, report.deleted);
+                            for (song, e) in export.failed {
+                                summary.push_str(&format!("\n{} failed: {}", song, e));
+                            }
+                            summary
+                        },
+                        Err(e) => format!("Failed: {}", e),
+                    };
+                    self.message = Some(self.message.take().map(|m| format!("{}\n{}", m, summary)).unwrap_or(summary));
+                    finished = true;
+                },
+            }
+        }
+        if finished {
+            self.reading = false;
+            self.running = None;
+        }
+    }
+}
+
+pub fn show(ui: &mut egui::Ui, form: &mut SyncForm, sets: &[String]) -> Option<SyncAction> {
+    form.poll();
+    let mut action = None;
+
+    ui.horizontal(|ui| {
+        for (i, profile) in form.profiles.iter().enumerate() {
+            let name = if profile.name.is_empty() { "Unnamed" } else { &profile.name };
+            ui.selectable_value(&mut form.selected, i, name);
+        }
+        if form.running.is_none() && button(ui, &GLOBAL_BUTTON_STYLE, "New profile", egui::Vec2::new(90.0, 20.0)).clicked() {
+            form.profiles.push(SyncProfile { name: format!("Device {}", form.profiles.len() + 1), read_back: true, ..Default::default() });
+            form.selected = form.profiles.len() - 1;
+        }
+    });
+    ui.separator();
+
+    let running = form.running.is_some();
+    if let Some(profile) = form.profiles.get_mut(form.selected) {
+        ui.add_enabled_ui(!running, |ui| {
+            egui::Grid::new("sync_profile").num_columns(2).show(ui, |ui| {
+                ui.label("Name");
+                ui.text_edit_singleline(&mut profile.name);
+                ui.end_row();
+
+                ui.label("Device folder");
+                ui.horizontal(|ui| {
+                    let target = if profile.export.target.as_os_str().is_empty() { String::from("None chosen") } else { profile.export.target.display().to_string() };
+                    ui.label(target);
+                    if button(ui, &GLOBAL_BUTTON_STYLE, "Choose…", egui::Vec2::new(70.0, 20.0)).clicked()
+                        && let Some(dir) = rfd::FileDialog::new().pick_folder()
+                    {
+                        profile.export.target = dir;
+                    }
+                });
+                ui.end_row();
+
+                ui.label("File names");
+                ui.text_edit_singleline(&mut profile.export.template);
+                ui.end_row();
+
+                ui.label("Format");
+                ui.horizontal(|ui| {
+                    for format in Format::ALL {
+                        ui.selectable_value(&mut profile.export.format, format, format.label());
+                    }
+                });
+                ui.end_row();
+
+                if profile.export.format != Format::Copy {
+                    ui.label("Bitrate");
+                    ui.add(egui::Slider::new(&mut profile.export.bitrate, 64..=320).suffix(" kbps"));
+                    ui.end_row();
+                }
+            });
+            ui.checkbox(&mut profile.export.m3u, "Write an M3U playlist per set");
+            ui.checkbox(&mut profile.read_back, "Import plays and ratings from the device")
+                .on_hover_text("Plays from a .scrobbler.log in the device folder, ratings from the POPM tags of synced MP3s");
+
+            ui.label(egui::RichText::new("Sets").strong());
+            ui.horizontal_wrapped(|ui| {
+                for set in sets {
+                    let mut synced = profile.sets.contains(set);
+                    if ui.checkbox(&mut synced, set).changed() {
+                        if synced {
+                            profile.sets.push(set.clone());
+                        } else {
+                            profile.sets.retain(|s| s != set);
+                        }
+                    }
+                }
+            });
+        });
+
+        let mut delete = false;
+        ui.horizontal(|ui| {
+            let ready = !running && !profile.export.target.as_os_str().is_empty() && !profile.sets.is_empty();
+            if ready && button(ui, &GLOBAL_BUTTON_STYLE, "Sync now", egui::Vec2::new(80.0, 30.0)).clicked() {
+                action = Some(SyncAction::Sync(profile.clone()));
+            }
+            delete = !running && button(ui, &GLOBAL_BUTTON_STYLE, "Delete profile", egui::Vec2::new(100.0, 30.0)).clicked();
+        });
+        if delete {
+            form.profiles.remove(form.selected);
+            form.selected = form.selected.saturating_sub(1);
+        }
+    } else {
+        ui.label("No sync profiles yet");
+    }
+
+    if running {
+        ui.add(egui::Spinner::new());
+        ui.label(&form.progress);
+        ui.ctx().request_repaint_after(Duration::from_millis(200));
+    }
+    if let Some(message) = &form.message {
+        ui.label(message);
+    }
+    // Syncing carries on in the background if closed early, but what the device had is needed first
+    if !form.reading && button(ui, &GLOBAL_BUTTON_STYLE, "Close", egui::Vec2::new(60.0, 30.0)).clicked() {
+        action = Some(SyncAction::Close);
+    }
+    action
+}
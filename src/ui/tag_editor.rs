@@ -0,0 +1,137 @@
+use eframe::egui;
+use std::path::PathBuf;
+use crate::playset::Song;
+use crate::playset::tag_writer::TagEdit;
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+/// A field of the form, only written back when `change` is ticked
+struct Field {
+    change: bool,
+    value: String,
+}
+
+impl Field {
+    /// Prefilled with the value all `songs` share, if they do
+    fn new(songs: &[Song], get: impl Fn(&Song) -> String) -> Self {
+        let first = songs.first().map(&get).unwrap_or_default();
+        let shared = songs.iter().all(|s| get(s) == first);
+        Self {
+            change: false,
+            value: if shared { first } else { String::new() },
+        }
+    }
+}
+
+pub struct TagForm {
+    pub songs: Vec<String>,
+    title: Field,
+    artist: Field,
+    album: Field,
+    genre: Field,
+    track_number: Field,
+    year: Field,
+    cover: Option<PathBuf>,
+    error: Option<String>,
+}
+
+pub enum TagFormResult {
+    Apply(TagEdit),
+    Cancel,
+}
+
+impl TagForm {
+    pub fn new(songs: &[Song]) -> Self {
+        Self {
+            songs: songs.iter().map(|s| s.name.clone()).collect(),
+            title: Field::new(songs, |s| s.title.clone()),
+            artist: Field::new(songs, |s| s.artist.clone()),
+            album: Field::new(songs, |s| s.album.clone()),
+            genre: Field::new(songs, |s| s.genre.clone()),
+            track_number: Field::new(songs, |s| s.track_number.map(|n| n.to_string()).unwrap_or_default()),
+            year: Field::new(songs, |s| s.year.map(|n| n.to_string()).unwrap_or_default()),
+            cover: None,
+            error: None,
+        }
+    }
+
+    fn to_edit(&self) -> Result<TagEdit, String> {
+        let text = |field: &Field| field.change.then(|| field.value.clone());
+        let track_number = match text(&self.track_number) {
+            Some(n) => Some(n.trim().parse::<u16>().map_err(|_| format!("\"{}\" is not a track number", n))?),
+            None => None,
+        };
+        let year = match text(&self.year) {
+            Some(y) => Some(y.trim().parse::<i32>().map_err(|_| format!("\"{}\" is not a year", y))?),
+            None => None,
+        };
+        Ok(TagEdit {
+            title: text(&self.title),
+            artist: text(&self.artist),
+            album: text(&self.album),
+            genre: text(&self.genre),
+            track_number,
+            year,
+            cover: self.cover.clone(),
+        })
+    }
+}
+
+fn field_row(ui: &mut egui::Ui, label: &str, field: &mut Field) {
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut field.change, label);
+        if ui.add(egui::TextEdit::singleline(&mut field.value)).changed() {
+            field.change = true;
+        }
+    });
+}
+
+/// Draws the form, returns what the user decided once they press a button
+pub fn show(ui: &mut egui::Ui, form: &mut TagForm) -> Option<TagFormResult> {
+    if form.songs.len() == 1 {
+        ui.label(&form.songs[0]);
+    } else {
+        ui.label(format!("Editing {} songs, only ticked fields are changed", form.songs.len()));
+    }
+    ui.separator();
+
+    field_row(ui, "Title", &mut form.title);
+    field_row(ui, "Artist", &mut form.artist);
+    field_row(ui, "Album", &mut form.album);
+    field_row(ui, "Genre", &mut form.genre);
+    field_row(ui, "Track number", &mut form.track_number);
+    field_row(ui, "Year", &mut form.year);
+
+    ui.horizontal(|ui| {
+        let text = match &form.cover {
+            Some(cover) => format!("Cover: {}", cover.file_name().and_then(|f| f.to_str()).unwrap_or("")),
+            None => String::from("Cover: unchanged"),
+        };
+        ui.label(text);
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Choose", egui::Vec2::new(60.0, 20.0)).clicked()
+            && let Some(cover) = rfd::FileDialog::new().add_filter("Image", &["png", "jpg", "jpeg", "bmp", "gif", "tif", "tiff"]).pick_file()
+        {
+            form.cover = Some(cover);
+        }
+        if form.cover.is_some() && button(ui, &GLOBAL_BUTTON_STYLE, "Clear", egui::Vec2::new(50.0, 20.0)).clicked() {
+            form.cover = None;
+        }
+    });
+
+    if let Some(error) = &form.error {
+        ui.colored_label(egui::Color32::RED, error);
+    }
+
+    let mut result = None;
+    ui.horizontal(|ui| {
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Save", egui::Vec2::new(60.0, 30.0)).clicked() {
+            match form.to_edit() {
+                Ok(edit) => result = Some(TagFormResult::Apply(edit)),
+                Err(e) => form.error = Some(e),
+            }
+        }
+        if button(ui, &GLOBAL_BUTTON_STYLE, "Cancel", egui::Vec2::new(60.0, 30.0)).clicked() {
+            result = Some(TagFormResult::Cancel);
+        }
+    });
+    result
+}
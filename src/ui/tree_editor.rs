@@ -0,0 +1,413 @@
+use eframe::egui;
+use egui::Color32;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::playset::{pset_format, Library, SongSet, SongTree, SongTreeNode};
+use crate::playset::sampling::Sampler;
+use crate::playset::rules::{Condition, Rule, SongField};
+use crate::playset::genres::GenreTaxonomy;
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+pub const OPERATORS: [(char, &str); 4] = [
+    (pset_format::UNION, "Union"),
+    (pset_format::INTERSECTION, "Intersection"),
+    (pset_format::DIFFERENCE, "Difference"),
+    (pset_format::SYMMETRIC_DIFFERENCE, "Symmetric Difference"),
+];
+/// Only union and intersection make sense over more than two sets
+const NARY_OPERATORS: [(char, &str); 2] = [
+    (pset_format::UNION, "Union"),
+    (pset_format::INTERSECTION, "Intersection"),
+];
+
+/// Drag and drop payload for a set reference or rule picked up from the palette
+struct DraggedSet(SongSet);
+
+/// Nodes are addressed by the child index taken at each level from the root
+enum TreeAction {
+    SetOperator(Vec<usize>, char),
+    Swap(Vec<usize>),
+    Wrap(Vec<usize>),
+    Complement(Vec<usize>),
+    Merge(Vec<usize>),
+    AddOperand(Vec<usize>),
+    Limit(Vec<usize>, Sampler),
+    SetSampler(Vec<usize>, Sampler),
+    Unwrap(Vec<usize>),
+    Remove(Vec<usize>),
+    Replace(Vec<usize>, SongSet),
+    /// Dropping onto hand-picked songs keeps them, the dropped set is added beside them
+    UnionWith(Vec<usize>, SongSet),
+}
+
+fn operator_name(op: char) -> &'static str {
+    OPERATORS.iter().find(|(c, _)| *c == op).map(|(_, name)| *name).unwrap_or("?")
+}
+
+fn new_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+fn empty_leaf() -> SongTree {
+    SongTree::Set(SongSet::Terminal(HashSet::new()))
+}
+
+/// Draws the whole tree of `editing` as nested blocks.
+/// Returns the rebuilt tree if the user changed anything this frame.
+pub fn show(ui: &mut egui::Ui, library: &Library, editing: &str) -> Option<SongTree> {
+    ui.label("Drag a set onto a leaf to use it there");
+    ui.horizontal_wrapped(|ui| {
+        let mut names = library.sets.keys().collect::<Vec<&String>>();
+        names.sort();
+        for name in names {
+            // Sets that already pull in the one being edited would form a cycle
+            if library.depends_on(name, editing) {
+                continue;
+            }
+            ui.dnd_drag_source(egui::Id::new(("tree_editor_palette", name)), DraggedSet(SongSet::NonTerminal(name.clone())), |ui| {
+                ui.label(egui::RichText::new(name).color(Color32::from_rgb(200, 50, 180)));
+            });
+        }
+    });
+    rule_palette(ui);
+    genre_palette(ui, library);
+    tag_palette(ui, library);
+    ui.separator();
+
+    let tree = library.sets.get(editing)?.songs.borrow().clone();
+    // Song counts of every node by path, flattening each node every frame is quadratic in the tree size
+    let counts_id = egui::Id::new(("tree_editor_counts", editing));
+    // Rules can select on play counts, so those changing counts too
+    let revision = (library.revision, library.stats_revision);
+    let mut counts = ui.data_mut(|d| d.get_temp::<((u64, u64), HashMap<Vec<usize>, usize>)>(counts_id))
+        .filter(|(r, _)| *r == revision)
+        .map(|(_, counts)| counts)
+        .unwrap_or_default();
+    let mut action = None;
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        node_ui(ui, &tree, &mut vec![], library, &mut counts, &mut action);
+    });
+    ui.data_mut(|d| d.insert_temp(counts_id, (revision, counts)));
+
+    action.map(|action| apply(&tree, action))
+}
+
+fn node_ui(ui: &mut egui::Ui, tree: &SongTree, path: &mut Vec<usize>, library: &Library, counts: &mut HashMap<Vec<usize>, usize>, action: &mut Option<TreeAction>) {
+    let count = *counts.entry(path.clone()).or_insert_with(|| tree.flatten(library).len());
+
+    if let SongTree::Set(song_set) = tree {
+        let label = match song_set {
+            SongSet::NonTerminal(name) => name.clone(),
+            SongSet::Terminal(songs) if songs.is_empty() => String::from("Empty (drop a set here)"),
+            SongSet::Terminal(_) => String::from("Hand-picked songs"),
+            SongSet::Rule(rule) => format!("Rule: {}", rule),
+            SongSet::Tag(tag) => format!("#{}", tag),
+        };
+        let (_, dropped) = ui.dnd_drop_zone::<DraggedSet, ()>(egui::Frame::default().inner_margin(4.0), |ui| {
+            ui.horizontal(|ui| {
+                ui.label(label);
+                ui.label(format!("{} songs", count));
+                common_buttons(ui, path, action);
+            });
+        });
+        if let Some(dropped) = dropped {
+            *action = Some(match song_set {
+                SongSet::Terminal(songs) if !songs.is_empty() => TreeAction::UnionWith(path.clone(), dropped.0.clone()),
+                _ => TreeAction::Replace(path.clone(), dropped.0.clone()),
+            });
+        }
+        return;
+    }
+
+    ui.group(|ui| {
+        ui.horizontal(|ui| {
+            match tree {
+                SongTree::Operation(op, _) => operator_combo(ui, *op, &OPERATORS, path, action),
+                SongTree::NAry(op, _) => operator_combo(ui, *op, &NARY_OPERATORS, path, action),
+                SongTree::Sample(sampler, _) => sampler_params(ui, sampler, path, action),
+                _ => {
+                    ui.label("Complement");
+                },
+            }
+            ui.label(format!("{} songs", count));
+
+            if let SongTree::Operation(op, _) = tree {
+                if button(ui, &GLOBAL_BUTTON_STYLE, "Swap", egui::Vec2::new(50.0, 20.0)).clicked() {
+                    *action = Some(TreeAction::Swap(path.clone()));
+                }
+                if (*op == pset_format::UNION || *op == pset_format::INTERSECTION)
+                    && button(ui, &GLOBAL_BUTTON_STYLE, "Merge", egui::Vec2::new(50.0, 20.0)).on_hover_text("Combine nested operands of the same operator into one node").clicked()
+                {
+                    *action = Some(TreeAction::Merge(path.clone()));
+                }
+            }
+            if let SongTree::NAry(..) | SongTree::Sample(Sampler::Weighted { .. }, _) = tree
+                && button(ui, &GLOBAL_BUTTON_STYLE, "Add operand", egui::Vec2::new(90.0, 20.0)).clicked()
+            {
+                *action = Some(TreeAction::AddOperand(path.clone()));
+            }
+            if button(ui, &GLOBAL_BUTTON_STYLE, "Unwrap", egui::Vec2::new(60.0, 20.0)).on_hover_text("Keep only the first operand").clicked() {
+                *action = Some(TreeAction::Unwrap(path.clone()));
+            }
+            common_buttons(ui, path, action);
+        });
+        ui.indent(("tree_editor_children", path.clone()), |ui| {
+            for (i, child) in tree.children().iter().enumerate() {
+                path.push(i);
+                node_ui(ui, child, path, library, counts, action);
+                path.pop();
+            }
+        });
+    });
+}
+
+/// Text field for writing a rule, which can be dragged onto a leaf once it parses
+fn rule_palette(ui: &mut egui::Ui) {
+    let id = egui::Id::new("tree_editor_rule");
+    let mut text = ui.data_mut(|d| d.get_temp::<String>(id)).unwrap_or_default();
+    ui.horizontal(|ui| {
+        ui.label("Rule");
+        ui.add(egui::TextEdit::singleline(&mut text).hint_text("year >= 2000"));
+        match Rule::parse(&text) {
+            Ok(rule) => {
+                ui.dnd_drag_source(egui::Id::new("tree_editor_rule_chip"), DraggedSet(SongSet::Rule(rule.clone())), |ui| {
+                    ui.label(egui::RichText::new(rule.to_string()).color(Color32::from_rgb(200, 50, 180)));
+                });
+            },
+            Err(e) if !text.is_empty() => {
+                ui.colored_label(Color32::RED, e);
+            },
+            Err(_) => {},
+        }
+    });
+    ui.data_mut(|d| d.insert_temp(id, text));
+}
+
+/// `name` followed by its sub-genres, each level indented a bit further
+fn genre_options(genres: &GenreTaxonomy, name: &str, depth: usize, out: &mut Vec<(String, usize)>) {
+    if out.iter().any(|(n, _)| n == name) {
+        return;
+    }
+    out.push((name.to_owned(), depth));
+    for child in genres.children(name) {
+        genre_options(genres, child, depth + 1, out);
+    }
+}
+
+/// Picks a genre from the taxonomy as a draggable rule that also selects its sub-genres
+fn genre_palette(ui: &mut egui::Ui, library: &Library) {
+    let id = egui::Id::new("tree_editor_genre");
+    let mut selected = ui.data_mut(|d| d.get_temp::<String>(id)).unwrap_or_default();
+
+    let mut options = vec![];
+    for root in library.genres.roots() {
+        genre_options(&library.genres, root, 0, &mut options);
+    }
+    // Genres tagged in the library that the taxonomy does not know about
+    let mut unknown = library.index.songs()
+        .map(|s| s.genre.clone())
+        .filter(|g| !g.is_empty() && !options.iter().any(|(n, _)| n == g))
+        .collect::<Vec<String>>();
+    unknown.sort();
+    unknown.dedup();
+    options.extend(unknown.into_iter().map(|g| (g, 0)));
+
+    ui.horizontal(|ui| {
+        ui.label("Genre");
+        egui::ComboBox::from_id_salt("tree_editor_genre_combo").selected_text(&selected).show_ui(ui, |ui| {
+            for (name, depth) in &options {
+                ui.selectable_value(&mut selected, name.clone(), format!("{}{}", "    ".repeat(*depth), name));
+            }
+        });
+        if !selected.is_empty() {
+            let rule = Rule { field: SongField::Genre, condition: Condition::Under(selected.clone()) };
+            ui.dnd_drag_source(egui::Id::new("tree_editor_genre_chip"), DraggedSet(SongSet::Rule(rule.clone())), |ui| {
+                ui.label(egui::RichText::new(rule.to_string()).color(Color32::from_rgb(200, 50, 180)));
+            });
+        }
+    });
+    ui.data_mut(|d| d.insert_temp(id, selected));
+}
+
+/// User tags as draggable `#tag` leaves
+fn tag_palette(ui: &mut egui::Ui, library: &Library) {
+    let tags = library.user_tags();
+    if tags.is_empty() {
+        return;
+    }
+    ui.horizontal_wrapped(|ui| {
+        ui.label("Tags");
+        for (tag, count) in tags {
+            ui.dnd_drag_source(egui::Id::new(("tree_editor_tag", &tag)), DraggedSet(SongSet::Tag(tag.clone())), |ui| {
+                ui.label(egui::RichText::new(format!("#{}", tag)).color(Color32::from_rgb(200, 50, 180)))
+                    .on_hover_text(format!("{} songs", count));
+            });
+        }
+    });
+}
+
+fn operator_combo(ui: &mut egui::Ui, op: char, operators: &[(char, &str)], path: &[usize], action: &mut Option<TreeAction>) {
+    let mut selected = op;
+    egui::ComboBox::from_id_salt(("tree_editor_op", path.to_vec())).selected_text(operator_name(op)).show_ui(ui, |ui| {
+        for (c, name) in operators {
+            ui.selectable_value(&mut selected, *c, *name);
+        }
+    });
+    if selected != op {
+        *action = Some(TreeAction::SetOperator(path.to_vec(), selected));
+    }
+}
+
+fn sampler_params(ui: &mut egui::Ui, sampler: &Sampler, path: &[usize], action: &mut Option<TreeAction>) {
+    ui.label(sampler.name());
+    let mut edited = sampler.clone();
+    match &mut edited {
+        Sampler::Take(n) => {
+            ui.add(egui::DragValue::new(n).prefix("n: "));
+        },
+        Sampler::Random { count, seed } => {
+            ui.add(egui::DragValue::new(count).prefix("count: "));
+            ui.add(egui::DragValue::new(seed).prefix("seed: "));
+        },
+        Sampler::Weighted { count, seed, weights } => {
+            ui.add(egui::DragValue::new(count).prefix("count: "));
+            ui.add(egui::DragValue::new(seed).prefix("seed: "));
+            for (i, weight) in weights.iter_mut().enumerate() {
+                ui.add(egui::DragValue::new(weight).prefix(format!("#{} weight: ", i + 1)));
+            }
+        },
+        Sampler::DurationCap(seconds) => {
+            let mut minutes = *seconds / 60;
+            if ui.add(egui::DragValue::new(&mut minutes).suffix(" min")).changed() {
+                *seconds = minutes * 60;
+            }
+        },
+    }
+    if edited != *sampler {
+        *action = Some(TreeAction::SetSampler(path.to_vec(), edited));
+    }
+}
+
+fn common_buttons(ui: &mut egui::Ui, path: &[usize], action: &mut Option<TreeAction>) {
+    if button(ui, &GLOBAL_BUTTON_STYLE, "Wrap", egui::Vec2::new(50.0, 20.0)).clicked() {
+        *action = Some(TreeAction::Wrap(path.to_vec()));
+    }
+    if button(ui, &GLOBAL_BUTTON_STYLE, "Not", egui::Vec2::new(40.0, 20.0)).on_hover_text("Take the complement").clicked() {
+        *action = Some(TreeAction::Complement(path.to_vec()));
+    }
+    ui.menu_button("Limit", |ui| {
+        let sampler = if ui.button("Take first N").clicked() {
+            Some(Sampler::Take(10))
+        } else if ui.button("Random sample").clicked() {
+            Some(Sampler::Random { count: 10, seed: new_seed() })
+        } else if ui.button("Weighted mix").clicked() {
+            Some(Sampler::Weighted { count: 30, seed: new_seed(), weights: vec![1] })
+        } else if ui.button("Duration cap").clicked() {
+            Some(Sampler::DurationCap(60 * 60))
+        } else {
+            None
+        };
+        if let Some(sampler) = sampler {
+            *action = Some(TreeAction::Limit(path.to_vec(), sampler));
+            ui.close_menu();
+        }
+    });
+    if !path.is_empty() && button(ui, &GLOBAL_BUTTON_STYLE, "Remove", egui::Vec2::new(60.0, 20.0)).clicked() {
+        *action = Some(TreeAction::Remove(path.to_vec()));
+    }
+}
+
+fn apply(tree: &SongTree, action: TreeAction) -> SongTree {
+    match action {
+        TreeAction::SetOperator(path, op) => map_at(tree, &path, &|t| match t {
+            SongTree::Operation(_, node) => SongTree::Operation(op, SongTreeNode::new(node.lhs(), node.rhs())),
+            SongTree::NAry(_, trees) => SongTree::NAry(op, trees.clone()),
+            t => t.clone(),
+        }),
+        TreeAction::Swap(path) => map_at(tree, &path, &|t| match t {
+            SongTree::Operation(op, node) => SongTree::Operation(*op, SongTreeNode::new(node.rhs(), node.lhs())),
+            t => t.clone(),
+        }),
+        TreeAction::Wrap(path) => map_at(tree, &path, &|t| {
+            SongTree::Operation(pset_format::UNION, SongTreeNode::new(Rc::new(t.clone()), Rc::new(empty_leaf())))
+        }),
+        TreeAction::Complement(path) => map_at(tree, &path, &|t| SongTree::Complement(Rc::new(t.clone()))),
+        TreeAction::Merge(path) => map_at(tree, &path, &|t| match t {
+            SongTree::Operation(op, _) => SongTree::NAry(*op, operands(t, *op)),
+            t => t.clone(),
+        }),
+        TreeAction::AddOperand(path) => map_at(tree, &path, &|t| match t {
+            SongTree::NAry(op, trees) => {
+                let mut trees = trees.clone();
+                trees.push(Rc::new(empty_leaf()));
+                SongTree::NAry(*op, trees)
+            },
+            SongTree::Sample(Sampler::Weighted { count, seed, weights }, trees) => {
+                let mut weights = weights.clone();
+                weights.push(1);
+                let mut trees = trees.clone();
+                trees.push(Rc::new(empty_leaf()));
+                SongTree::Sample(Sampler::Weighted { count: *count, seed: *seed, weights }, trees)
+            },
+            t => t.clone(),
+        }),
+        TreeAction::Limit(path, sampler) => map_at(tree, &path, &|t| SongTree::Sample(sampler.clone(), vec![Rc::new(t.clone())])),
+        TreeAction::SetSampler(path, sampler) => map_at(tree, &path, &|t| match t {
+            SongTree::Sample(_, trees) => SongTree::Sample(sampler.clone(), trees.clone()),
+            t => t.clone(),
+        }),
+        TreeAction::Unwrap(path) => map_at(tree, &path, &|t| {
+            t.children().first().map(|c| (**c).clone()).unwrap_or_else(|| t.clone())
+        }),
+        // Removing an operand collapses a two operand parent into the one left over
+        TreeAction::Remove(path) => match path.split_last() {
+            Some((index, parent)) => map_at(tree, parent, &|t| {
+                let mut children = t.children();
+                children.remove(*index);
+                match (t, children.len()) {
+                    (SongTree::NAry(op, _), 2..) => SongTree::NAry(*op, children),
+                    (SongTree::Sample(Sampler::Weighted { count, seed, weights }, _), 1..) => {
+                        let mut weights = weights.clone();
+                        if *index < weights.len() {
+                            weights.remove(*index);
+                        }
+                        SongTree::Sample(Sampler::Weighted { count: *count, seed: *seed, weights }, children)
+                    },
+                    (_, 0) => empty_leaf(),
+                    _ => (*children[0]).clone(),
+                }
+            }),
+            None => empty_leaf(),
+        },
+        TreeAction::Replace(path, set) => map_at(tree, &path, &|_| SongTree::Set(set.clone())),
+        TreeAction::UnionWith(path, set) => map_at(tree, &path, &|t| {
+            SongTree::Operation(pset_format::UNION, SongTreeNode::new(Rc::new(t.clone()), Rc::new(SongTree::Set(set.clone()))))
+        }),
+    }
+}
+
+/// Operands of `tree` with nested `op` nodes pulled up to the same level
+fn operands(tree: &SongTree, op: char) -> Vec<Rc<SongTree>> {
+    tree.children()
+        .into_iter()
+        .flat_map(|c| match &*c {
+            SongTree::Operation(o, _) | SongTree::NAry(o, _) if *o == op => operands(&c, op),
+            _ => vec![c],
+        }).collect()
+}
+
+/// Rebuilds `tree` with the node at `path` replaced by `f` of it
+fn map_at(tree: &SongTree, path: &[usize], f: &dyn Fn(&SongTree) -> SongTree) -> SongTree {
+    match path.split_first() {
+        None => f(tree),
+        Some((index, rest)) => {
+            let children = tree.children()
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| if i == *index { Rc::new(map_at(&c, rest, f)) } else { c })
+                .collect();
+            tree.with_children(children)
+        },
+    }
+}
@@ -0,0 +1,75 @@
+use eframe::egui;
+use egui::Color32;
+use crate::playset::Library;
+use super::{button, GLOBAL_BUTTON_STYLE};
+
+/// Adds and removes user tags on one or more songs
+pub struct UserTagForm {
+    pub songs: Vec<String>,
+    input: String,
+}
+
+pub enum UserTagAction {
+    Add(String),
+    Remove(String),
+    Close,
+}
+
+impl UserTagForm {
+    pub fn new(songs: Vec<String>) -> Self {
+        Self { songs, input: String::new() }
+    }
+}
+
+pub fn show(ui: &mut egui::Ui, form: &mut UserTagForm, library: &Library) -> Option<UserTagAction> {
+    let mut action = None;
+    if form.songs.len() == 1 {
+        ui.label(&form.songs[0]);
+    } else {
+        ui.label(format!("Tagging {} songs", form.songs.len()));
+    }
+    ui.separator();
+
+    // Tags on any of the songs, with how many of them have it
+    let songs = form.songs.iter().filter_map(|s| library.song_by_name(s)).collect::<Vec<_>>();
+    let mut present = songs.iter().flat_map(|s| s.tags.iter().cloned()).collect::<Vec<String>>();
+    present.sort();
+    present.dedup();
+    ui.horizontal_wrapped(|ui| {
+        if present.is_empty() {
+            ui.label("No tags yet");
+        }
+        for tag in &present {
+            let count = songs.iter().filter(|s| s.tags.contains(tag)).count();
+            let text = if songs.len() > 1 { format!("#{} ({}/{}) ✕", tag, count, songs.len()) } else { format!("#{} ✕", tag) };
+            if ui.add(egui::Label::new(egui::RichText::new(text).color(Color32::from_rgb(200, 50, 180))).sense(egui::Sense::click())).on_hover_text("Remove").clicked() {
+                action = Some(UserTagAction::Remove(tag.clone()));
+            }
+        }
+    });
+
+    ui.horizontal(|ui| {
+        let response = ui.add(egui::TextEdit::singleline(&mut form.input).hint_text("no-lyrics"));
+        let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if (entered || button(ui, &GLOBAL_BUTTON_STYLE, "Add", egui::Vec2::new(50.0, 20.0)).clicked()) && !form.input.trim().is_empty() {
+            action = Some(UserTagAction::Add(std::mem::take(&mut form.input)));
+        }
+    });
+
+    let known = library.user_tags().into_iter().filter(|(t, _)| !present.contains(t)).collect::<Vec<(String, usize)>>();
+    if !known.is_empty() {
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Add existing:");
+            for (tag, _) in known {
+                if ui.add(egui::Label::new(format!("#{}", tag)).sense(egui::Sense::click())).clicked() {
+                    action = Some(UserTagAction::Add(tag));
+                }
+            }
+        });
+    }
+
+    if button(ui, &GLOBAL_BUTTON_STYLE, "Done", egui::Vec2::new(60.0, 30.0)).clicked() {
+        action = Some(UserTagAction::Close);
+    }
+    action
+}
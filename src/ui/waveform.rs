@@ -0,0 +1,46 @@
+use eframe::egui;
+use egui::Color32;
+use crate::playset::waveform::Waveform;
+
+const PLAYED: Color32 = Color32::from_rgb(200, 50, 180);
+const UNPLAYED: Color32 = Color32::from_gray(110);
+
+/// Draws `waveform` with the first `progress` (0-1) of it highlighted.
+/// Clicking or letting go of a drag seeks, the returned value is the position picked as a fraction of the song.
+pub fn seek_bar(ui: &mut egui::Ui, waveform: Option<&Waveform>, progress: f32, size: egui::Vec2) -> Option<f32> {
+    let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click_and_drag());
+    let painter = ui.painter_at(rect);
+    let middle = rect.center().y;
+    let half = rect.height() / 2.0;
+    let played_until = rect.left() + rect.width() * progress.clamp(0.0, 1.0);
+
+    match waveform.filter(|w| !w.peaks.is_empty()) {
+        Some(waveform) => {
+            let columns = rect.width().max(1.0) as usize;
+            for x in 0..columns {
+                // Several buckets can fall into one pixel column, the loudest one is drawn
+                let from = x * waveform.peaks.len() / columns;
+                let to = ((x + 1) * waveform.peaks.len() / columns).max(from + 1).min(waveform.peaks.len());
+                let peak = waveform.peaks[from..to].iter().max().copied().unwrap_or(0) as f32 / 255.0;
+                let rms = waveform.rms[from..to].iter().max().copied().unwrap_or(0) as f32 / 255.0;
+
+                let left = rect.left() + x as f32;
+                let color = if left < played_until { PLAYED } else { UNPLAYED };
+                painter.line_segment([egui::pos2(left, middle - peak * half), egui::pos2(left, middle + peak * half)], (1.0, color.gamma_multiply(0.5)));
+                painter.line_segment([egui::pos2(left, middle - rms * half), egui::pos2(left, middle + rms * half)], (1.0, color));
+            }
+        },
+        // Still being decoded, a plain progress bar until then
+        None => {
+            painter.line_segment([egui::pos2(rect.left(), middle), egui::pos2(rect.right(), middle)], (2.0, UNPLAYED));
+            painter.line_segment([egui::pos2(rect.left(), middle), egui::pos2(played_until, middle)], (2.0, PLAYED));
+        },
+    }
+    painter.line_segment([egui::pos2(played_until, rect.top()), egui::pos2(played_until, rect.bottom())], (1.0, Color32::WHITE));
+
+    if response.clicked() || response.drag_stopped() {
+        let pointer = response.interact_pointer_pos()?;
+        return Some(((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0));
+    }
+    None
+}